@@ -0,0 +1,55 @@
+// Duplicate delivery of one signed message, verified from scratch every time vs answered from the verify cache.
+// cargo bench --bench verify_cache
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+use stockchain::gossip::secret::Secret;
+
+static DELIVERIES: u32 = 1000;
+
+fn paired() -> (Secret, PeerId, Secret, PeerId) {
+    let (alice_id, bob_id) = (PeerId::random(), PeerId::random());
+    let mut alice = Secret::new().unwrap();
+    let mut bob = Secret::new().unwrap();
+    let (kem_pk, signature, pk) = alice.send_shared_secret(bob_id).unwrap();
+    let (kem_ct, signature, pk) = bob
+        .receive_shared_secret(alice_id, alice.algorithms(), kem_pk, signature, pk)
+        .unwrap();
+    alice
+        .receive_shared_secret_response(bob_id, kem_ct, signature, pk)
+        .unwrap();
+    (alice, alice_id, bob, bob_id)
+}
+
+fn deliver(
+    bob: &Secret,
+    alice_id: PeerId,
+    nonce: [u8; 12],
+    ciphertext: &[u8],
+    cached: bool,
+) -> Duration {
+    let start = Instant::now();
+    for _ in 0..DELIVERIES {
+        if !cached {
+            bob.clear_verify_cache();
+        }
+        bob.decrypt_verified(alice_id, b"aad", nonce, ciphertext.to_vec())
+            .unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let (alice, alice_id, bob, bob_id) = paired();
+    let (nonce, ciphertext) = alice
+        .encrypt_signed(bob_id, b"aad", b"the same message, delivered again")
+        .unwrap();
+    let uncached = deliver(&bob, alice_id, nonce, &ciphertext, false);
+    let cached = deliver(&bob, alice_id, nonce, &ciphertext, true);
+    println!(
+        "{DELIVERIES} deliveries: {:?} uncached, {:?} cached ({:.1}x)",
+        uncached,
+        cached,
+        uncached.as_secs_f64() / cached.as_secs_f64()
+    );
+}
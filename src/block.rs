@@ -32,9 +32,12 @@ impl Block {
         );
         self.hash == calculated_hash
     }
+}
 
-    pub fn to_string(&self) -> String {
-        format!(
+impl std::fmt::Display for Block {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
             "Block {{ index: {}, previous_hash: {}, timestamp: {}, data: {}, hash: {} }}",
             self.index, self.previous_hash, self.timestamp, self.data, self.hash
         )
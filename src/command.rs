@@ -0,0 +1,344 @@
+use libp2p::{Multiaddr, PeerId};
+use std::fmt::Display;
+use stockchain::gossip::room;
+
+// a message typed without quotes is everything after the room, an empty one is sent as this
+static BLANK_MSG: &str = "BLANK_MSG";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    PendingAcks,
+    PublicKey,
+    // pinned keys of peers by fingerprint
+    Keys,
+    RotateKey,
+    Addrs,
+    // IPv4 and IPv6 multiaddrs alike, e.g. /ip6/::1/tcp/4001
+    Dial {
+        addr: Multiaddr,
+    },
+    // look a peer up in the DHT and dial it
+    Find {
+        peer: PeerId,
+    },
+    // a relay's multiaddr ending in /p2p/<peer id>
+    Relay {
+        addr: Multiaddr,
+    },
+    Info,
+    Sessions,
+    Peers,
+    Transfers,
+    Metrics,
+    // leaves every room and joins them again, after switching networks
+    Rejoin,
+    // public rooms announced by others
+    Directory,
+    Ping {
+        room: String,
+    },
+    // ping every known peer and list who answers
+    PingAll,
+    JoinRoom {
+        room: String,
+    },
+    JoinRoomWithPassword {
+        room: String,
+        passphrase: String,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    SubscribedPeers {
+        room: String,
+    },
+    // `peer` is a peer id or a DM room name
+    Block {
+        peer: String,
+    },
+    Unblock {
+        peer: String,
+    },
+    // `room` is a peer id or a DM room name, no name clears the nickname
+    Nick {
+        room: String,
+        name: Option<String>,
+    },
+    Forget {
+        room: String,
+    },
+    PinKey {
+        room: String,
+        key: String,
+    },
+    History {
+        room: String,
+    },
+    // sign that we're in the room, for the others to list us as a member
+    ProveMembership {
+        room: String,
+    },
+    Members {
+        room: String,
+    },
+    // send the last `limit` messages of the room to peers joining it, 0 stops
+    CatchUp {
+        room: String,
+        limit: usize,
+    },
+    RequestPublicKey {
+        room: String,
+    },
+    SharedSecretExchange {
+        room: String,
+    },
+    SharedSecretCommunication {
+        room: String,
+        message: String,
+        signed: bool,
+    },
+    SendFile {
+        room: String,
+        path: String,
+    },
+    GroupKey {
+        room: String,
+    },
+    ShareGroupKey {
+        room: String,
+        peer_room: String,
+    },
+    GroupMessage {
+        room: String,
+        message: String,
+    },
+    Chat {
+        room: String,
+        message: String,
+    },
+    // chat to every public room we're in
+    Broadcast {
+        message: String,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Empty,
+    UnterminatedQuote,
+    Unknown(String),
+    BadAddress(String),
+    BadPeerId(String),
+    // see `room::valid_room_name`
+    BadRoomName(String),
+    // a required argument is missing, holds how the command is used
+    Usage(&'static str),
+}
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Empty => write!(f, "<cmd> <room> <info?>"),
+            CommandError::UnterminatedQuote => write!(f, "Unterminated quote"),
+            CommandError::Unknown(cmd) => write!(f, "Unknown command: {}", cmd),
+            CommandError::BadAddress(addr) => write!(f, "Not a multiaddr: {}", addr),
+            CommandError::BadPeerId(peer) => write!(f, "Not a peer id: {}", peer),
+            CommandError::BadRoomName(room) => write!(f, "Invalid room name: {:?}", room),
+            CommandError::Usage(usage) => write!(f, "{}", usage),
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, CommandError> {
+        let tokens = tokenize(line)?;
+        let Some((name, args)) = tokens.split_first() else {
+            return Err(CommandError::Empty);
+        };
+        let arg =
+            |i: usize, usage: &'static str| args.get(i).cloned().ok_or(CommandError::Usage(usage));
+        // everything after the room, so messages don't have to be quoted
+        let message = || match args.get(1..) {
+            Some(rest) if !rest.is_empty() => rest.join(" "),
+            _ => BLANK_MSG.to_string(),
+        };
+        let command = match name.as_str() {
+            "pending_acks" | "pac" => Command::PendingAcks,
+            "public_key" | "pk" => Command::PublicKey,
+            "keys" | "k" => Command::Keys,
+            "addrs" | "a" => Command::Addrs,
+            "dial" | "d" => {
+                let addr = arg(0, "d <multiaddr>")?;
+                match addr.parse() {
+                    Ok(addr) => Command::Dial { addr },
+                    Err(_) => return Err(CommandError::BadAddress(addr)),
+                }
+            }
+            "find" => {
+                let peer = arg(0, "find <peer id>")?;
+                match peer.parse() {
+                    Ok(peer) => Command::Find { peer },
+                    Err(_) => return Err(CommandError::BadPeerId(peer)),
+                }
+            }
+            "relay" => {
+                let addr = arg(0, "relay <multiaddr>")?;
+                match addr.parse() {
+                    Ok(addr) => Command::Relay { addr },
+                    Err(_) => return Err(CommandError::BadAddress(addr)),
+                }
+            }
+            "info" | "i" => Command::Info,
+            "rotate_key" | "rk" => Command::RotateKey,
+            "sessions" | "s" => Command::Sessions,
+            "peers" => Command::Peers,
+            "transfers" | "t" => Command::Transfers,
+            "metrics" | "m" => Command::Metrics,
+            "rejoin" | "rj" => Command::Rejoin,
+            "directory" | "dir" => Command::Directory,
+            "pingall" | "pa" => Command::PingAll,
+            "ping" | "p" => Command::Ping {
+                room: arg(0, "p <room>")?,
+            },
+            "join_room" | "jr" => Command::JoinRoom {
+                room: room_name(arg(0, "jr <room>")?)?,
+            },
+            "join_room_with_password" | "jrp" => Command::JoinRoomWithPassword {
+                room: room_name(arg(0, "jrp <room> <passphrase>")?)?,
+                passphrase: arg(1, "jrp <room> <passphrase>")?,
+            },
+            "leave_room" | "lr" => Command::LeaveRoom {
+                room: arg(0, "lr <room>")?,
+            },
+            "subscribed_peers" | "sp" => Command::SubscribedPeers {
+                room: arg(0, "sp <room>")?,
+            },
+            "block" => Command::Block {
+                peer: arg(0, "block <peer>")?,
+            },
+            "unblock" => Command::Unblock {
+                peer: arg(0, "unblock <peer>")?,
+            },
+            "nick" => Command::Nick {
+                room: arg(0, "nick <room> <name?>")?,
+                name: args
+                    .get(1..)
+                    .filter(|rest| !rest.is_empty())
+                    .map(|rest| rest.join(" ")),
+            },
+            "forget" | "f" => Command::Forget {
+                room: arg(0, "f <room>")?,
+            },
+            "pin_key" | "pin" => Command::PinKey {
+                room: arg(0, "pin <room> <public key>")?,
+                key: arg(1, "pin <room> <public key>")?,
+            },
+            "history" | "h" => Command::History {
+                room: arg(0, "h <room>")?,
+            },
+            "prove" | "pr" => Command::ProveMembership {
+                room: arg(0, "pr <room>")?,
+            },
+            "members" | "mem" => Command::Members {
+                room: arg(0, "mem <room>")?,
+            },
+            "catch_up" | "cu" => Command::CatchUp {
+                room: arg(0, "cu <room> <messages>")?,
+                limit: arg(1, "cu <room> <messages>")?
+                    .parse()
+                    .map_err(|_| CommandError::Usage("cu <room> <messages>"))?,
+            },
+            "request_public_key" | "rpk" => Command::RequestPublicKey {
+                room: arg(0, "rpk <room>")?,
+            },
+            "shared_secret_exchange" | "sse" => Command::SharedSecretExchange {
+                room: arg(0, "sse <room>")?,
+            },
+            "shared_secret_communication" | "ssc" => Command::SharedSecretCommunication {
+                room: arg(0, "ssc <room> <message>")?,
+                message: message(),
+                signed: false,
+            },
+            "signed_shared_secret_communication" | "sssc" => Command::SharedSecretCommunication {
+                room: arg(0, "sssc <room> <message>")?,
+                message: message(),
+                signed: true,
+            },
+            "send_file" | "sf" => Command::SendFile {
+                room: arg(0, "sf <room> <path>")?,
+                path: arg(1, "sf <room> <path>")?,
+            },
+            "group_key" | "gk" => Command::GroupKey {
+                room: arg(0, "gk <room>")?,
+            },
+            "share_group_key" | "sgk" => Command::ShareGroupKey {
+                room: arg(0, "sgk <room> <peer>")?,
+                peer_room: arg(1, "sgk <room> <peer>")?,
+            },
+            "group_message" | "gm" => Command::GroupMessage {
+                room: arg(0, "gm <room> <message>")?,
+                message: message(),
+            },
+            "chat" | "c" => Command::Chat {
+                room: arg(0, "c <room> <message>")?,
+                message: message(),
+            },
+            "broadcast" | "bc" if args.is_empty() => {
+                return Err(CommandError::Usage("bc <message>"));
+            }
+            "broadcast" | "bc" => Command::Broadcast {
+                message: args.join(" "),
+            },
+            cmd => return Err(CommandError::Unknown(cmd.to_string())),
+        };
+        Ok(command)
+    }
+}
+
+// Splits on whitespace, except inside double quotes. A backslash takes the next character literally, so quotes can
+// be part of an argument.
+pub fn tokenize(line: &str) -> Result<Vec<String>, CommandError> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    // `""` is an empty argument, not no argument
+    let mut in_token = false;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                token.push(chars.next().unwrap_or('\\'));
+                in_token = true;
+            }
+            '"' => {
+                quoted = !quoted;
+                in_token = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            c => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(CommandError::UnterminatedQuote);
+    }
+    if in_token {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// Rejects a room argument `join_room` would refuse, before it's sent anywhere
+fn room_name(room: String) -> Result<String, CommandError> {
+    if room::valid_room_name(&room) {
+        Ok(room)
+    } else {
+        Err(CommandError::BadRoomName(room))
+    }
+}
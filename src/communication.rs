@@ -1,30 +1,133 @@
-use crate::gossip::{GenerateRoomName, message::MessageData, room::Room};
-use libp2p::PeerId;
+use crate::gossip::{GenerateRoomName, message::MessageData, room::RoomKind};
+use libp2p::{PeerId, gossipsub::MessageId};
 use oqs::{kem, sig};
 use serde::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum InteractionMessage {
-    Ping,
+    // the nonce is echoed back in the Pong so the round trip can be timed
+    Ping(u64),
+    Pong(u64),
     RequestPublicKey,
     ReplyPublicKey(sig::PublicKey),
     SharedSecretExchange(SharedSecretExchange),
     SharedSecretExchangeResponse(SharedSecretExchangeResponse),
     SharedSecretCommunication(([u8; 12], Vec<u8>)),
-    Other(String),
+    // like SharedSecretCommunication, but the plaintext carries the sender's signature, see `Secret::encrypt_signed`
+    SignedSharedSecretCommunication(([u8; 12], Vec<u8>)),
+    // sent back once a SharedSecretCommunication has been decrypted
+    Ack(MessageId),
+    // the symmetric key of a room, encrypted with the pairwise shared secret
+    GroupKeyShare(GroupKeyShare),
+    GroupMessage(([u8; 12], Vec<u8>)),
+    FileChunk(FileChunk),
+    // we replaced our signing key, see `Secret::rotate_signing_key`
+    KeyRotation(KeyRotation),
+    // signed claim of the sender that it's in a room, see `Gossip::prove_membership`
+    MembershipProof(MembershipProof),
+    // the latest messages of a room, sent to a peer that just joined it, see `Gossip::set_catch_up`
+    CatchUp(CatchUp),
+    // the public rooms the sender is in, sent to PUBLIC_DIRECTORY every ROOM_ANNOUNCE_INTERVAL
+    RoomAnnounce(Vec<String>),
+    // plain text in a public room
+    PublicChat(String),
+    // a valid message that doesn't make sense where it was received
+    Unknown { raw: String },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+pub struct GroupKeyShare {
+    pub room: String,
+    pub key: ([u8; 12], Vec<u8>),
+}
+
+impl GroupKeyShare {
+    pub fn new(room: String, key: ([u8; 12], Vec<u8>)) -> Self {
+        Self { room, key }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileChunk {
+    pub id: u64,
+    pub seq: u32,
+    pub total: u32,
+    pub nonce: [u8; 12],
+    // encrypted with the pairwise shared secret
+    pub data: Vec<u8>,
+}
+
+// `timestamp` is in unix milliseconds, it's signed along with the room so a proof goes stale
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MembershipProof {
+    pub room: String,
+    pub timestamp: u64,
+    pub signature: sig::Signature,
+}
+
+impl MembershipProof {
+    pub fn new(room: String, timestamp: u64, signature: sig::Signature) -> Self {
+        Self {
+            room,
+            timestamp,
+            signature,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CatchUp {
+    pub room: String,
+    // oldest first
+    pub messages: Vec<CatchUpMessage>,
+}
+
+// A message from the history of the sender, `peer` is who the sender says wrote it and can't be verified
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CatchUpMessage {
+    pub peer: PeerId,
+    pub message: String,
+}
+
+// The new signing key, signed with the old one so peers that pinned the old key can trust it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyRotation {
+    pub old_pk: sig::PublicKey,
+    pub new_pk: sig::PublicKey,
+    pub signature: sig::Signature,
+}
+
+impl KeyRotation {
+    pub fn new(old_pk: sig::PublicKey, new_pk: sig::PublicKey, signature: sig::Signature) -> Self {
+        Self {
+            old_pk,
+            new_pk,
+            signature,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SharedSecretExchange {
+    // the algorithms the sender uses, so the receiver can tell an incompatible peer apart from a bad signature
+    pub sig_algorithm: sig::Algorithm,
+    pub kem_algorithm: kem::Algorithm,
     pub kem_pk: kem::PublicKey,
     pub signature: sig::Signature,
     pub pk: sig::PublicKey,
 }
 
 impl SharedSecretExchange {
-    pub fn new(kem_pk: kem::PublicKey, signature: sig::Signature, pk: sig::PublicKey) -> Self {
+    pub fn new(
+        algorithms: (sig::Algorithm, kem::Algorithm),
+        kem_pk: kem::PublicKey,
+        signature: sig::Signature,
+        pk: sig::PublicKey,
+    ) -> Self {
         Self {
+            sig_algorithm: algorithms.0,
+            kem_algorithm: algorithms.1,
             kem_pk,
             signature,
             pk,
@@ -49,41 +152,73 @@ impl SharedSecretExchangeResponse {
     }
 }
 
+// bytes of a malformed payload shown in GetDataViaMessageError::Serde
+static PREVIEW_LEN: usize = 32;
+
 #[derive(Debug)]
 pub enum GetDataViaMessageError {
     NotOurChannel,
-    Serde(SerdeError),
-}
-impl From<serde_json::Error> for GetDataViaMessageError {
-    fn from(err: serde_json::Error) -> Self {
-        GetDataViaMessageError::Serde(err)
-    }
+    // `preview` is the start of the payload in hex
+    Serde {
+        peer: PeerId,
+        error: SerdeError,
+        preview: String,
+    },
 }
 
 impl InteractionMessage {
+    // `kind` is the kind of the room the message arrived in, messages that don't belong there come out as Unknown
     pub fn from_msg(
         self_peer_id: PeerId,
         message_data: &MessageData,
+        kind: RoomKind,
     ) -> Result<Self, GetDataViaMessageError> {
-        match (
-            &message_data.room,
-            serde_json::from_str(&message_data.message)?,
-        ) {
-            (_, Self::Ping) => Ok(Self::Ping),
-            (Room::PublicRoom(_), e) => Ok(Self::Other(format!("Public room: {:?}", e))),
-            // we can't have request public key in public room, because the group gets flooded with everyone saying their public keys
-            (_, Self::RequestPublicKey) => Ok(Self::RequestPublicKey),
-            (_, Self::ReplyPublicKey(e)) => Ok(Self::ReplyPublicKey(e)),
-            (_, Self::SharedSecretExchange(e)) => {
-                if self_peer_id.generate_room_name() != message_data.room.name() {
-                    // we don't care if it's not in our channel
-                    return Err(GetDataViaMessageError::NotOurChannel);
-                }
-                return Ok(Self::SharedSecretExchange(e));
+        let message: Self = match serde_json::from_str(&message_data.message) {
+            Ok(message) => message,
+            // people typing into a public room from other clients send plain text
+            Err(_) if kind == RoomKind::Public => {
+                return Ok(Self::PublicChat(message_data.message.clone()));
+            }
+            Err(error) => {
+                return Err(GetDataViaMessageError::Serde {
+                    peer: message_data.peer,
+                    error,
+                    preview: hex_preview(message_data.message.as_bytes()),
+                });
             }
-            (_, Self::SharedSecretExchangeResponse(e)) => Ok(Self::SharedSecretExchangeResponse(e)),
-            (_, Self::SharedSecretCommunication(e)) => Ok(Self::SharedSecretCommunication(e)),
-            (_, Self::Other(e)) => Ok(Self::Other(e)),
+        };
+        message.admit(self_peer_id, message_data, kind)
+    }
+    // The checks of `from_msg` for a message that was deserialized some other way, e.g. from CBOR
+    pub fn admit(
+        self,
+        self_peer_id: PeerId,
+        message_data: &MessageData,
+        kind: RoomKind,
+    ) -> Result<Self, GetDataViaMessageError> {
+        if !kind.accepts(&self) {
+            return Ok(Self::Unknown {
+                raw: message_data.message.clone(),
+            });
+        }
+        if let Self::SharedSecretExchange(_) = self
+            && self_peer_id.generate_room_name() != message_data.room.name()
+        {
+            // we don't care if it's not in our channel
+            return Err(GetDataViaMessageError::NotOurChannel);
         }
+        Ok(self)
+    }
+}
+
+fn hex_preview(data: &[u8]) -> String {
+    let mut preview: String = data
+        .iter()
+        .take(PREVIEW_LEN)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if data.len() > PREVIEW_LEN {
+        preview.push_str("...");
     }
+    preview
 }
@@ -0,0 +1,71 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Where timeouts, expiry and message timestamps get the time from. The two have to move together, a clock that
+// advances one but not the other makes messages look older or newer than the timeouts around them.
+pub trait Clock: Debug + Send + Sync {
+    // monotonic, for measuring how long something took
+    fn now(&self) -> Instant;
+    // wall clock, for timestamps that go over the wire
+    fn unix_millis(&self) -> u64;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn unix_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+// Stands still until `advance` is called, so a test can step over a timeout instead of sleeping through it
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    start: Instant,
+    unix_start: u64,
+    // shared between clones, a test keeps one and advances the clock of whatever it handed the others to
+    elapsed: Arc<Mutex<Duration>>,
+}
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl MockClock {
+    // Starts at the current time
+    pub fn new() -> Self {
+        Self::starting_at(SystemClock.unix_millis())
+    }
+    // Starts with the wall clock at `unix_millis`, for timestamps that are the same on every run
+    pub fn starting_at(unix_millis: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            unix_start: unix_millis,
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed() += by;
+    }
+    fn elapsed(&self) -> std::sync::MutexGuard<'_, Duration> {
+        // a poisoned lock only means a panic while advancing, the duration itself is still valid
+        self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed()
+    }
+    fn unix_millis(&self) -> u64 {
+        self.unix_start + self.elapsed().as_millis() as u64
+    }
+}
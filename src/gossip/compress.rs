@@ -0,0 +1,55 @@
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use std::io::{Read, Write};
+
+// Payload framing, the first byte tells how the rest is encoded
+static FRAME_RAW: u8 = 0;
+static FRAME_DEFLATE: u8 = 1;
+pub static DEFAULT_COMPRESS_THRESHOLD: usize = 1024;
+// a small deflated payload can expand to a lot, anything inflating past this is dropped
+static MAX_INFLATED_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum CompressError {
+    // the payload was empty or started with an unknown frame byte
+    UnknownFrame(Option<u8>),
+    Inflate(std::io::Error),
+    TooLarge,
+}
+
+// Deflates payloads of at least `threshold` bytes, None never compresses. A payload deflate can't shrink is sent raw.
+pub fn frame(payload: &[u8], threshold: Option<usize>) -> Vec<u8> {
+    if threshold.is_some_and(|threshold| payload.len() >= threshold)
+        && let Some(deflated) = deflate(payload)
+        && deflated.len() < payload.len()
+    {
+        return [&[FRAME_DEFLATE], &deflated[..]].concat();
+    }
+    [&[FRAME_RAW], payload].concat()
+}
+
+pub fn unframe(data: &[u8]) -> Result<Vec<u8>, CompressError> {
+    match data.split_first() {
+        Some((&kind, payload)) if kind == FRAME_RAW => Ok(payload.to_vec()),
+        Some((&kind, payload)) if kind == FRAME_DEFLATE => inflate(payload),
+        Some((&kind, _)) => Err(CompressError::UnknownFrame(Some(kind))),
+        None => Err(CompressError::UnknownFrame(None)),
+    }
+}
+
+fn deflate(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload).ok()?;
+    encoder.finish().ok()
+}
+
+fn inflate(payload: &[u8]) -> Result<Vec<u8>, CompressError> {
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(payload)
+        .take(MAX_INFLATED_SIZE + 1)
+        .read_to_end(&mut inflated)
+        .map_err(CompressError::Inflate)?;
+    if inflated.len() as u64 > MAX_INFLATED_SIZE {
+        return Err(CompressError::TooLarge);
+    }
+    Ok(inflated)
+}
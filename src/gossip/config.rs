@@ -0,0 +1,105 @@
+use libp2p::{Multiaddr, connection_limits::ConnectionLimits, gossipsub};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::{
+    MAX_MESSAGE_SIZE, SecurityTransport,
+    clock::{Clock, SystemClock},
+    compress::DEFAULT_COMPRESS_THRESHOLD,
+    rate_limit::RateLimit,
+    wire::WireFormat,
+};
+
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    // how often gossipsub maintains its mesh, not to be confused with the DM keepalive of `Gossip::heartbeat`
+    pub heartbeat_interval: Duration,
+    pub validation_mode: gossipsub::ValidationMode,
+    // anything bigger has to go through send_file. Has to fit a handshake, which grows with the post-quantum
+    // algorithms, see MAX_MESSAGE_SIZE
+    pub max_transmit_size: usize,
+    // number of peers gossipsub keeps in the mesh of a topic, and the bounds it lets it drift within
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    // inbound messages per peer, enforced by us rather than gossipsub
+    pub rate_limit: RateLimit,
+    // rooms we can be in at once, our own DM room included
+    pub max_rooms: usize,
+    pub security: SecurityTransport,
+    // payloads at least this big are deflated before sending, None sends everything as is. Peers decompress either
+    // way.
+    pub compress_threshold: Option<usize>,
+    // how we serialize what we send, peers read either format
+    pub wire_format: WireFormat,
+    // connections open at once, every one of them holds a socket. None is unlimited
+    pub max_established: Option<u32>,
+    // connections still being set up, counted for incoming and outgoing ones separately
+    pub max_pending: Option<u32>,
+    // a peer can reach us over TCP and QUIC and dial us while we dial it, so it's more than one
+    pub max_established_per_peer: Option<u32>,
+    // nodes ending in `/p2p/<peer id>` to find the DHT through, mdns only finds peers on the local network
+    pub bootstrap_nodes: Vec<Multiaddr>,
+    // what our timeouts and message timestamps go by, shared with `Secret` and `Nonce`. Tests hand in a `MockClock`
+    pub clock: Arc<dyn Clock>,
+}
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(1),
+            // enforce message signing, the author of a message is taken from its signature
+            validation_mode: gossipsub::ValidationMode::Strict,
+            max_transmit_size: MAX_MESSAGE_SIZE,
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            rate_limit: RateLimit::default(),
+            max_rooms: 256,
+            security: SecurityTransport::default(),
+            compress_threshold: Some(DEFAULT_COMPRESS_THRESHOLD),
+            wire_format: WireFormat::default(),
+            // well below the usual limit of 1024 file descriptors, with room for a few hundred peers on a LAN
+            max_established: Some(256),
+            max_pending: Some(32),
+            max_established_per_peer: Some(4),
+            bootstrap_nodes: Vec::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+impl GossipConfig {
+    // Fails if the values don't fit together, e.g. `mesh_n` outside of `mesh_n_low..=mesh_n_high`
+    pub fn build(&self) -> Result<gossipsub::Config, gossipsub::ConfigBuilderError> {
+        gossipsub::ConfigBuilder::default()
+            .heartbeat_interval(self.heartbeat_interval)
+            .validation_mode(self.validation_mode.clone())
+            .message_id_fn(message_id) // content-address messages. No two messages of the same content will be propagated.
+            .max_transmit_size(self.max_transmit_size)
+            .mesh_n(self.mesh_n)
+            .mesh_n_low(self.mesh_n_low)
+            .mesh_n_high(self.mesh_n_high)
+            .build()
+    }
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits::default()
+            .with_max_established(self.max_established)
+            .with_max_pending_incoming(self.max_pending)
+            .with_max_pending_outgoing(self.max_pending)
+            .with_max_established_per_peer(self.max_established_per_peer)
+    }
+}
+
+// To content-address message, we can take the hash of message and use it as an ID.
+fn message_id(message: &gossipsub::Message) -> gossipsub::MessageId {
+    message_id_of(&message.data)
+}
+// The id gossipsub will give a message published with `data`
+pub(super) fn message_id_of(data: &[u8]) -> gossipsub::MessageId {
+    let mut s = DefaultHasher::new();
+    data.hash(&mut s);
+    gossipsub::MessageId::from(s.finish().to_string())
+}
@@ -1,9 +1,18 @@
 use super::{GossipEvent, MyBehaviourEvent};
-use libp2p::{Multiaddr, PeerId, gossipsub::Message, swarm::SwarmEvent};
+use libp2p::{
+    Multiaddr, PeerId,
+    gossipsub::{Message, MessageId},
+    swarm::SwarmEvent,
+};
 
 pub trait EventHandler {
     fn new_connections(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent>;
     fn new_disconnections(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent>;
-    fn message(&mut self, peer_id: PeerId, message: Message) -> Option<GossipEvent>;
+    fn message(
+        &mut self,
+        peer_id: PeerId,
+        message_id: MessageId,
+        message: Message,
+    ) -> Option<GossipEvent>;
     fn handle(&mut self, event: SwarmEvent<MyBehaviourEvent>) -> Option<GossipEvent>;
 }
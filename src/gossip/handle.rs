@@ -0,0 +1,185 @@
+use futures::StreamExt;
+use libp2p::{Multiaddr, PeerId};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::communication::InteractionMessage;
+
+use super::room::GossipRooms;
+use super::{Gossip, GossipError, GossipEvent, PublishOutcome};
+
+// commands and events queued between a GossipHandle and the task running the swarm, a full event channel holds the
+// task back until the application catches up
+pub static HANDLE_CHANNEL_CAPACITY: usize = 64;
+
+type Reply<T> = oneshot::Sender<Result<T, GossipError>>;
+
+enum HandleCommand {
+    Request(Request),
+    // kept apart from the requests, `run` has to await the shutdown and stop afterwards
+    Shutdown { reply: oneshot::Sender<()> },
+}
+
+// what `Gossip::execute` answers right away
+enum Request {
+    Send {
+        message: InteractionMessage,
+        room: String,
+        reply: Reply<PublishOutcome>,
+    },
+    JoinRoom {
+        room: String,
+        reply: Reply<()>,
+    },
+    LeaveRoom {
+        room: String,
+        reply: Reply<()>,
+    },
+    Dial {
+        addr: Multiaddr,
+        reply: Reply<()>,
+    },
+}
+
+// Talks to a Gossip running on its own task, see `Gossip::spawn`. Clones talk to the same task, which stops once
+// every handle is dropped or `shutdown` is called.
+#[derive(Clone)]
+pub struct GossipHandle {
+    peer_id: PeerId,
+    commands: mpsc::Sender<HandleCommand>,
+}
+impl GossipHandle {
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+    // Publishes `message` to `room` like `Gossip::try_gossip`. QueueFull, or InsufficientPeers right after joining, is
+    // worth retrying
+    pub async fn send(
+        &self,
+        message: InteractionMessage,
+        room: &str,
+    ) -> Result<PublishOutcome, GossipError> {
+        let room = room.to_string();
+        self.request(|reply| Request::Send {
+            message,
+            room,
+            reply,
+        })
+        .await
+    }
+    pub async fn join_room(&self, room: &str) -> Result<(), GossipError> {
+        let room = room.to_string();
+        self.request(|reply| Request::JoinRoom { room, reply })
+            .await
+    }
+    pub async fn leave_room(&self, room: &str) -> Result<(), GossipError> {
+        let room = room.to_string();
+        self.request(|reply| Request::LeaveRoom { room, reply })
+            .await
+    }
+    pub async fn dial(&self, addr: Multiaddr) -> Result<(), GossipError> {
+        self.request(|reply| Request::Dial { addr, reply }).await
+    }
+    // Runs `Gossip::shutdown` on the task and waits for it to finish, the other handles get Stopped from then on
+    pub async fn shutdown(self) {
+        let (reply, done) = oneshot::channel();
+        if self
+            .commands
+            .send(HandleCommand::Shutdown { reply })
+            .await
+            .is_ok()
+        {
+            let _ = done.await;
+        }
+    }
+    async fn request<T>(
+        &self,
+        request: impl FnOnce(Reply<T>) -> Request,
+    ) -> Result<T, GossipError> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(HandleCommand::Request(request(reply)))
+            .await
+            .map_err(|_| GossipError::Stopped)?;
+        result.await.map_err(|_| GossipError::Stopped)?
+    }
+}
+
+impl Gossip {
+    // Moves the node onto a task of its own, for applications that can't drive the swarm from their event loop.
+    // Join rooms and open ears before, or through the handle after. The task also runs `heartbeat`,
+    // `retry_listeners` and `retry_handshakes`, everything they and the swarm produce comes out of the receiver.
+    pub fn spawn(self) -> (GossipHandle, mpsc::Receiver<GossipEvent>) {
+        let (commands, command_receiver) = mpsc::channel(HANDLE_CHANNEL_CAPACITY);
+        let (events, event_receiver) = mpsc::channel(HANDLE_CHANNEL_CAPACITY);
+        let handle = GossipHandle {
+            peer_id: self.peer_id(),
+            commands,
+        };
+        tokio::spawn(self.run(command_receiver, events));
+        (handle, event_receiver)
+    }
+    async fn run(
+        mut self,
+        mut commands: mpsc::Receiver<HandleCommand>,
+        events: mpsc::Sender<GossipEvent>,
+    ) {
+        let mut heartbeat = tokio::time::interval(self.heartbeat_interval);
+        loop {
+            let retry_at = self.next_listener_retry();
+            let handshake_retry_at = self.next_handshake_retry();
+            let produced: Vec<GossipEvent> = tokio::select! {
+                command = commands.recv() => match command {
+                    Some(HandleCommand::Shutdown { reply }) => {
+                        self.shutdown().await;
+                        let _ = reply.send(());
+                        return;
+                    }
+                    Some(HandleCommand::Request(request)) => {
+                        self.execute(request);
+                        Vec::new()
+                    }
+                    // every handle is gone, nobody can tell us what to do anymore
+                    None => break,
+                },
+                event = self.swarm.select_next_some() => self.handle_event(event).into_iter().collect(),
+                _ = heartbeat.tick() => self.heartbeat(),
+                _ = tokio::time::sleep_until(retry_at.unwrap_or_else(Instant::now).into()), if retry_at.is_some() => {
+                    self.retry_listeners().into_iter().collect()
+                }
+                _ = tokio::time::sleep_until(handshake_retry_at.unwrap_or_else(Instant::now).into()), if handshake_retry_at.is_some() => {
+                    self.retry_handshakes()
+                }
+            };
+            for event in produced.into_iter().chain(self.take_deferred_events()) {
+                // nobody listening for events is fine, the handle can still be used
+                let _ = events.send(event).await;
+            }
+        }
+        self.shutdown().await;
+    }
+    fn execute(&mut self, request: Request) {
+        match request {
+            Request::Send {
+                message,
+                room,
+                reply,
+            } => {
+                let result = match self.get_topic_from_name(&room) {
+                    Some(topic) => self.try_gossip(&message, topic).map_err(GossipError::from),
+                    None => Err(GossipError::NotJoined(room)),
+                };
+                let _ = reply.send(result);
+            }
+            Request::JoinRoom { room, reply } => {
+                let _ = reply.send(self.join_room(&room));
+            }
+            Request::LeaveRoom { room, reply } => {
+                let _ = reply.send(self.leave_room(&room));
+            }
+            Request::Dial { addr, reply } => {
+                let _ = reply.send(self.dial(addr).map_err(GossipError::from));
+            }
+        }
+    }
+}
@@ -1,24 +1,30 @@
-use std::error::Error;
-
-use libp2p::Multiaddr;
-use libp2p::gossipsub::{Event, Message, TopicHash};
+use libp2p::gossipsub::{Event, Message, MessageId, TopicHash};
 use libp2p::mdns::Event::{Discovered, Expired};
-use libp2p::swarm::SwarmEvent;
+use libp2p::swarm::{DialError, ListenError, SwarmEvent};
+use libp2p::{Multiaddr, dcutr, identify, kad, relay};
 use libp2p::{PeerId, gossipsub::IdentTopic};
 
-use crate::communication::InteractionMessage;
+use crate::communication::{GetDataViaMessageError, InteractionMessage};
 
+use super::compress;
 use super::events::EventHandler;
 use super::message::MessageData;
-use super::nonce::Nonce;
-use super::room::{GossipRooms, Room};
-use super::{Gossip, GossipEvent, MyBehaviourEvent};
+use super::nonce::NonceError;
+use super::room::{self, GossipRooms, Room, RoomKind, RoomLookupError};
+use super::wire::{self, WireFormat};
+use super::{
+    GenerateRoomName, Gossip, GossipError, GossipEvent, MyBehaviourEvent, connection_limit_reached,
+};
 
 impl GossipRooms for Gossip {
-    fn get_peer_from_room_name(&self, room_name: &str) -> Option<&PeerId> {
-        self.peer_ids
-            .iter()
-            .find(|id| id.to_string().contains(&room_name))
+    fn get_peer_from_room_name(&self, room_name: &str) -> Result<&PeerId, RoomLookupError> {
+        let Some(peers) = self.room_peers.get(room_name) else {
+            return Err(RoomLookupError::NotFound);
+        };
+        if peers.len() > 1 {
+            return Err(RoomLookupError::Ambiguous(peers.iter().copied().collect()));
+        }
+        peers.iter().next().ok_or(RoomLookupError::NotFound)
     }
     fn get_topic_from_name(&self, topic_self: &str) -> Option<IdentTopic> {
         for (room_name, room) in self.topics.iter() {
@@ -28,26 +34,52 @@ impl GossipRooms for Gossip {
         }
         None
     }
-    fn join_room(&mut self, topic_str: &str) -> Result<(), Box<dyn Error>> {
+    fn join_room(&mut self, topic_str: &str) -> Result<(), GossipError> {
+        if self.get_topic_from_name(topic_str).is_some() {
+            // already joined, this also keeps the auto-joined DM room from being added twice
+            return Ok(());
+        }
+        if !room::valid_room_name(topic_str) {
+            return Err(GossipError::InvalidRoomName(topic_str.to_string()));
+        }
+        if self.topics.len() >= self.max_rooms {
+            return Err(GossipError::TooManyRooms(self.max_rooms));
+        }
         let topic = IdentTopic::new(topic_str);
         self.topics.push((topic_str.to_string(), topic.clone()));
+        self.topic_names.insert(topic.hash(), topic_str.to_string());
 
         self.swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
         Ok(())
     }
-    fn leave_room(&mut self, topic_str: &str) -> Result<(), Box<dyn Error>> {
-        let topic = IdentTopic::new(topic_str);
+    fn join_public_room(&mut self, name: &str) -> Result<String, GossipError> {
+        let Some(room_name) = room::public_room_name(name) else {
+            return Err(GossipError::InvalidRoomName(name.to_string()));
+        };
+        self.join_room(&room_name)?;
+        Ok(room_name)
+    }
+    fn join_dm_room(&mut self, peer: PeerId) -> Result<String, GossipError> {
+        let room_name = peer.generate_room_name();
+        self.join_room(&room_name)?;
+        Ok(room_name)
+    }
+    fn leave_room(&mut self, topic_str: &str) -> Result<(), GossipError> {
+        let Some(topic) = self.get_topic_from_name(topic_str) else {
+            return Err(GossipError::NotJoined(topic_str.to_string()));
+        };
         self.topics.retain(|(t, _)| t != topic_str);
-        let _ = self.swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
+        self.topic_names.remove(&topic.hash());
+        // peers are looked up by room name, a room we left shouldn't resolve to anyone
+        self.room_peers.remove(topic_str);
+        self.room_keys.remove(topic_str);
+        self.room_members.remove(topic_str);
+        self.swarm.behaviour_mut().gossipsub.unsubscribe(&topic);
         Ok(())
     }
-    fn get_room_from_hash(&self, topic: TopicHash) -> Room {
-        for t in &self.topics {
-            if t.1.hash() == topic {
-                return self.get_room_from_name(t.0.clone());
-            }
-        }
-        panic!("Topic not found");
+    fn get_room_from_hash(&self, topic: TopicHash) -> Option<Room> {
+        let name = self.topic_names.get(&topic)?;
+        Some(self.get_room_from_name(name.clone()))
     }
     fn get_room_from_name(&self, topic: String) -> Room {
         if topic.starts_with("public_") {
@@ -55,22 +87,34 @@ impl GossipRooms for Gossip {
         }
         Room::DirectMessage(topic)
     }
+    fn list_rooms(&self) -> Vec<Room> {
+        self.topics
+            .iter()
+            .map(|(room_name, _)| self.get_room_from_name(room_name.clone()))
+            .collect()
+    }
 }
 
 impl EventHandler for Gossip {
     fn new_connections(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent> {
         let mut peers = Vec::with_capacity(list.len());
-        for (peer_id, _multiaddr) in list {
+        for (peer_id, multiaddr) in list {
             self.swarm
                 .behaviour_mut()
                 .gossipsub
                 .add_explicit_peer(&peer_id);
+            let now = self.clock.now();
+            self.peer_info(peer_id).seen(multiaddr, now);
             peers.push(peer_id);
         }
+        tracing::debug!("Discovered {:?}", peers);
         for peer in peers.iter() {
-            self.peer_ids.insert(peer.clone());
+            self.room_peers
+                .entry(peer.generate_room_name())
+                .or_default()
+                .insert(*peer);
         }
-        return Some(GossipEvent::NewConnection(peers));
+        Some(GossipEvent::NewConnection(peers))
     }
     fn new_disconnections(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent> {
         let mut peers = Vec::with_capacity(list.len());
@@ -81,42 +125,169 @@ impl EventHandler for Gossip {
                 .remove_explicit_peer(&peer_id);
             peers.push(peer_id);
         }
+        tracing::debug!("Expired {:?}", peers);
         for peer in peers.iter() {
-            self.peer_ids.remove(peer);
+            self.group_member_left(peer, None);
+            let now = self.clock.now();
+            if let Some(info) = self.peers.get_mut(peer) {
+                info.gone(now);
+            }
+            let room_name = peer.generate_room_name();
+            if let Some(room_peers) = self.room_peers.get_mut(&room_name) {
+                room_peers.remove(peer);
+                if room_peers.is_empty() {
+                    self.room_peers.remove(&room_name);
+                }
+            }
         }
-        return Some(GossipEvent::Disconnection(peers));
+        Some(GossipEvent::Disconnection(peers))
     }
-    fn message(&mut self, peer_id: PeerId, message: Message) -> Option<GossipEvent> {
-        let is_public_room = message.topic.to_string().starts_with("public_");
-        let is_message_by_the_dm_op = peer_id.to_string().contains(&message.topic.to_string());
-        let is_message_in_self_dm = self
-            .peer_id()
-            .to_string()
-            .contains(&message.topic.to_string());
-        // Messages to ignore
-        // Private Room: Other DM's, other's messages
-        // Messages to allow
-        // Public Rooms
-        // Private Room: DM OP's messages
-        // FTF: Valid
-        // FFF: Invalid
-        // T__: Valid
-        if !is_public_room && !is_message_by_the_dm_op && !is_message_in_self_dm {
-            // probably someone asking the OP something, we don't care
+    fn message(
+        &mut self,
+        peer_id: PeerId,
+        message_id: MessageId,
+        message: Message,
+    ) -> Option<GossipEvent> {
+        let _span =
+            tracing::info_span!("message", peer = %peer_id, room = %message.topic).entered();
+        // gossipsub can still deliver messages for a topic we just left
+        let Some(room) = self.get_room_from_hash(message.topic.clone()) else {
+            tracing::debug!("Message received on unknown topic");
+            return None;
+        };
+        self.metrics.received(message.data.len());
+        let kind = self.room_kind(&room);
+        // sequence numbers and everything bound to a peer go by the author, the propagation source may only be
+        // relaying
+        let author = message.source.unwrap_or(peer_id);
+        // in a DM room only our own and the messages of the peer it belongs to concern us, the rest is probably
+        // someone asking the owner something
+        if kind == RoomKind::DirectMessage
+            && !room
+                .owner()
+                .is_some_and(|owner| owner == self.peer_id() || owner == author)
+        {
             return None;
         }
-        let data = Nonce::remove_nonce(&message.data);
-        let content = String::from_utf8_lossy(&data);
-        let msg_data = MessageData {
-            peer: peer_id,
-            message: content.to_string(),
-            room: self.get_room_from_hash(message.topic),
+        let data = match self.room_key(&message.topic) {
+            // dropped if sent by someone without the passphrase of the room
+            Some(key) => room::verify_payload(key, &message.data)?,
+            None => &message.data[..],
+        };
+        let (format, data) = match wire::untag(data) {
+            Ok(untagged) => untagged,
+            Err(e) => {
+                tracing::warn!("Message in an unknown format dropped: {:?}", e);
+                return None;
+            }
+        };
+        let Some(seq) = self.nonce.get_seq(data) else {
+            tracing::warn!("Message too short to carry a sequence number");
+            return None;
         };
-        let Ok(interaction) = InteractionMessage::from_msg(self.peer_id(), &msg_data) else {
-            println!("Error parsing message: {:?}", msg_data);
+        if self.is_blocked(&peer_id) || self.is_blocked(&author) {
+            return None;
+        }
+        if let Err(blocked) = self.within_rate_limit(author) {
+            return blocked.then_some(GossipEvent::PeerGraylisted(author));
+        }
+        if let Err(NonceError::OutOfOrder { seq, last }) = self.nonce.check_seq(author, seq) {
+            tracing::debug!(%author, seq, last, "Duplicate or out of order message dropped");
+            self.metrics.replays_dropped += 1;
             return None;
+        }
+        self.heard_from(author);
+        let data = match self.nonce.remove_nonce_checked(data, self.max_message_age) {
+            Ok(data) => data,
+            Err(NonceError::Expired { age }) => {
+                tracing::warn!("Stale message dropped, sent {:?} ago", age);
+                self.metrics.replays_dropped += 1;
+                return None;
+            }
+            Err(_) => return None,
+        };
+        let data = match compress::unframe(&data) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::warn!("Undecodable payload dropped: {:?}", e);
+                return None;
+            }
         };
-        return Some(GossipEvent::Message((msg_data, interaction)));
+        let (content, parsed) = match format {
+            WireFormat::Json => (String::from_utf8_lossy(&data).to_string(), None),
+            WireFormat::Cbor => match WireFormat::deserialize_cbor(&data) {
+                // the JSON form stands in for the text, filters and history read the same whatever the sender used
+                Ok(parsed) => (
+                    serde_json::to_string(&parsed).unwrap_or_default(),
+                    Some(parsed),
+                ),
+                Err(e) => {
+                    tracing::warn!("Undecodable CBOR payload dropped: {:?}", e);
+                    return None;
+                }
+            },
+        };
+        let msg_data = MessageData {
+            id: message_id,
+            peer: author,
+            message: content,
+            room,
+            nickname: self.nickname(&author).map(str::to_string),
+        };
+        let interaction = match parsed {
+            Some(parsed) => parsed.admit(self.peer_id(), &msg_data, kind),
+            None => InteractionMessage::from_msg(self.peer_id(), &msg_data, kind),
+        };
+        let interaction = match interaction {
+            Ok(interaction) => interaction,
+            Err(GetDataViaMessageError::NotOurChannel) => return None,
+            Err(e) => {
+                tracing::warn!("Error parsing message: {:?}", e);
+                return None;
+            }
+        };
+        if let InteractionMessage::Ack(id) = &interaction {
+            self.pending_acks.remove(id);
+        }
+        if let InteractionMessage::Pong(nonce) = &interaction {
+            self.receive_pong(author, *nonce);
+        }
+        match interaction {
+            // the chunk is bound to its sender by the AAD, see `chunk_aad`
+            InteractionMessage::FileChunk(chunk) => return self.receive_file_chunk(author, chunk),
+            InteractionMessage::ReplyPublicKey(public_key) => {
+                return self.receive_public_key(author, &message.topic, public_key);
+            }
+            // bound to the author, the signing key belongs to whoever signed the gossipsub message
+            InteractionMessage::KeyRotation(rotation) => {
+                return self.receive_key_rotation(author, rotation);
+            }
+            // bound to the author like key rotations
+            InteractionMessage::MembershipProof(proof) => {
+                return self.receive_membership_proof(author, &msg_data.room, proof);
+            }
+            InteractionMessage::CatchUp(catch_up) => {
+                return self.receive_catch_up(author, catch_up);
+            }
+            InteractionMessage::RoomAnnounce(rooms) => {
+                self.receive_room_announce(author, rooms);
+                return None;
+            }
+            InteractionMessage::SharedSecretExchange(exchange) => {
+                return self.receive_shared_secret(author, exchange);
+            }
+            InteractionMessage::SharedSecretExchangeResponse(response) => {
+                return self.receive_shared_secret_response(author, response);
+            }
+            _ => {}
+        }
+        if !self.passes_filter(&msg_data) {
+            tracing::debug!("Message dropped by the message filter");
+            return None;
+        }
+        tracing::info!("Message received");
+        self.record_history(&msg_data);
+        Some(GossipEvent::Message((msg_data, interaction)))
     }
     fn handle(&mut self, event: SwarmEvent<MyBehaviourEvent>) -> Option<GossipEvent> {
         match event {
@@ -128,11 +299,141 @@ impl EventHandler for Gossip {
             }
             SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(Event::Message {
                 propagation_source: peer_id,
-                message_id: _,
+                message_id,
                 message,
-            })) => self.message(peer_id, message),
-            SwarmEvent::NewListenAddr { address, .. } => {
-                println!("Local node is listening on {address}");
+            })) => self.message(peer_id, message_id, message),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+                ..
+            })) => self.peer_identified(peer_id, info),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(Event::Subscribed {
+                peer_id,
+                topic,
+            })) => {
+                let room = self.get_room_from_hash(topic)?;
+                self.send_catch_up(peer_id, &room);
+                Some(GossipEvent::Subscribed {
+                    peer: peer_id,
+                    room,
+                })
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(Event::Unsubscribed {
+                peer_id,
+                topic,
+            })) => {
+                let room = self.get_room_from_hash(topic)?;
+                self.group_member_left(&peer_id, Some(room.name()));
+                self.member_left(&peer_id, room.name());
+                // rooms announced by a peer that left the directory aren't refreshed anymore
+                if room.name() == room::PUBLIC_DIRECTORY {
+                    self.forget_announced_rooms(&peer_id);
+                }
+                Some(GossipEvent::Unsubscribed {
+                    peer: peer_id,
+                    room,
+                })
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                if endpoint.is_dialer() {
+                    // peers we dialed ourselves aren't announced by mdns, treat them like discovered ones
+                    self.new_connections(vec![(peer_id, endpoint.get_remote_address().clone())]);
+                }
+                // only the first connection to a peer changes whether we can reach it
+                if num_established.get() > 1 {
+                    return None;
+                }
+                self.connected.insert(peer_id);
+                Some(GossipEvent::PeerConnected(peer_id))
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                self.connected.remove(&peer_id);
+                Some(GossipEvent::PeerDisconnected(peer_id))
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id,
+                error: DialError::Denied { cause },
+                ..
+            } => connection_limit_reached(peer_id, &cause),
+            SwarmEvent::IncomingConnectionError {
+                error: ListenError::Denied { cause },
+                ..
+            } => connection_limit_reached(None, &cause),
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                tracing::debug!("Dialing {:?} failed: {}", peer_id, error);
+                None
+            }
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error,
+                ..
+            } => {
+                tracing::debug!(
+                    "Incoming connection from {} failed: {}",
+                    send_back_addr,
+                    error
+                );
+                None
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(
+                relay::client::Event::ReservationReqAccepted {
+                    relay_peer_id,
+                    renewal,
+                    ..
+                },
+            )) => {
+                // the relay client confirms the circuit address as external itself, renewals keep the same one
+                (!renewal).then_some(GossipEvent::RelayReserved(relay_peer_id))
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(
+                relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. },
+            )) => {
+                tracing::info!("Circuit through relay {} established", relay_peer_id);
+                None
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Relay(
+                relay::client::Event::InboundCircuitEstablished { src_peer_id, .. },
+            )) => {
+                tracing::info!("{} reached us through a relay", src_peer_id);
+                None
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Dcutr(dcutr::Event {
+                remote_peer_id,
+                result,
+            })) => match result {
+                Ok(_) => Some(GossipEvent::DirectConnectionUpgraded(remote_peer_id)),
+                // the relayed connection stays, so nothing is lost
+                Err(e) => {
+                    tracing::debug!("Hole punching to {} failed: {}", remote_peer_id, e);
+                    None
+                }
+            },
+            SwarmEvent::Behaviour(MyBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::GetClosestPeers(result),
+                step,
+                ..
+            })) => self.peer_lookup_progressed(id, result, step.last),
+            SwarmEvent::NewListenAddr { address, .. } => Some(GossipEvent::Listening(address)),
+            SwarmEvent::ListenerClosed {
+                addresses, reason, ..
+            } => {
+                tracing::warn!("Listener on {:?} closed: {:?}", addresses, reason);
+                self.listener_lost();
+                None
+            }
+            SwarmEvent::ListenerError { error, .. } => {
+                tracing::warn!("Listener error: {}", error);
+                self.listener_lost();
                 None
             }
             _ => None,
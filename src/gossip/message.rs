@@ -1,38 +1,39 @@
 use super::{
-    GenerateRoomName, Gossip,
+    Gossip,
     room::{GossipRooms, Room},
 };
 use crate::communication::InteractionMessage;
+use serde::Serialize;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct MessageData {
+    pub id: libp2p::gossipsub::MessageId,
     pub peer: libp2p::PeerId,
     pub message: String,
     pub room: Room,
+    // what we call the sender, see `Gossip::set_nickname`
+    pub nickname: Option<String>,
 }
 
 impl MessageData {
-    pub fn reply_to_peer(&self, gossip: &mut Gossip, message: &InteractionMessage) {
-        let room_name = self.peer.generate_room_name();
-        if let Err(e) = gossip.join_room(&room_name) {
-            println!("Error joining room: {e:?}");
-            return;
+    // The sender's nickname if we gave it one, its peer id otherwise
+    pub fn sender(&self) -> String {
+        match &self.nickname {
+            Some(nickname) => nickname.clone(),
+            None => self.peer.to_string(),
         }
-        let room_name = gossip.get_topic_from_name(&room_name);
-        let Some(room_name) = room_name else {
-            println!("Error getting room name");
-            return;
-        };
-        if let Err(e) = gossip.gossip(message, room_name) {
-            println!("Error sending shared secret exchange response: {e:?}");
+    }
+    pub fn reply_to_peer(&self, gossip: &mut Gossip, message: &InteractionMessage) {
+        if let Err(e) = gossip.send_to_peer(self.peer, message) {
+            tracing::warn!("Error replying to {}: {e:?}", self.peer);
         }
     }
     pub fn reply_to_room(&self, gossip: &mut Gossip, message: &InteractionMessage) {
-        if let Err(e) = gossip.gossip(
+        if let Err(e) = gossip.try_gossip(
             message,
-            gossip.get_topic_from_name(&self.room.name()).unwrap(),
+            gossip.get_topic_from_name(self.room.name()).unwrap(),
         ) {
-            println!("Error sending public key: {e:?}");
+            tracing::warn!("Error replying in {}: {e:?}", self.room);
         }
     }
 }
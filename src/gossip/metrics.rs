@@ -0,0 +1,29 @@
+use serde::Serialize;
+
+// Counters for monitoring a running node, see `Gossip::metrics`. Message and byte counts are gossipsub payloads as
+// they go over the wire, before decompression and without gossipsub's own framing.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Metrics {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub decrypt_failures: u64,
+    // duplicate, out of order and stale messages
+    pub replays_dropped: u64,
+    pub publish_errors: u64,
+    // filled in when the snapshot is taken
+    pub active_sessions: usize,
+    pub connected_peers: usize,
+}
+
+impl Metrics {
+    pub(crate) fn sent(&mut self, len: usize) {
+        self.messages_sent += 1;
+        self.bytes_sent += len as u64;
+    }
+    pub(crate) fn received(&mut self, len: usize) {
+        self.messages_received += 1;
+        self.bytes_received += len as u64;
+    }
+}
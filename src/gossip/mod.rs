@@ -1,79 +1,669 @@
+use futures::{Stream, StreamExt, stream};
 use libp2p::{
-    PeerId, gossipsub, mdns, noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
+    Multiaddr, PeerId, StreamProtocol,
+    connection_limits::{self, ConnectionLimits},
+    dcutr, gossipsub, identify, identity, kad, mdns,
+    multiaddr::Protocol,
+    noise, relay,
+    swarm::{ConnectionDenied, DialError, NetworkBehaviour, SwarmEvent, dial_opts::DialOpts},
+    tcp, tls, yamux,
 };
 use std::{
-    collections::{HashSet, hash_map::DefaultHasher},
+    collections::{HashMap, HashSet, VecDeque},
     error::Error,
     fmt::Display,
-    hash::{Hash, Hasher},
-    time::Duration,
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use tokio::io;
-use tracing_subscriber::EnvFilter;
 
-use crate::communication::InteractionMessage;
+use oqs::{kem, sig};
+use serde::Serialize;
+use zeroize::Zeroize;
 
+use crate::communication::{
+    CatchUp, CatchUpMessage, FileChunk, GroupKeyShare, InteractionMessage, KeyRotation,
+    MembershipProof, SharedSecretExchange, SharedSecretExchangeResponse,
+};
+
+pub mod clock;
+pub mod compress;
+pub mod config;
 pub mod events;
+pub mod handle;
 pub mod impls;
 pub mod message;
+pub mod metrics;
 pub mod nonce;
+pub mod peer;
+pub mod rate_limit;
 pub mod room;
 pub mod secret;
+pub mod transfer;
+pub mod verify_cache;
+pub mod wire;
 
+use clock::Clock;
+pub use config::GossipConfig;
 use events::EventHandler;
 use message::MessageData;
-use nonce::Nonce;
-use room::GossipRooms;
-use secret::Secret;
+use metrics::Metrics;
+use nonce::{DEFAULT_MAX_MESSAGE_AGE, Nonce};
+use peer::PeerInfo;
+use rate_limit::{RateLimit, TokenBucket};
+use room::{GossipRooms, PUBLIC_DIRECTORY, Room, RoomKind};
+use secret::{Secret, SecretError};
+use transfer::{
+    FILE_CHUNK_SIZE, FILE_TRANSFER_TIMEOUT, IncomingFile, MAX_BUFFERED_BYTES, MAX_FILE_CHUNKS,
+    MAX_FILE_SIZE, MAX_TRANSFERS_PER_PEER, TransferError, TransferProgress, chunk_aad,
+};
+use wire::{DecodeError, WireFormat};
+
+// upper bound for a single published message, file chunks are sized to stay below it. The largest messages are
+// the handshakes: a SharedSecretExchange carries a KEM public key, a signing key and a signature, and JSON spends
+// about 3.6 bytes on each of their bytes. With ML-DSA-87 and ML-KEM-1024 that's around 32 KiB. SLH-DSA signatures
+// need up to 180 KiB, Classic McEliece keys don't fit at all. Pick `GossipConfig::max_transmit_size` (and
+// `wire_format`, CBOR keeps bytes as they are) to match the algorithms of `Secret::with_algorithms`.
+pub static MAX_MESSAGE_SIZE: usize = 256 * 1024;
+// pongs arriving after this are not counted
+static PING_TIMEOUT: Duration = Duration::from_secs(30);
+// encrypted messages not acknowledged by then are given up on, the peer may never answer
+static ACK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// public key replies arriving after this are treated as unsolicited
+static PUBLIC_KEY_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+static DEFAULT_HISTORY_CAPACITY: usize = 100;
+static DEFAULT_OUTBOUND_CAPACITY: usize = 256;
+static DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+// heartbeats a DM peer can miss before it's considered dead
+static HEARTBEAT_MISSES: u32 = 3;
+// messages from a peer in a row that fail to decrypt before its session is considered stale, e.g. after it restarted
+static SESSION_FAILURE_LIMIT: u32 = 3;
+// a stale session is only replaced by a new handshake this often, so two peers can't keep tearing down each other's
+static REHANDSHAKE_COOLDOWN: Duration = Duration::from_secs(60);
+static IDENTIFY_PROTOCOL: &str = "/stockchain/0.1.0";
+// our own DHT rather than the public IPFS one, its peers wouldn't know anything about ours
+static KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/stockchain/kad/1.0.0");
+// how long we wait for the response to a SharedSecretExchange before `handshake_retry` kicks in
+static DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// how often we announce our public rooms in PUBLIC_DIRECTORY, and how long an announcement is trusted without being
+// repeated
+static ROOM_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+static ROOM_ANNOUNCE_TTL: Duration = Duration::from_secs(3 * 60);
+// rooms taken from a single announcement, the rest is ignored
+static MAX_ANNOUNCED_ROOMS: usize = 64;
+// how long `ping_all` waits for pongs before peers count as not responding
+static PING_SWEEP_TIMEOUT: Duration = Duration::from_secs(5);
+// how long `shutdown` keeps the swarm running so queued publishes and unsubscriptions reach the peers
+static SHUTDOWN_FLUSH: Duration = Duration::from_millis(500);
+
+// A SharedSecretExchange we sent and haven't got a response to yet
+struct PendingHandshake {
+    exchange: SharedSecretExchange,
+    // sends so far, the first one included
+    attempt: u32,
+    retry_at: Instant,
+}
+
+// Pings sent by `ping_all`, by nonce, and the round trips of the pongs that came back so far
+struct PingSweep {
+    nonces: HashMap<u64, PeerId>,
+    round_trips: HashMap<PeerId, Option<Duration>>,
+    deadline: Instant,
+}
 
 #[derive(NetworkBehaviour)]
 pub struct MyBehaviour {
     gossipsub: gossipsub::Behaviour,
     mdns: mdns::tokio::Behaviour,
+    identify: identify::Behaviour,
+    limits: connection_limits::Behaviour,
+    // reservations on relays, see `use_relay`
+    relay: relay::client::Behaviour,
+    // upgrades connections through a relay to direct ones by hole punching, does nothing without a relay
+    dcutr: dcutr::Behaviour,
+    // finds peers by id beyond the local network, see `find_peer`
+    kad: kad::Behaviour<kad::store::MemoryStore>,
 }
 
 #[derive(Debug)]
 pub enum GossipSendError {
     PublishError(gossipsub::PublishError),
     SerdeError(serde_json::Error),
+    CborError(ciborium::ser::Error<std::io::Error>),
+    // nobody subscribed to the topic before the timeout of `gossip_when_ready`
+    NotReady,
+    // the outbound queue holds `outbound_capacity` messages already, see `try_gossip`
+    QueueFull,
+    // the framed message is bigger than `GossipConfig::max_transmit_size`, gossipsub would refuse it
+    TooLarge { size: usize, max: usize },
+}
+impl Display for GossipSendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GossipSendError::PublishError(e) => write!(f, "Publish error: {}", e),
+            GossipSendError::SerdeError(e) => write!(f, "Serialization error: {}", e),
+            GossipSendError::CborError(e) => write!(f, "CBOR serialization error: {}", e),
+            GossipSendError::NotReady => write!(f, "Nobody subscribed to the room in time"),
+            GossipSendError::QueueFull => write!(f, "Outbound queue is full"),
+            GossipSendError::TooLarge { size, max } => write!(
+                f,
+                "Message of {} bytes is over the limit of {} bytes, send big payloads with send_file or raise \
+                 max_transmit_size (handshakes grow with the signature and KEM algorithms)",
+                size, max
+            ),
+        }
+    }
 }
 impl From<gossipsub::PublishError> for GossipSendError {
     fn from(err: gossipsub::PublishError) -> Self {
         GossipSendError::PublishError(err)
     }
 }
+impl From<ciborium::ser::Error<std::io::Error>> for GossipSendError {
+    fn from(err: ciborium::ser::Error<std::io::Error>) -> Self {
+        GossipSendError::CborError(err)
+    }
+}
 impl From<serde_json::Error> for GossipSendError {
     fn from(err: serde_json::Error) -> Self {
         GossipSendError::SerdeError(err)
     }
 }
 
+// What became of a published message. The peer count is taken when publishing, 0 means the message went nowhere.
+#[derive(Debug, Clone)]
+pub struct PublishOutcome {
+    pub message_id: gossipsub::MessageId,
+    pub mesh_peer_count: usize,
+}
+
+// Exponential backoff, for listening again after every listener closed and for resending unanswered handshakes
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    // None retries forever
+    pub max_attempts: Option<u32>,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+impl RetryPolicy {
+    // the default for handshakes, a peer that didn't answer three times is most likely gone
+    pub fn handshake() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(30),
+            max_attempts: Some(3),
+        }
+    }
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay)
+    }
+}
+
+// Decides whether a received message is passed on as GossipEvent::Message, see `Gossip::set_message_filter`
+pub type MessageFilter = Box<dyn Fn(&MessageData) -> bool + Send>;
+
+// how publishing one message to several rooms went, by room name
+pub type RoomOutcomes = Vec<(String, Result<PublishOutcome, GossipSendError>)>;
+
+// How TCP connections are encrypted and authenticated, both ends have to pick the same. QUIC always brings its own
+// TLS 1.3, so this only matters for TCP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SecurityTransport {
+    #[default]
+    Noise,
+    Tls,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportConfig {
+    #[default]
+    Tcp,
+    Quic,
+    Both,
+}
+impl TransportConfig {
+    pub fn uses_tcp(&self) -> bool {
+        matches!(self, TransportConfig::Tcp | TransportConfig::Both)
+    }
+    pub fn uses_quic(&self) -> bool {
+        matches!(self, TransportConfig::Quic | TransportConfig::Both)
+    }
+    // Listen on all interfaces of both IP stacks and whatever port the OS assigns. On a host without IPv6 the `::`
+    // addresses fail to bind, which `listen` tolerates as long as the IPv4 ones work.
+    pub fn default_addrs(&self) -> Vec<Multiaddr> {
+        let mut addrs = Vec::new();
+        for ip in [
+            Protocol::Ip4(Ipv4Addr::UNSPECIFIED),
+            Protocol::Ip6(Ipv6Addr::UNSPECIFIED),
+        ] {
+            if self.uses_tcp() {
+                addrs.push(Multiaddr::empty().with(ip.clone()).with(Protocol::Tcp(0)));
+            }
+            if self.uses_quic() {
+                addrs.push(
+                    Multiaddr::empty()
+                        .with(ip)
+                        .with(Protocol::Udp(0))
+                        .with(Protocol::QuicV1),
+                );
+            }
+        }
+        addrs
+    }
+    pub fn allows(&self, addr: &Multiaddr) -> bool {
+        if addr.iter().any(|p| matches!(p, Protocol::QuicV1)) {
+            return self.uses_quic();
+        }
+        self.uses_tcp()
+    }
+}
+
+#[derive(Debug)]
+pub enum GossipError {
+    // building the swarm or binding/dialing an address failed
+    Transport(Box<dyn Error + Send + Sync>),
+    Subscription(gossipsub::SubscriptionError),
+    AddressParse(libp2p::multiaddr::Error),
+    CryptoInit(Box<dyn Error + Send + Sync>),
+    Crypto(oqs::Error),
+    Secret(SecretError),
+    Identity(identity::DecodingError),
+    Io(std::io::Error),
+    Send(GossipSendError),
+    // a file too big to send, see MAX_FILE_SIZE
+    Transfer(TransferError),
+    NotJoined(String),
+    // a relay address has to end in the relay's `/p2p/<peer id>`
+    InvalidRelayAddress(Multiaddr),
+    // a nickname that's empty or contains control characters
+    InvalidNickname(String),
+    // a room name that's empty, too long or contains control characters or whitespace, or a public room name that
+    // would collide with the DM namespace, see `join_public_room`
+    InvalidRoomName(String),
+    // joining another room would exceed `GossipConfig::max_rooms`
+    TooManyRooms(usize),
+    // invalid peer score parameters or scoring was already enabled
+    PeerScoring(String),
+    Config(gossipsub::ConfigBuilderError),
+    // the task started by `spawn` is gone, the handle can't reach it anymore
+    Stopped,
+}
+impl Display for GossipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GossipError::Transport(e) => write!(f, "Transport error: {}", e),
+            GossipError::Subscription(e) => write!(f, "Subscription error: {}", e),
+            GossipError::AddressParse(e) => write!(f, "Invalid address: {}", e),
+            GossipError::CryptoInit(e) => write!(f, "Crypto initialization error: {}", e),
+            GossipError::Crypto(e) => write!(f, "Crypto error: {}", e),
+            GossipError::Secret(e) => write!(f, "{}", e),
+            GossipError::Identity(e) => write!(f, "Identity error: {}", e),
+            GossipError::Io(e) => write!(f, "IO error: {}", e),
+            GossipError::Send(e) => write!(f, "Send error: {}", e),
+            GossipError::Transfer(e) => write!(f, "Transfer error: {}", e),
+            GossipError::NotJoined(room) => write!(f, "Room not joined: {}", room),
+            GossipError::InvalidRelayAddress(addr) => {
+                write!(f, "Relay address without a peer id: {}", addr)
+            }
+            GossipError::InvalidRoomName(name) => write!(f, "Invalid room name: {:?}", name),
+            GossipError::InvalidNickname(name) => write!(f, "Invalid nickname: {:?}", name),
+            GossipError::TooManyRooms(max) => write!(f, "Can't be in more than {} rooms", max),
+            GossipError::PeerScoring(e) => write!(f, "Peer scoring error: {}", e),
+            GossipError::Config(e) => write!(f, "Invalid gossipsub config: {}", e),
+            GossipError::Stopped => write!(f, "Gossip task stopped"),
+        }
+    }
+}
+impl Error for GossipError {}
+impl From<tls::certificate::GenError> for GossipError {
+    fn from(err: tls::certificate::GenError) -> Self {
+        GossipError::Transport(Box::new(err))
+    }
+}
+impl From<noise::Error> for GossipError {
+    fn from(err: noise::Error) -> Self {
+        GossipError::Transport(Box::new(err))
+    }
+}
+impl From<gossipsub::ConfigBuilderError> for GossipError {
+    fn from(err: gossipsub::ConfigBuilderError) -> Self {
+        GossipError::Config(err)
+    }
+}
+impl From<libp2p::BehaviourBuilderError> for GossipError {
+    fn from(err: libp2p::BehaviourBuilderError) -> Self {
+        GossipError::Transport(Box::new(err))
+    }
+}
+impl From<libp2p::TransportError<std::io::Error>> for GossipError {
+    fn from(err: libp2p::TransportError<std::io::Error>) -> Self {
+        GossipError::Transport(Box::new(err))
+    }
+}
+impl From<DialError> for GossipError {
+    fn from(err: DialError) -> Self {
+        GossipError::Transport(Box::new(err))
+    }
+}
+impl From<gossipsub::SubscriptionError> for GossipError {
+    fn from(err: gossipsub::SubscriptionError) -> Self {
+        GossipError::Subscription(err)
+    }
+}
+impl From<libp2p::multiaddr::Error> for GossipError {
+    fn from(err: libp2p::multiaddr::Error) -> Self {
+        GossipError::AddressParse(err)
+    }
+}
+impl From<oqs::Error> for GossipError {
+    fn from(err: oqs::Error) -> Self {
+        GossipError::Crypto(err)
+    }
+}
+impl From<SecretError> for GossipError {
+    fn from(err: SecretError) -> Self {
+        GossipError::Secret(err)
+    }
+}
+impl From<identity::DecodingError> for GossipError {
+    fn from(err: identity::DecodingError) -> Self {
+        GossipError::Identity(err)
+    }
+}
+impl From<std::io::Error> for GossipError {
+    fn from(err: std::io::Error) -> Self {
+        GossipError::Io(err)
+    }
+}
+impl From<TransferError> for GossipError {
+    fn from(err: TransferError) -> Self {
+        GossipError::Transfer(err)
+    }
+}
+impl From<GossipSendError> for GossipError {
+    fn from(err: GossipSendError) -> Self {
+        GossipError::Send(err)
+    }
+}
+
 pub struct Gossip {
     pub swarm: libp2p::Swarm<MyBehaviour>,
     pub topics: Vec<(String, gossipsub::IdentTopic)>,
-    pub peer_ids: HashSet<PeerId>,
+    // room name by topic hash, kept in sync with `topics` by join_room / leave_room so inbound messages don't scan it
+    topic_names: HashMap<gossipsub::TopicHash, String>,
+    // every peer discovered so far, including the ones that went offline
+    pub peers: HashMap<PeerId, PeerInfo>,
+    // peers with an open connection, a discovered peer isn't necessarily connected and the other way around
+    connected: HashSet<PeerId>,
+    // DM room name -> discovered peers owning it, used to resolve a room name back to a peer
+    pub room_peers: HashMap<String, HashSet<PeerId>>,
     pub secret: Secret,
     pub nonce: Nonce,
+    // encrypted messages we sent that the recipient hasn't acknowledged yet, with the time they were sent. Dropped
+    // after ACK_TIMEOUT.
+    pub pending_acks: HashMap<gossipsub::MessageId, Instant>,
+    // peers we handed the key of a room to, they get a new key whenever one of them leaves
+    pub group_members: HashMap<String, HashSet<PeerId>>,
+    // files being received, keyed by sender and transfer id
+    pub incoming_files: HashMap<(PeerId, u64), IncomingFile>,
+    // messages whose nonce timestamp is older than this are dropped
+    pub max_message_age: Duration,
+    // events that came in while `gossip_when_ready` was driving the swarm, or that came up outside of `handle_event`
+    deferred_events: VecDeque<GossipEvent>,
+    // pings we sent by nonce, a ping to a room can be answered by several peers so they stay until they expire
+    pending_pings: HashMap<u64, Instant>,
+    ping_sweep: Option<PingSweep>,
+    // running `find_peer` queries and the peer each one looks for
+    peer_lookups: HashMap<kad::QueryId, PeerId>,
+    // rooms we asked for public keys in, replies anywhere else are unsolicited and dropped
+    public_key_requests: HashMap<gossipsub::TopicHash, Instant>,
+    // last measured round trip time per peer
+    latencies: HashMap<PeerId, Duration>,
+    // when we last got a valid message from each peer
+    last_heard: HashMap<PeerId, Instant>,
+    // how often `heartbeat` should be called, DM peers not heard from for a few intervals are considered dead
+    pub heartbeat_interval: Duration,
+    is_shut_down: bool,
+    // recent inbound messages per room, oldest first
+    history: HashMap<String, VecDeque<MessageData>>,
+    // messages kept per room in `history`
+    pub history_capacity: usize,
+    // rooms whose latest messages are sent to peers joining them, with how many, see `set_catch_up`
+    catch_up: HashMap<String, usize>,
+    // names we gave peers, shown in place of their peer ids
+    nicknames: HashMap<PeerId, String>,
+    // peers that proved with their signing key that they're in a room, see `prove_membership`
+    room_members: HashMap<String, HashSet<PeerId>>,
+    // messages waiting for gossipsub to have room for them, see `try_gossip`
+    outbound: VecDeque<(gossipsub::IdentTopic, Vec<u8>)>,
+    pub outbound_capacity: usize,
+    // see `GossipConfig::compress_threshold`
+    compress_threshold: Option<usize>,
+    wire_format: WireFormat,
+    // see `GossipConfig::max_transmit_size`
+    max_transmit_size: usize,
+    message_filter: Option<MessageFilter>,
+    // addresses `open_ears_on` listens on, listened on again if every listener closes
+    listen_addrs: Vec<Multiaddr>,
+    // attempt number and when to try listening again
+    listener_retry: Option<(u32, Instant)>,
+    pub retry_policy: RetryPolicy,
+    pending_handshakes: HashMap<PeerId, PendingHandshake>,
+    pub handshake_timeout: Duration,
+    // `max_attempts` counts the first send too, None retries until the peer answers
+    pub handshake_retry: RetryPolicy,
+    // set once peer scoring is enabled
+    graylist_threshold: Option<f64>,
+    graylisted: HashSet<PeerId>,
+    // peers whose messages we drop and don't relay
+    blocked: HashSet<PeerId>,
+    rate_limit: RateLimit,
+    max_rooms: usize,
+    rate_limiters: HashMap<PeerId, TokenBucket>,
+    // messages dropped per peer for exceeding the rate limit
+    dropped: HashMap<PeerId, u64>,
+    // decryption failures in a row per peer, see SESSION_FAILURE_LIMIT
+    decrypt_failures: HashMap<PeerId, u32>,
+    // when we last replaced a stale session with a new handshake
+    rehandshakes: HashMap<PeerId, Instant>,
+    // start a new handshake when a session expires and the peer is still connected
+    pub auto_rehandshake: bool,
+    // keys of password protected rooms by room name, their messages carry an HMAC under it
+    room_keys: HashMap<String, [u8; 32]>,
+    metrics: Metrics,
+    // public rooms announced in PUBLIC_DIRECTORY and who's in them
+    announced_rooms: HashMap<String, HashSet<PeerId>>,
+    // when each peer last announced its rooms, every announcement replaces the previous one
+    room_announcements: HashMap<PeerId, Instant>,
+    last_room_announce: Option<Instant>,
+    clock: Arc<dyn Clock>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum GossipEvent {
     NewConnection(Vec<libp2p::PeerId>),
     Disconnection(Vec<libp2p::PeerId>),
     Message((MessageData, InteractionMessage)),
+    Listening(Multiaddr),
+    // all listeners had closed and listening succeeded again
+    ListenerRestarted,
+    // first connection to the peer opened / last one closed, unlike mdns discovery and expiry
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+    // identify exchanged addresses and protocols with the peer
+    PeerIdentified(PeerId),
+    // the peer joined / left a room we're in
+    Subscribed {
+        peer: PeerId,
+        room: Room,
+    },
+    Unsubscribed {
+        peer: PeerId,
+        room: Room,
+    },
+    // a connection to or from the peer (if known) was refused, `reason` says which limit of `GossipConfig` it hit
+    ConnectionLimitReached {
+        peer: Option<PeerId>,
+        reason: String,
+    },
+    // every peer `ping_all` pinged with its round trip, None if it didn't answer in time
+    PingSweep(Vec<(PeerId, Option<Duration>)>),
+    // the relay accepted our reservation, peers can reach us through the circuit address it came with
+    RelayReserved(PeerId),
+    // the DHT knew addresses of the peer we looked for with `find_peer`, it's being dialed
+    PeerFound {
+        peer: PeerId,
+        addrs: Vec<Multiaddr>,
+    },
+    PeerNotFound(PeerId),
+    // hole punching replaced the relayed connection to the peer with a direct one
+    DirectConnectionUpgraded(PeerId),
+    // the peer's score dropped below the graylist threshold, or it was blocked for flooding
+    PeerGraylisted(PeerId),
+    // a peer answered our RequestPublicKey, a handshake with it can be started
+    PublicKeyLearned(PeerId),
+    // the peer rotated its signing key and we pinned the new one
+    PeerKeyRotated(PeerId),
+    // the peer didn't answer our handshake after every retry of `handshake_retry`
+    HandshakeFailed(PeerId),
+    // a handshake with the peer completed, messages to it can be encrypted from now on
+    SessionEstablished {
+        peer: PeerId,
+        algorithms: (sig::Algorithm, kem::Algorithm),
+    },
+    // the peer proved it's in the room with a membership proof signed by its pinned key
+    MemberVerified {
+        peer: PeerId,
+        room: String,
+    },
+    // the peer sent us the latest messages of a room we joined
+    CatchUp {
+        peer: PeerId,
+        room: String,
+        messages: Vec<CatchUpMessage>,
+    },
+    // messages from the peer kept failing to decrypt and its session was dropped, a new handshake is needed
+    SessionExpired(PeerId),
+    // a chunk of an incoming file arrived, `received` and `total` count chunks
+    FileProgress {
+        peer: PeerId,
+        id: u64,
+        received: u32,
+        total: u32,
+    },
+    FileCompleted {
+        peer: PeerId,
+        id: u64,
+        data: Vec<u8>,
+    },
+    // the transfer was abandoned, what arrived of it is dropped
+    FileFailed {
+        peer: PeerId,
+        id: u64,
+        reason: String,
+    },
 }
 impl Display for GossipEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             GossipEvent::NewConnection(peers) => write!(f, "New connection: {:?}", peers),
             GossipEvent::Disconnection(peers) => write!(f, "Disconnection: {:?}", peers),
+            GossipEvent::Listening(address) => write!(f, "Listening on {}", address),
+            GossipEvent::ListenerRestarted => write!(f, "Listening again"),
+            GossipEvent::PeerConnected(peer) => write!(f, "Connected to {}", peer),
+            GossipEvent::PeerDisconnected(peer) => write!(f, "Disconnected from {}", peer),
+            GossipEvent::PeerIdentified(peer) => write!(f, "Identified {}", peer),
+            GossipEvent::Subscribed { peer, room } => write!(f, "{} joined {}", peer, room),
+            GossipEvent::Unsubscribed { peer, room } => write!(f, "{} left {}", peer, room),
+            GossipEvent::ConnectionLimitReached {
+                peer: Some(peer),
+                reason,
+            } => write!(f, "Refused a connection with {}: {}", peer, reason),
+            GossipEvent::ConnectionLimitReached { peer: None, reason } => {
+                write!(f, "Refused a connection: {}", reason)
+            }
+            GossipEvent::PingSweep(round_trips) => write!(
+                f,
+                "{}/{} peers answered the ping",
+                round_trips.iter().filter(|(_, rtt)| rtt.is_some()).count(),
+                round_trips.len()
+            ),
+            GossipEvent::PeerFound { peer, addrs } => {
+                write!(f, "Found {} on {} addresses", peer, addrs.len())
+            }
+            GossipEvent::PeerNotFound(peer) => write!(f, "{} isn't in the DHT", peer),
+            GossipEvent::RelayReserved(relay) => write!(f, "Reserved a slot on relay {}", relay),
+            GossipEvent::DirectConnectionUpgraded(peer) => {
+                write!(
+                    f,
+                    "Connected to {} directly instead of through a relay",
+                    peer
+                )
+            }
+            GossipEvent::PeerGraylisted(peer) => write!(f, "Graylisted {}", peer),
+            GossipEvent::PublicKeyLearned(peer) => write!(f, "Learned public key of {}", peer),
+            GossipEvent::PeerKeyRotated(peer) => write!(f, "{} rotated its signing key", peer),
+            GossipEvent::HandshakeFailed(peer) => write!(f, "Handshake with {} failed", peer),
+            GossipEvent::SessionEstablished { peer, algorithms } => write!(
+                f,
+                "Session established with {} ({} / {})",
+                peer,
+                algorithms.0.name(),
+                algorithms.1.name()
+            ),
+            GossipEvent::SessionExpired(peer) => write!(f, "Session with {} expired", peer),
+            GossipEvent::MemberVerified { peer, room } => {
+                write!(f, "{} proved it's in {}", peer, room)
+            }
+            GossipEvent::CatchUp {
+                peer,
+                room,
+                messages,
+            } => write!(
+                f,
+                "{} earlier messages of {} from {}",
+                messages.len(),
+                room,
+                peer
+            ),
+            GossipEvent::FileProgress {
+                peer,
+                id,
+                received,
+                total,
+            } => write!(
+                f,
+                "File {} from {}: {}/{} chunks",
+                id, peer, received, total
+            ),
+            GossipEvent::FileCompleted { peer, id, data } => {
+                write!(f, "File {} from {}: {} bytes", id, peer, data.len())
+            }
+            GossipEvent::FileFailed { peer, id, reason } => {
+                write!(f, "File {} from {} failed: {}", id, peer, reason)
+            }
             GossipEvent::Message(data) => {
                 write!(
                     f,
                     "Message from {}({}): {} | {:?}",
-                    data.0.peer, data.0.room, data.0.message, data.1
+                    data.0.sender(),
+                    data.0.room,
+                    data.0.message,
+                    data.1
                 )
             }
         }
@@ -81,62 +671,158 @@ impl Display for GossipEvent {
 }
 
 impl Gossip {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
-        let _ = tracing_subscriber::fmt()
-            .with_env_filter(EnvFilter::from_default_env())
-            .try_init();
-
-        let swarm = libp2p::SwarmBuilder::with_new_identity()
-            .with_tokio()
-            .with_tcp(
-                tcp::Config::default(),
-                noise::Config::new,
-                yamux::Config::default,
-            )?
-            .with_quic()
-            .with_behaviour(|key| {
-                // To content-address message, we can take the hash of message and use it as an ID.
-                let message_id_fn = |message: &gossipsub::Message| {
-                    let mut s = DefaultHasher::new();
-                    message.data.hash(&mut s);
-                    gossipsub::MessageId::from(s.finish().to_string())
-                };
+    pub fn new() -> Result<Self, GossipError> {
+        Self::with_config(GossipConfig::default())
+    }
+    pub fn with_config(config: GossipConfig) -> Result<Self, GossipError> {
+        Self::from_keypair(identity::Keypair::generate_ed25519(), config)
+    }
+    // Loads the ed25519 keypair stored at `path`, or generates one and saves it there, so that the peer id (and the
+    // DM room derived from it) stays the same across restarts.
+    pub fn with_identity(path: &Path) -> Result<Self, GossipError> {
+        Self::with_identity_and_config(path, GossipConfig::default())
+    }
+    pub fn with_identity_and_config(
+        path: &Path,
+        config: GossipConfig,
+    ) -> Result<Self, GossipError> {
+        let keypair = if path.exists() {
+            identity::Keypair::from_protobuf_encoding(&fs::read(path)?)?
+        } else {
+            let keypair = identity::Keypair::generate_ed25519();
+            secret::write_private(path, &keypair.to_protobuf_encoding()?)?;
+            keypair
+        };
+        Self::from_keypair(keypair, config)
+    }
+    fn from_keypair(keypair: identity::Keypair, config: GossipConfig) -> Result<Self, GossipError> {
+        let gossipsub_config = config.build()?;
+        let limits = config.connection_limits();
+        let bootstrap_nodes = config.bootstrap_nodes.clone();
+        let rate_limit = config.rate_limit;
+        let clock = config.clock.clone();
+        let mut secret = Secret::new().map_err(GossipError::CryptoInit)?;
+        secret.clock = clock.clone();
+        let builder = libp2p::SwarmBuilder::with_existing_identity(keypair).with_tokio();
+        // the security upgrade is part of the builder's type, so each choice needs its own chain
+        let swarm = match config.security {
+            SecurityTransport::Noise => builder
+                .with_tcp(
+                    tcp::Config::default(),
+                    noise::Config::new,
+                    yamux::Config::default,
+                )?
+                .with_quic()
+                .with_relay_client(noise::Config::new, yamux::Config::default)?
+                .with_behaviour(|key, relay| build_behaviour(key, gossipsub_config, limits, relay))?
+                .build(),
+            SecurityTransport::Tls => builder
+                .with_tcp(
+                    tcp::Config::default(),
+                    tls::Config::new,
+                    yamux::Config::default,
+                )?
+                .with_quic()
+                .with_relay_client(tls::Config::new, yamux::Config::default)?
+                .with_behaviour(|key, relay| build_behaviour(key, gossipsub_config, limits, relay))?
+                .build(),
+        };
 
-                // Set a custom gossipsub configuration
-                let gossipsub_config = gossipsub::ConfigBuilder::default()
-                    .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
-                    .validation_mode(gossipsub::ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message
-                    // signing)
-                    .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content will be propagated.
-                    .build()
-                    .map_err(io::Error::other)?; // Temporary hack because `build` does not return a proper `std::error::Error`.
-
-                // build a gossipsub network behaviour
-                let gossipsub = gossipsub::Behaviour::new(
-                    gossipsub::MessageAuthenticity::Signed(key.clone()),
-                    gossipsub_config,
-                )?;
-
-                let mdns = mdns::tokio::Behaviour::new(
-                    mdns::Config::default(),
-                    key.public().to_peer_id(),
-                )?;
-                Ok(MyBehaviour { gossipsub, mdns })
-            })?
-            .build();
-
-        Ok(Self {
+        let mut gossip = Self {
             swarm,
             topics: Vec::new(),
-            peer_ids: HashSet::new(),
-            secret: Secret::new()?,
-            nonce: Nonce::new(),
-        })
+            topic_names: HashMap::new(),
+            peers: HashMap::new(),
+            connected: HashSet::new(),
+            room_peers: HashMap::new(),
+            secret,
+            nonce: Nonce::with_clock(clock.clone()),
+            pending_acks: HashMap::new(),
+            group_members: HashMap::new(),
+            incoming_files: HashMap::new(),
+            max_message_age: DEFAULT_MAX_MESSAGE_AGE,
+            deferred_events: VecDeque::new(),
+            pending_pings: HashMap::new(),
+            ping_sweep: None,
+            peer_lookups: HashMap::new(),
+            public_key_requests: HashMap::new(),
+            latencies: HashMap::new(),
+            last_heard: HashMap::new(),
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            is_shut_down: false,
+            history: HashMap::new(),
+            history_capacity: DEFAULT_HISTORY_CAPACITY,
+            catch_up: HashMap::new(),
+            room_members: HashMap::new(),
+            nicknames: HashMap::new(),
+            outbound: VecDeque::new(),
+            outbound_capacity: DEFAULT_OUTBOUND_CAPACITY,
+            listen_addrs: Vec::new(),
+            listener_retry: None,
+            retry_policy: RetryPolicy::default(),
+            pending_handshakes: HashMap::new(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_retry: RetryPolicy::handshake(),
+            graylist_threshold: None,
+            graylisted: HashSet::new(),
+            blocked: HashSet::new(),
+            rate_limit,
+            max_rooms: config.max_rooms,
+            compress_threshold: config.compress_threshold,
+            wire_format: config.wire_format,
+            max_transmit_size: config.max_transmit_size,
+            message_filter: None,
+            rate_limiters: HashMap::new(),
+            dropped: HashMap::new(),
+            decrypt_failures: HashMap::new(),
+            metrics: Metrics::default(),
+            announced_rooms: HashMap::new(),
+            room_announcements: HashMap::new(),
+            last_room_announce: None,
+            rehandshakes: HashMap::new(),
+            auto_rehandshake: true,
+            room_keys: HashMap::new(),
+            clock,
+        };
+        if !bootstrap_nodes.is_empty()
+            && let Err(e) = gossip.bootstrap(&bootstrap_nodes)
+        {
+            tracing::warn!("No bootstrap node could be dialed: {}", e);
+        }
+        Ok(gossip)
     }
     pub fn peer_id(&self) -> PeerId {
-        self.swarm.local_peer_id().clone()
+        *self.swarm.local_peer_id()
+    }
+    // Everything about our own node worth putting in a bug report, one item per line
+    pub fn describe(&self) -> String {
+        let (sig_algorithm, kem_algorithm) = self.secret.algorithms();
+        let rooms: Vec<String> = self
+            .list_rooms()
+            .iter()
+            .map(|room| room.to_string())
+            .collect();
+        format!(
+            "Peer id: {}\nDM room: {}\nSignature algorithm: {}\nKEM algorithm: {}\nPublic key: {}\nRooms: {}\nSessions: {}",
+            self.peer_id(),
+            self.peer_id().generate_room_name(),
+            sig_algorithm.name(),
+            kem_algorithm.name(),
+            self.secret.public_key_encoded(),
+            rooms.join(", "),
+            self.secret.sessions().count()
+        )
+    }
+    pub fn open_ears(&mut self) -> Result<(), GossipError> {
+        self.open_ears_on(&[], TransportConfig::default())
     }
-    pub fn open_ears(&mut self) -> Result<(), Box<dyn Error>> {
+    // An empty `addrs` listens on the default addresses of the chosen transports, otherwise addresses of a transport
+    // that isn't enabled are skipped.
+    pub fn open_ears_on(
+        &mut self,
+        addrs: &[Multiaddr],
+        transport: TransportConfig,
+    ) -> Result<(), GossipError> {
         // Before opening ears, we join a room with the name of our peer id, so that if someone wants to relay a message
         // specifically to us, they can do so by sending it to our peer id.
         // note that since the peer id is public, this room is not for sensitive messages.
@@ -144,27 +830,1575 @@ impl Gossip {
         // note: also encrypted messages can be used to establish a private room as well.
         //! CHECK BEFORE FURTHER IMPLEMENTATION: IS IT POSSIBLE TO LIST ALL THE ROOMS = GOOD THING I DID, YES THEY CAN
 
-        let last_five_id_char = self.peer_id().generate_room_name();
-        self.join_room(&last_five_id_char)?;
+        self.join_dm_room(self.peer_id())?;
 
-        // Listen on all interfaces and whatever port the OS assigns
-        // self.swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
-        self.swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+        self.listen_addrs = if addrs.is_empty() {
+            transport.default_addrs()
+        } else {
+            addrs
+                .iter()
+                .filter(|addr| transport.allows(addr))
+                .cloned()
+                .collect()
+        };
+        self.listen()
+    }
+    fn listen(&mut self) -> Result<(), GossipError> {
+        // it's fine if some of the addresses can't be bound, as long as we are reachable on at least one
+        let mut last_error = None;
+        let mut listening = false;
+        for addr in self.listen_addrs.clone() {
+            match self.swarm.listen_on(addr) {
+                Ok(_) => listening = true,
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) if !listening => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+    // Called when a listener went away, schedules listening again once none are left
+    fn listener_lost(&mut self) {
+        if self.swarm.listeners().next().is_some() || self.listener_retry.is_some() {
+            return;
+        }
+        tracing::warn!("No listener left, trying to listen again");
+        self.listener_retry = Some((0, self.clock.now()));
+    }
+    // When the next attempt to listen again is due, None if all is fine
+    pub fn next_listener_retry(&self) -> Option<Instant> {
+        self.listener_retry.map(|(_, at)| at)
+    }
+    // Listens on the addresses given to `open_ears_on` again and resubscribes to every room. Failing attempts are
+    // retried according to `retry_policy`.
+    pub fn retry_listeners(&mut self) -> Option<GossipEvent> {
+        let (attempt, _) = self.listener_retry?;
+        if let Err(e) = self.listen() {
+            let attempt = attempt + 1;
+            if self
+                .retry_policy
+                .max_attempts
+                .is_some_and(|max| attempt >= max)
+            {
+                tracing::error!(
+                    "Giving up listening again after {} attempts: {}",
+                    attempt,
+                    e
+                );
+                self.listener_retry = None;
+                return None;
+            }
+            let delay = self.retry_policy.delay(attempt);
+            tracing::warn!("Listening again failed, retrying in {:?}: {}", delay, e);
+            self.listener_retry = Some((attempt, self.clock.now() + delay));
+            return None;
+        }
+        self.listener_retry = None;
+        for (_, topic) in self.topics.iter() {
+            if let Err(e) = self.swarm.behaviour_mut().gossipsub.subscribe(topic) {
+                tracing::warn!("Error resubscribing to {}: {:?}", topic, e);
+            }
+        }
+        Some(GossipEvent::ListenerRestarted)
+    }
+    // What we know about `peer_id`, starting to keep track of it if we didn't yet
+    fn peer_info(&mut self, peer_id: PeerId) -> &mut PeerInfo {
+        let now = self.clock.now();
+        self.peers
+            .entry(peer_id)
+            .or_insert_with(|| PeerInfo::new(now))
+    }
+    // Records what the peer told us about itself, the address it observed us on is a candidate external address
+    fn peer_identified(&mut self, peer_id: PeerId, info: identify::Info) -> Option<GossipEvent> {
+        // mdns addresses stay out of the DHT, a LAN address is of no use to peers elsewhere. Identify is the only place
+        // we learn the listen addresses of a peer that dialed us
+        if info.protocols.contains(&KAD_PROTOCOL) {
+            for addr in &info.listen_addrs {
+                self.swarm
+                    .behaviour_mut()
+                    .kad
+                    .add_address(&peer_id, addr.clone());
+            }
+        }
+        let now = self.clock.now();
+        let peer = self.peer_info(peer_id);
+        for addr in info.listen_addrs {
+            peer.seen(addr, now);
+        }
+        peer.protocols = info.protocols.iter().map(|p| p.to_string()).collect();
+        self.swarm.add_external_address(info.observed_addr);
+        Some(GossipEvent::PeerIdentified(peer_id))
+    }
+    // Drops everything the peer sends from now on, gossipsub also stops relaying its messages
+    pub fn block_peer(&mut self, peer_id: PeerId) {
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .blacklist_peer(&peer_id);
+        self.blocked.insert(peer_id);
+    }
+    pub fn unblock_peer(&mut self, peer_id: &PeerId) {
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .remove_blacklisted_peer(peer_id);
+        self.blocked.remove(peer_id);
+    }
+    pub fn is_blocked(&self, peer_id: &PeerId) -> bool {
+        self.blocked.contains(peer_id)
+    }
+    // One peer id per line
+    pub fn save_blocklist(&self, path: &Path) -> Result<(), GossipError> {
+        let list: Vec<String> = self.blocked.iter().map(|peer| peer.to_string()).collect();
+        fs::write(path, list.join("\n"))?;
+        Ok(())
+    }
+    // Blocks every peer listed in a file written by `save_blocklist`, a missing file is an empty list
+    pub fn load_blocklist(&mut self, path: &Path) -> Result<(), GossipError> {
+        if !path.exists() {
+            return Ok(());
+        }
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match line.parse() {
+                Ok(peer_id) => self.block_peer(peer_id),
+                Err(e) => tracing::warn!("Invalid peer id {:?} in blocklist: {}", line, e),
+            }
+        }
+        Ok(())
+    }
+    // Names `peer_id` in our output from now on, replacing an earlier nickname. Spaces are fine, control characters
+    // aren't.
+    pub fn set_nickname(&mut self, peer_id: PeerId, name: &str) -> Result<(), GossipError> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().any(char::is_control) {
+            return Err(GossipError::InvalidNickname(name.to_string()));
+        }
+        self.nicknames.insert(peer_id, name.to_string());
+        Ok(())
+    }
+    pub fn clear_nickname(&mut self, peer_id: &PeerId) -> Option<String> {
+        self.nicknames.remove(peer_id)
+    }
+    pub fn nickname(&self, peer_id: &PeerId) -> Option<&str> {
+        self.nicknames.get(peer_id).map(String::as_str)
+    }
+    // One `<peer id> <nickname>` per line
+    pub fn save_nicknames(&self, path: &Path) -> Result<(), GossipError> {
+        let list: Vec<String> = self
+            .nicknames
+            .iter()
+            .map(|(peer, name)| format!("{} {}", peer, name))
+            .collect();
+        fs::write(path, list.join("\n"))?;
+        Ok(())
+    }
+    // Loads nicknames written by `save_nicknames`, a missing file is an empty list
+    pub fn load_nicknames(&mut self, path: &Path) -> Result<(), GossipError> {
+        if !path.exists() {
+            return Ok(());
+        }
+        for line in fs::read_to_string(path)?.lines() {
+            let Some((peer, name)) = line.trim().split_once(' ') else {
+                continue;
+            };
+            let loaded = match peer.parse() {
+                Ok(peer_id) => self.set_nickname(peer_id, name),
+                Err(_) => Err(GossipError::InvalidNickname(line.to_string())),
+            };
+            if let Err(e) = loaded {
+                tracing::warn!("Skipped nickname {:?}: {}", line, e);
+            }
+        }
+        Ok(())
+    }
+    // Counts a message of `peer_id` against its rate limit, an Err means it has to be dropped. Blocks the peer once it
+    // went over the limit `RateLimit::block_after` times, the Err is then true.
+    fn within_rate_limit(&mut self, peer_id: PeerId) -> Result<(), bool> {
+        let limit = &self.rate_limit;
+        let now = self.clock.now();
+        let allowed = self
+            .rate_limiters
+            .entry(peer_id)
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .take(limit, now);
+        if allowed {
+            return Ok(());
+        }
+        let dropped = self.dropped.entry(peer_id).or_default();
+        *dropped += 1;
+        tracing::debug!(%peer_id, dropped = *dropped, "Rate limit exceeded, message dropped");
+        if self.rate_limit.block_after == Some(*dropped) {
+            tracing::warn!("Blocking {} for flooding", peer_id);
+            self.block_peer(peer_id);
+            return Err(true);
+        }
+        Err(false)
+    }
+    // Messages of the peer dropped so far for exceeding the rate limit
+    pub fn dropped_count(&self, peer_id: &PeerId) -> u64 {
+        self.dropped.get(peer_id).copied().unwrap_or(0)
+    }
+    // Addresses other nodes confirmed they can reach us on, empty unless something like identify or autonat
+    // reported them
+    pub fn external_addrs(&self) -> Vec<Multiaddr> {
+        self.swarm.external_addresses().cloned().collect()
+    }
+    // Our listen and external addresses ending in `/p2p/<peer id>`, ready to be passed to `dial` elsewhere
+    pub fn dial_addrs(&self) -> Vec<Multiaddr> {
+        let peer_id = self.peer_id();
+        let listeners: Vec<&Multiaddr> = self.swarm.listeners().collect();
+        // circuit addresses are both listened on and external
+        let external = self
+            .swarm
+            .external_addresses()
+            .filter(|addr| !listeners.contains(addr));
+        listeners
+            .iter()
+            .copied()
+            .chain(external)
+            .map(|addr| addr.clone().with_p2p(peer_id).unwrap_or_else(|addr| addr))
+            .collect()
+    }
+    // Asks the DHT for the addresses of `peer_id` and dials it once they're known, the answer comes as PeerFound or
+    // PeerNotFound. Needs a DHT node to start from, either found by mdns or given to `bootstrap`.
+    pub fn find_peer(&mut self, peer_id: PeerId) {
+        let query = self.swarm.behaviour_mut().kad.get_closest_peers(peer_id);
+        self.peer_lookups.insert(query, peer_id);
+    }
+    fn peer_lookup_progressed(
+        &mut self,
+        query: kad::QueryId,
+        result: kad::GetClosestPeersResult,
+        last: bool,
+    ) -> Option<GossipEvent> {
+        let peer_id = *self.peer_lookups.get(&query)?;
+        let peers = match result {
+            Ok(ok) => ok.peers,
+            // whatever came back before the timeout may still hold the peer
+            Err(kad::GetClosestPeersError::Timeout { peers, .. }) => peers,
+        };
+        // the peer answered the query itself, addresses only come along when another peer told us about it
+        let Some(found) = peers.into_iter().find(|peer| peer.peer_id == peer_id) else {
+            if !last {
+                return None;
+            }
+            self.peer_lookups.remove(&query);
+            return Some(GossipEvent::PeerNotFound(peer_id));
+        };
+        self.peer_lookups.remove(&query);
+        if let Some(mut query) = self.swarm.behaviour_mut().kad.query_mut(&query) {
+            query.finish();
+        }
+        let info = self.peer_info(peer_id);
+        info.addrs.extend(found.addrs);
+        let addrs: Vec<Multiaddr> = info.addrs.iter().cloned().collect();
+        let opts = DialOpts::peer_id(peer_id).addresses(addrs.clone()).build();
+        if let Err(e) = self.swarm.dial(opts) {
+            tracing::debug!("Dialing {} failed: {}", peer_id, e);
+        }
+        Some(GossipEvent::PeerFound {
+            peer: peer_id,
+            addrs,
+        })
+    }
+    // Reserves a slot on the relay at `relay_addr`, which has to end in `/p2p/<relay peer id>`. Peers that can't
+    // dial us directly, e.g. behind a symmetric NAT, reach us through the circuit address once RelayReserved comes,
+    // it's among `external_addrs` and `dial_addrs` from then on.
+    pub fn use_relay(&mut self, relay_addr: Multiaddr) -> Result<(), GossipError> {
+        if !matches!(relay_addr.iter().last(), Some(Protocol::P2p(_))) {
+            return Err(GossipError::InvalidRelayAddress(relay_addr));
+        }
+        self.swarm
+            .listen_on(relay_addr.with(Protocol::P2pCircuit))?;
+        Ok(())
+    }
+    // mdns only finds peers on the local network, peers elsewhere have to be dialed explicitly
+    pub fn dial(&mut self, addr: Multiaddr) -> Result<(), DialError> {
+        let result = self.swarm.dial(addr);
+        // a dial refused right away doesn't come back as a swarm event
+        if let Err(DialError::Denied { cause }) = &result
+            && let Some(event) = connection_limit_reached(None, cause)
+        {
+            self.deferred_events.push_back(event);
+        }
+        result
+    }
+    // Dials `addrs`, the ones ending in `/p2p/<peer id>` also seed the DHT
+    pub fn bootstrap(&mut self, addrs: &[Multiaddr]) -> Result<(), GossipError> {
+        let mut last_error = None;
+        let mut dialing = false;
+        let mut seeded = false;
+        for addr in addrs {
+            if let Some(Protocol::P2p(peer_id)) = addr.iter().last() {
+                self.swarm
+                    .behaviour_mut()
+                    .kad
+                    .add_address(&peer_id, addr.clone());
+                seeded = true;
+            }
+        }
+        if seeded && let Err(e) = self.swarm.behaviour_mut().kad.bootstrap() {
+            tracing::debug!("DHT bootstrap failed: {}", e);
+        }
+        for addr in addrs {
+            match self.dial(addr.clone()) {
+                Ok(()) => dialing = true,
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) if !dialing => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+    // Like `try_gossip`, but drives the swarm until the outbound queue has room instead of failing with QueueFull.
+    // Events handled in the meantime are kept, see `take_deferred_events`.
+    pub async fn gossip(
+        &mut self,
+        message: &InteractionMessage,
+        topic: gossipsub::IdentTopic,
+    ) -> Result<PublishOutcome, GossipSendError> {
+        while self.outbound.len() >= self.outbound_capacity {
+            let event = self.swarm.select_next_some().await;
+            if let Some(event) = self.handle_event(event) {
+                self.deferred_events.push_back(event);
+            }
+        }
+        self.try_gossip(message, topic)
+    }
+    // Publishes `message`, or queues it while gossipsub's own queues are full. Everything we send goes through here,
+    // so `outbound_len` shows the pressure. Fails with QueueFull once `outbound_capacity` messages are waiting.
+    pub fn try_gossip(
+        &mut self,
+        message: &InteractionMessage,
+        topic: gossipsub::IdentTopic,
+    ) -> Result<PublishOutcome, GossipSendError> {
+        if self.outbound.len() >= self.outbound_capacity {
+            return Err(GossipSendError::QueueFull);
+        }
+        let topic_hash = topic.hash();
+        let data = self.encode(message, &topic_hash)?;
+        let mesh_peer_count = self.peers_for(&topic_hash).len();
+        // publishing past queued messages would reorder what we send
+        self.flush_outbound();
+        let message_id = if self.outbound.is_empty() {
+            match self
+                .swarm
+                .behaviour_mut()
+                .gossipsub
+                .publish(topic.clone(), data.clone())
+            {
+                Ok(message_id) => {
+                    self.metrics.sent(data.len());
+                    message_id
+                }
+                Err(gossipsub::PublishError::AllQueuesFull(_)) => {
+                    let message_id = config::message_id_of(&data);
+                    self.outbound.push_back((topic, data));
+                    message_id
+                }
+                Err(e) => {
+                    self.metrics.publish_errors += 1;
+                    return Err(e.into());
+                }
+            }
+        } else {
+            let message_id = config::message_id_of(&data);
+            self.outbound.push_back((topic, data));
+            message_id
+        };
+        self.track(message, &message_id, topic_hash);
+        Ok(PublishOutcome {
+            message_id,
+            mesh_peer_count,
+        })
+    }
+    // A snapshot of the counters since the node started
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            active_sessions: self.secret.sessions().count(),
+            connected_peers: self.connected.len(),
+            ..self.metrics.clone()
+        }
+    }
+    // Messages waiting in the outbound queue, callers can hold back new ones while it's filling up
+    pub fn outbound_len(&self) -> usize {
+        self.outbound.len()
+    }
+    // Hands queued messages to gossipsub until its queues are full again, called whenever the swarm is polled
+    fn flush_outbound(&mut self) {
+        while let Some((topic, data)) = self.outbound.pop_front() {
+            match self
+                .swarm
+                .behaviour_mut()
+                .gossipsub
+                .publish(topic.clone(), data.clone())
+            {
+                Ok(_) => self.metrics.sent(data.len()),
+                Err(gossipsub::PublishError::AllQueuesFull(_)) => {
+                    self.outbound.push_front((topic, data));
+                    return;
+                }
+                Err(e) => {
+                    self.metrics.publish_errors += 1;
+                    tracing::warn!("Queued message to {} dropped: {:?}", topic, e);
+                }
+            }
+        }
+    }
+    // Serializes `message` the way it goes over the wire, with the format tag, our nonce and the HMAC of a password
+    // protected room
+    fn encode(
+        &mut self,
+        message: &InteractionMessage,
+        topic: &gossipsub::TopicHash,
+    ) -> Result<Vec<u8>, GossipSendError> {
+        let payload = self.frame_payload(message)?;
+        let mut data = wire::tag(self.wire_format, self.nonce.add_nonce(&payload));
+        if let Some(key) = self.room_key(topic) {
+            data = room::tag_payload(key, data);
+        }
+        // gossipsub's own check only says the message was too large, not by how much
+        if data.len() > self.max_transmit_size {
+            return Err(GossipSendError::TooLarge {
+                size: data.len(),
+                max: self.max_transmit_size,
+            });
+        }
+        Ok(data)
+    }
+    // Compressed inside the nonce header, sequence numbers and replay checks see the same header either way
+    fn frame_payload(&self, message: &InteractionMessage) -> Result<Vec<u8>, GossipSendError> {
+        Ok(compress::frame(
+            &self.wire_format.serialize(message)?,
+            self.compress_threshold,
+        ))
+    }
+    // The bytes `try_gossip` would publish for `message` right now, without publishing it or using up a sequence
+    // number. The HMAC of a password protected room isn't added, it depends on the room.
+    pub fn encode_message(&self, message: &InteractionMessage) -> Result<Vec<u8>, GossipSendError> {
+        let payload = self.frame_payload(message)?;
+        Ok(wire::tag(self.wire_format, self.nonce.peek_nonce(&payload)))
+    }
+    // Takes apart what `encode_message` put together, in either wire format. Only the framing is checked, none of
+    // the sequence number, age, room or filter checks incoming messages go through.
+    pub fn decode_message(&self, data: &[u8]) -> Result<InteractionMessage, DecodeError> {
+        let (format, data) = wire::untag(data)?;
+        let payload = compress::unframe(&self.nonce.remove_nonce(data)?)?;
+        match format {
+            WireFormat::Json => Ok(serde_json::from_slice(&payload)?),
+            WireFormat::Cbor => Ok(WireFormat::deserialize_cbor(&payload)?),
+        }
+    }
+    // Remembers what we sent that we expect an answer to
+    fn track(
+        &mut self,
+        message: &InteractionMessage,
+        message_id: &gossipsub::MessageId,
+        topic_hash: gossipsub::TopicHash,
+    ) {
+        match message {
+            InteractionMessage::SharedSecretCommunication(_)
+            | InteractionMessage::SignedSharedSecretCommunication(_) => {
+                let now = self.clock.now();
+                self.pending_acks
+                    .retain(|_, sent| now.duration_since(*sent) < ACK_TIMEOUT);
+                self.pending_acks.insert(message_id.clone(), now);
+            }
+            InteractionMessage::Ping(nonce) => {
+                let now = self.clock.now();
+                self.pending_pings
+                    .retain(|_, sent| now.duration_since(*sent) < PING_TIMEOUT);
+                self.pending_pings.insert(*nonce, now);
+            }
+            InteractionMessage::RequestPublicKey => {
+                self.public_key_requests
+                    .insert(topic_hash, self.clock.now());
+            }
+            // sent in the DM room of the peer we're handshaking with
+            InteractionMessage::SharedSecretExchange(exchange) => {
+                let Some(peer_id) = self
+                    .get_room_from_hash(topic_hash)
+                    .and_then(|room| room.owner())
+                else {
+                    return;
+                };
+                let retry_at = self.clock.now() + self.handshake_timeout;
+                // a new exchange replaces the KEM key of the previous one, only the new one can be answered
+                self.pending_handshakes
+                    .entry(peer_id)
+                    .and_modify(|pending| {
+                        pending.exchange = exchange.clone();
+                        pending.retry_at = retry_at;
+                    })
+                    .or_insert_with(|| PendingHandshake {
+                        exchange: exchange.clone(),
+                        attempt: 1,
+                        retry_at,
+                    });
+            }
+            _ => {}
+        }
+    }
+    // Joins `room` and authenticates everything sent there with a key derived from `passphrase`. Messages from
+    // anyone without the passphrase are dropped.
+    pub fn join_room_with_password(
+        &mut self,
+        room: &str,
+        passphrase: &str,
+    ) -> Result<(), GossipError> {
+        self.join_room(room)?;
+        self.room_keys
+            .insert(room.to_string(), room::derive_room_key(room, passphrase));
         Ok(())
     }
-    pub fn gossip(
+    // Leaves every room, our own DM room included, and returns their names for `rejoin_all`. The keys of password
+    // protected rooms are kept, rejoining them doesn't need the passphrase again.
+    pub fn leave_all_rooms(&mut self) -> Vec<String> {
+        let room_keys = self.room_keys.clone();
+        let rooms: Vec<String> = self.topics.iter().map(|(name, _)| name.clone()).collect();
+        for room in &rooms {
+            // only fails for rooms we're not in
+            let _ = self.leave_room(room);
+        }
+        self.room_keys = room_keys;
+        rooms
+    }
+    // Joins `rooms` again after `leave_all_rooms`. Our own DM room goes first, so peers can still reach us if
+    // `max_rooms` doesn't leave space for all of them. Keeps going past rooms that can't be joined and returns the
+    // last error.
+    pub fn rejoin_all(&mut self, rooms: &[String]) -> Result<(), GossipError> {
+        let own_room = self.peer_id().generate_room_name();
+        let (own, others): (Vec<&String>, Vec<&String>) =
+            rooms.iter().partition(|room| **room == own_room);
+        let mut last_error = None;
+        for room in own.into_iter().chain(others) {
+            if let Err(e) = self.join_room(room) {
+                last_error = Some(e);
+            }
+        }
+        last_error.map_or(Ok(()), Err)
+    }
+    // Publishes `message` to every public room we're in, DM rooms are skipped. Returns how it went per room.
+    pub fn broadcast_public(&mut self, message: &InteractionMessage) -> RoomOutcomes {
+        let public: Vec<(String, gossipsub::IdentTopic)> = self
+            .topics
+            .iter()
+            .filter(|(name, _)| self.get_room_from_name(name.clone()).is_public())
+            .cloned()
+            .collect();
+        public
+            .into_iter()
+            .map(|(name, topic)| {
+                let result = self.try_gossip(message, topic);
+                (name, result)
+            })
+            .collect()
+    }
+    // Application level policy on top of gossipsub's validation, e.g. a length limit or a schema check. Messages the
+    // filter returns false for are dropped instead of being emitted as GossipEvent::Message. Messages handled by
+    // gossip itself, like handshakes and file chunks, don't go through it.
+    pub fn set_message_filter(&mut self, filter: MessageFilter) {
+        self.message_filter = Some(filter);
+    }
+    pub fn clear_message_filter(&mut self) {
+        self.message_filter = None;
+    }
+    pub(crate) fn passes_filter(&self, message: &MessageData) -> bool {
+        self.message_filter
+            .as_ref()
+            .is_none_or(|filter| filter(message))
+    }
+    // A public room turns into a group room once we hold a group key for it
+    pub fn room_kind(&self, room: &Room) -> RoomKind {
+        match room.kind() {
+            RoomKind::Public if self.secret.has_group_key(room.name()) => RoomKind::Group,
+            kind => kind,
+        }
+    }
+    // When the next unanswered handshake is due for a retry, None if none is pending
+    pub fn next_handshake_retry(&self) -> Option<Instant> {
+        self.pending_handshakes
+            .values()
+            .map(|pending| pending.retry_at)
+            .min()
+    }
+    // Resends every handshake whose response didn't come within `handshake_timeout`, with the backoff of
+    // `handshake_retry` on top. Returns HandshakeFailed for the ones out of attempts.
+    pub fn retry_handshakes(&mut self) -> Vec<GossipEvent> {
+        let now = self.clock.now();
+        let due: Vec<PeerId> = self
+            .pending_handshakes
+            .iter()
+            .filter(|(_, pending)| pending.retry_at <= now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        let mut events = Vec::new();
+        for peer_id in due {
+            let Some(mut pending) = self.pending_handshakes.remove(&peer_id) else {
+                continue;
+            };
+            if self
+                .handshake_retry
+                .max_attempts
+                .is_some_and(|max| pending.attempt >= max)
+            {
+                tracing::warn!(
+                    "No handshake response from {} after {} attempts",
+                    peer_id,
+                    pending.attempt
+                );
+                self.secret.cancel_handshake(&peer_id);
+                events.push(GossipEvent::HandshakeFailed(peer_id));
+                continue;
+            }
+            tracing::debug!("Resending handshake to {}", peer_id);
+            let exchange = InteractionMessage::SharedSecretExchange(pending.exchange.clone());
+            if let Err(e) = self.send_to_peer(peer_id, &exchange) {
+                tracing::debug!("Resending handshake to {} failed: {:?}", peer_id, e);
+            }
+            pending.retry_at =
+                now + self.handshake_timeout + self.handshake_retry.delay(pending.attempt);
+            pending.attempt += 1;
+            self.pending_handshakes.insert(peer_id, pending);
+        }
+        events
+    }
+    // Hash of a room we're in, what gossipsub identifies its messages by
+    pub fn topic_hash(&self, name: &str) -> Option<gossipsub::TopicHash> {
+        let topic = self.get_topic_from_name(name)?;
+        Some(topic.hash())
+    }
+    fn room_key(&self, topic: &gossipsub::TopicHash) -> Option<&[u8; 32]> {
+        let name = self.topic_names.get(topic)?;
+        self.room_keys.get(name)
+    }
+    // Right after startup nobody is subscribed to the topic yet and `try_gossip` fails with `InsufficientPeers`.
+    // This keeps driving the swarm until a peer is there to publish to and the outbound queue has room, or `timeout`
+    // elapses.
+    // Events handled in the meantime are kept, see `take_deferred_events`.
+    pub async fn gossip_when_ready(
         &mut self,
         message: &InteractionMessage,
         topic: gossipsub::IdentTopic,
-    ) -> Result<gossipsub::MessageId, GossipSendError> {
-        let data = self
-            .nonce
-            .add_nonce(serde_json::to_string(message)?.as_bytes());
-        Ok(self.swarm.behaviour_mut().gossipsub.publish(topic, data)?)
+        timeout: Duration,
+    ) -> Result<PublishOutcome, GossipSendError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while self.peers_for(&topic.hash()).is_empty()
+            || self.outbound.len() >= self.outbound_capacity
+        {
+            let Ok(event) = tokio::time::timeout_at(deadline, self.swarm.select_next_some()).await
+            else {
+                return Err(GossipSendError::NotReady);
+            };
+            if let Some(event) = self.handle_event(event) {
+                self.deferred_events.push_back(event);
+            }
+        }
+        self.try_gossip(message, topic)
+    }
+    fn peers_for(&self, topic: &gossipsub::TopicHash) -> Vec<PeerId> {
+        let gossipsub = &self.swarm.behaviour().gossipsub;
+        let mut peers: HashSet<PeerId> = gossipsub.mesh_peers(topic).copied().collect();
+        // explicit peers never end up in the mesh, but they still get everything we publish
+        peers.extend(
+            gossipsub
+                .all_peers()
+                .filter(|(peer, topics)| self.is_online(peer) && topics.contains(&topic))
+                .map(|(peer, _)| *peer),
+        );
+        peers.into_iter().collect()
+    }
+    // Whether gossipsub holds a subscription to `room`, which it does from `join_room` on until `leave_room`
+    pub fn is_subscribed(&self, room: &str) -> bool {
+        let topic = gossipsub::IdentTopic::new(room).hash();
+        self.swarm
+            .behaviour()
+            .gossipsub
+            .topics()
+            .any(|subscribed| *subscribed == topic)
+    }
+    // The peers that would receive a message sent to `room` right now, None if we haven't joined it
+    pub fn subscribed_peers(&self, room: &str) -> Option<Vec<PeerId>> {
+        let topic = self.get_topic_from_name(room)?;
+        Some(self.peers_for(&topic.hash()))
+    }
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &PeerInfo)> {
+        self.peers.iter()
+    }
+    pub fn connected_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.connected.iter()
+    }
+    // Whether something sent to the peer can actually be delivered right now
+    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
+        self.connected.contains(peer_id)
+    }
+    pub fn is_online(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).is_some_and(|info| info.online)
+    }
+    pub fn ping_message(&self) -> InteractionMessage {
+        InteractionMessage::Ping(rand::random())
+    }
+    fn receive_pong(&mut self, peer_id: PeerId, nonce: u64) {
+        let Some(sent) = self.pending_pings.get(&nonce) else {
+            return;
+        };
+        let rtt = self.clock.now().duration_since(*sent);
+        self.latencies.insert(peer_id, rtt);
+        let Some(sweep) = &mut self.ping_sweep else {
+            return;
+        };
+        // a pong only counts for the peer the ping went to
+        if sweep.nonces.get(&nonce) != Some(&peer_id) {
+            return;
+        }
+        sweep.round_trips.insert(peer_id, Some(rtt));
+        if sweep.round_trips.values().all(Option::is_some)
+            && let Some(event) = self.end_ping_sweep()
+        {
+            self.deferred_events.push_back(event);
+        }
+    }
+    // Pings the DM room of every peer we know of, their round trips come as a PingSweep event once all of them
+    // answered or PING_SWEEP_TIMEOUT passed, see `finish_ping_sweep`. Replaces a sweep still running. Returns how many
+    // peers were pinged.
+    pub fn ping_all(&mut self) -> usize {
+        let self_id = self.peer_id();
+        let peers: Vec<PeerId> = self
+            .peers
+            .keys()
+            .filter(|peer_id| **peer_id != self_id)
+            .copied()
+            .collect();
+        let mut sweep = PingSweep {
+            nonces: HashMap::new(),
+            round_trips: HashMap::new(),
+            deadline: self.clock.now() + PING_SWEEP_TIMEOUT,
+        };
+        for peer_id in peers {
+            let nonce: u64 = rand::random();
+            // a peer we can't send to is reported as not answering
+            if let Err(e) = self.send_to_peer(peer_id, &InteractionMessage::Ping(nonce)) {
+                tracing::debug!("Pinging {} failed: {:?}", peer_id, e);
+            }
+            sweep.nonces.insert(nonce, peer_id);
+            sweep.round_trips.insert(peer_id, None);
+        }
+        let pinged = sweep.round_trips.len();
+        self.ping_sweep = (pinged > 0).then_some(sweep);
+        pinged
+    }
+    // When the running `ping_all` gives up on the peers that didn't answer
+    pub fn next_ping_sweep_deadline(&self) -> Option<Instant> {
+        self.ping_sweep.as_ref().map(|sweep| sweep.deadline)
+    }
+    // Ends the running `ping_all` once its time is up
+    pub fn finish_ping_sweep(&mut self) -> Option<GossipEvent> {
+        if self
+            .ping_sweep
+            .as_ref()
+            .is_none_or(|sweep| sweep.deadline > self.clock.now())
+        {
+            return None;
+        }
+        self.end_ping_sweep()
+    }
+    fn end_ping_sweep(&mut self) -> Option<GossipEvent> {
+        let sweep = self.ping_sweep.take()?;
+        let mut round_trips: Vec<(PeerId, Option<Duration>)> =
+            sweep.round_trips.into_iter().collect();
+        round_trips.sort_by_key(|(peer_id, _)| peer_id.to_base58());
+        Some(GossipEvent::PingSweep(round_trips))
+    }
+    fn receive_public_key(
+        &mut self,
+        peer_id: PeerId,
+        topic: &gossipsub::TopicHash,
+        public_key: oqs::sig::PublicKey,
+    ) -> Option<GossipEvent> {
+        let now = self.clock.now();
+        self.public_key_requests
+            .retain(|_, sent| now.duration_since(*sent) < PUBLIC_KEY_REQUEST_TIMEOUT);
+        if !self.public_key_requests.contains_key(topic) {
+            tracing::warn!("Unsolicited public key from {} dropped", peer_id);
+            return None;
+        }
+        match self.secret.learn_key(peer_id, public_key) {
+            Ok(true) => Some(GossipEvent::PublicKeyLearned(peer_id)),
+            Ok(false) => None,
+            Err(e) => {
+                tracing::warn!("Public key from {} rejected: {}", peer_id, e);
+                None
+            }
+        }
+    }
+    // Replaces our signing key and announces the new one, signed with the old one, in every room we're in. Returns
+    // how the announcement went per room.
+    pub fn rotate_signing_key(&mut self) -> Result<RoomOutcomes, GossipError> {
+        let (old_pk, new_pk, signature) = self.secret.rotate_signing_key()?;
+        let rotation = InteractionMessage::KeyRotation(KeyRotation::new(old_pk, new_pk, signature));
+        let topics = self.topics.clone();
+        Ok(topics
+            .into_iter()
+            .map(|(name, topic)| {
+                let result = self.try_gossip(&rotation, topic);
+                (name, result)
+            })
+            .collect())
+    }
+    fn receive_key_rotation(
+        &mut self,
+        peer_id: PeerId,
+        rotation: KeyRotation,
+    ) -> Option<GossipEvent> {
+        if self.secret.known_keys.get(&peer_id) == Some(&rotation.new_pk) {
+            // the same announcement through another room
+            return None;
+        }
+        match self.secret.receive_key_rotation(
+            peer_id,
+            rotation.old_pk,
+            rotation.new_pk,
+            rotation.signature,
+        ) {
+            Ok(()) => Some(GossipEvent::PeerKeyRotated(peer_id)),
+            Err(e) => {
+                tracing::warn!("Key rotation of {} rejected: {}", peer_id, e);
+                None
+            }
+        }
+    }
+    // Joins PUBLIC_DIRECTORY, from then on our public rooms are announced there with every heartbeat that's due and
+    // `known_public_rooms` fills up with the rooms of others
+    pub fn join_public_directory(&mut self) -> Result<(), GossipError> {
+        self.join_room(PUBLIC_DIRECTORY)?;
+        self.last_room_announce = None;
+        Ok(())
+    }
+    // Announces the public rooms we're in to PUBLIC_DIRECTORY. Password protected rooms are left out, their names
+    // aren't meant to be found.
+    pub fn announce_rooms(&mut self) -> Result<PublishOutcome, GossipError> {
+        let topic = self
+            .get_topic_from_name(PUBLIC_DIRECTORY)
+            .ok_or(GossipError::NotJoined(PUBLIC_DIRECTORY.to_string()))?;
+        let rooms: Vec<String> = self
+            .list_rooms()
+            .into_iter()
+            .filter(|room| room.is_public() && room.name() != PUBLIC_DIRECTORY)
+            .map(|room| room.name().to_string())
+            .filter(|name| !self.room_keys.contains_key(name))
+            .collect();
+        let outcome = self.try_gossip(&InteractionMessage::RoomAnnounce(rooms), topic)?;
+        self.last_room_announce = Some(self.clock.now());
+        Ok(outcome)
+    }
+    fn receive_room_announce(&mut self, peer_id: PeerId, rooms: Vec<String>) {
+        self.forget_announced_rooms(&peer_id);
+        for room in rooms.into_iter().take(MAX_ANNOUNCED_ROOMS) {
+            // only names we'd join ourselves, nobody gets to list DM rooms or the directory
+            if room::public_room_name(&room).as_ref() != Some(&room) || room == PUBLIC_DIRECTORY {
+                continue;
+            }
+            self.announced_rooms
+                .entry(room)
+                .or_default()
+                .insert(peer_id);
+        }
+        self.room_announcements.insert(peer_id, self.clock.now());
+    }
+    fn forget_announced_rooms(&mut self, peer_id: &PeerId) {
+        self.room_announcements.remove(peer_id);
+        self.announced_rooms.retain(|_, peers| {
+            peers.remove(peer_id);
+            !peers.is_empty()
+        });
+    }
+    fn expire_room_announcements(&mut self) {
+        let now = self.clock.now();
+        let expired: Vec<PeerId> = self
+            .room_announcements
+            .iter()
+            .filter(|(_, announced)| now.duration_since(**announced) >= ROOM_ANNOUNCE_TTL)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in expired {
+            self.forget_announced_rooms(&peer_id);
+        }
+    }
+    // Public rooms others announced in PUBLIC_DIRECTORY within ROOM_ANNOUNCE_TTL, with the peers in them
+    pub fn known_public_rooms(&self) -> &HashMap<String, HashSet<PeerId>> {
+        &self.announced_rooms
+    }
+    // Responder side of the handshake, the session is usable as soon as our response is out
+    fn receive_shared_secret(
+        &mut self,
+        peer_id: PeerId,
+        exchange: SharedSecretExchange,
+    ) -> Option<GossipEvent> {
+        if !self
+            .secret
+            .resolve_handshake_collision(self.peer_id(), peer_id)
+        {
+            tracing::debug!("Handshake collision with {}, ours goes ahead", peer_id);
+            return None;
+        }
+        // the peer's handshake replaced ours, there's nothing left of ours to resend
+        self.pending_handshakes.remove(&peer_id);
+        let algorithms = (exchange.sig_algorithm, exchange.kem_algorithm);
+        let response = match self.secret.receive_shared_secret(
+            peer_id,
+            algorithms,
+            exchange.kem_pk,
+            exchange.signature,
+            exchange.pk,
+        ) {
+            Ok((kem_ct, signature, pk)) => SharedSecretExchangeResponse::new(kem_ct, signature, pk),
+            Err(e) => {
+                tracing::warn!("Error receiving shared secret from {}: {}", peer_id, e);
+                return None;
+            }
+        };
+        let response = InteractionMessage::SharedSecretExchangeResponse(response);
+        if let Err(e) = self.send_to_peer(peer_id, &response) {
+            tracing::warn!(
+                "Error answering shared secret exchange of {}: {}",
+                peer_id,
+                e
+            );
+            return None;
+        }
+        self.decrypt_failures.remove(&peer_id);
+        Some(GossipEvent::SessionEstablished {
+            peer: peer_id,
+            algorithms,
+        })
+    }
+    fn receive_shared_secret_response(
+        &mut self,
+        peer_id: PeerId,
+        response: SharedSecretExchangeResponse,
+    ) -> Option<GossipEvent> {
+        match self.secret.receive_shared_secret_response(
+            peer_id,
+            response.kem_ct,
+            response.signature,
+            response.pk,
+        ) {
+            Ok(shared_secret) => {
+                shared_secret.into_vec().zeroize();
+                self.decrypt_failures.remove(&peer_id);
+                self.pending_handshakes.remove(&peer_id);
+                Some(GossipEvent::SessionEstablished {
+                    peer: peer_id,
+                    algorithms: self.secret.algorithms(),
+                })
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Error receiving shared secret response of {}: {}",
+                    peer_id,
+                    e
+                );
+                None
+            }
+        }
+    }
+    // Enables gossipsub peer scoring, peers misbehaving in a room lose score and are eventually ignored.
+    // Can only be done once.
+    pub fn with_peer_scoring(
+        mut self,
+        params: gossipsub::PeerScoreParams,
+        thresholds: gossipsub::PeerScoreThresholds,
+    ) -> Result<Self, GossipError> {
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .with_peer_score(params, thresholds.clone())
+            .map_err(GossipError::PeerScoring)?;
+        self.graylist_threshold = Some(thresholds.graylist_threshold);
+        Ok(self)
+    }
+    // None if peer scoring isn't enabled
+    pub fn peer_score(&self, peer_id: &PeerId) -> Option<f64> {
+        self.swarm.behaviour().gossipsub.peer_score(peer_id)
+    }
+    // Peers whose score dropped below the graylist threshold since the last call, gossipsub ignores everything
+    // they send from now on
+    fn newly_graylisted(&mut self) -> Vec<PeerId> {
+        let Some(threshold) = self.graylist_threshold else {
+            return Vec::new();
+        };
+        let connected: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        let mut graylisted = Vec::new();
+        for peer_id in connected {
+            let below = self
+                .peer_score(&peer_id)
+                .is_some_and(|score| score < threshold);
+            if !below {
+                self.graylisted.remove(&peer_id);
+            } else if self.graylisted.insert(peer_id) {
+                graylisted.push(peer_id);
+            }
+        }
+        graylisted
+    }
+    // Pings every peer we have a DM room open with, announces our public rooms when due and reports newly graylisted
+    // peers and timed out transfers, meant to be called every `heartbeat_interval`
+    pub fn heartbeat(&mut self) -> Vec<GossipEvent> {
+        let self_id = self.peer_id();
+        let dm_peers: Vec<PeerId> = self
+            .list_rooms()
+            .iter()
+            .filter_map(Room::owner)
+            .filter(|peer_id| *peer_id != self_id)
+            .collect();
+        for peer_id in dm_peers {
+            let ping = self.ping_message();
+            if let Err(e) = self.send_to_peer(peer_id, &ping) {
+                tracing::debug!("Heartbeat to {} failed: {:?}", peer_id, e);
+            }
+        }
+        let mut events: Vec<GossipEvent> = self
+            .newly_graylisted()
+            .into_iter()
+            .map(GossipEvent::PeerGraylisted)
+            .collect();
+        events.extend(self.expire_transfers());
+        self.expire_room_announcements();
+        let announce_due = self.last_room_announce.is_none_or(|announced| {
+            self.clock.now().duration_since(announced) >= ROOM_ANNOUNCE_INTERVAL
+        });
+        if announce_due
+            && self.get_topic_from_name(PUBLIC_DIRECTORY).is_some()
+            && let Err(e) = self.announce_rooms()
+        {
+            tracing::debug!("Announcing our rooms failed: {:?}", e);
+        }
+        events
+    }
+    fn heard_from(&mut self, peer_id: PeerId) {
+        self.last_heard.insert(peer_id, self.clock.now());
+    }
+    // Whether the peer sent anything within the last few heartbeat intervals
+    pub fn is_peer_alive(&self, peer_id: &PeerId) -> bool {
+        self.last_heard.get(peer_id).is_some_and(|heard| {
+            self.clock.now().duration_since(*heard) < self.heartbeat_interval * HEARTBEAT_MISSES
+        })
+    }
+    pub fn latency(&self, peer_id: &PeerId) -> Option<Duration> {
+        self.latencies.get(peer_id).copied()
+    }
+    // Leaves every room and closes all connections, so peers see us leave instead of timing out.
+    // Calling it again does nothing.
+    pub async fn shutdown(&mut self) {
+        if self.is_shut_down {
+            return;
+        }
+        self.is_shut_down = true;
+        // last chance for queued messages, they can't be published once we left their rooms
+        self.flush_outbound();
+        let rooms: Vec<String> = self.topics.iter().map(|(name, _)| name.clone()).collect();
+        for room in rooms {
+            let _ = self.leave_room(&room);
+        }
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_FLUSH;
+        while let Ok(event) = tokio::time::timeout_at(deadline, self.swarm.select_next_some()).await
+        {
+            let _ = self.handle_event(event);
+        }
+        let connected: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer_id in connected {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+    }
+    fn record_history(&mut self, message: &MessageData) {
+        let history = self
+            .history
+            .entry(message.room.name().to_string())
+            .or_default();
+        while history.len() >= self.history_capacity.max(1) {
+            history.pop_front();
+        }
+        history.push_back(message.clone());
+    }
+    // Up to `limit` of the latest messages received in `room`, oldest first
+    pub fn history(&self, room: &str, limit: usize) -> Vec<&MessageData> {
+        let Some(history) = self.history.get(room) else {
+            return Vec::new();
+        };
+        history
+            .iter()
+            .skip(history.len().saturating_sub(limit))
+            .collect()
+    }
+    // Signs that we're in `room` with our signing key and sends the proof there. In a password protected room it
+    // shows that the key holder also knows the passphrase. Peers that pinned our key add us to their `room_members`.
+    pub fn prove_membership(&mut self, room: &str) -> Result<PublishOutcome, GossipError> {
+        let topic = self
+            .get_topic_from_name(room)
+            .ok_or(GossipError::NotJoined(room.to_string()))?;
+        let timestamp = self.clock.unix_millis();
+        let signature = self.secret.sign_membership(room, timestamp)?;
+        let proof = MembershipProof::new(room.to_string(), timestamp, signature);
+        Ok(self.try_gossip(&InteractionMessage::MembershipProof(proof), topic)?)
+    }
+    // Only proofs for the room they arrive in count, signed by the pinned key of the author within
+    // `max_message_age` of now
+    fn receive_membership_proof(
+        &mut self,
+        peer_id: PeerId,
+        room: &Room,
+        proof: MembershipProof,
+    ) -> Option<GossipEvent> {
+        if proof.room != room.name() {
+            tracing::warn!("Membership proof for {} replayed in {}", proof.room, room);
+            return None;
+        }
+        // unlike message timestamps, a proof from far in the future would stay fresh for too long
+        let skew = Duration::from_millis(self.clock.unix_millis().abs_diff(proof.timestamp));
+        if skew > self.max_message_age {
+            tracing::warn!("Stale membership proof of {} dropped", peer_id);
+            return None;
+        }
+        if let Err(e) =
+            self.secret
+                .verify_membership(peer_id, &proof.room, proof.timestamp, &proof.signature)
+        {
+            tracing::warn!("Membership proof of {} rejected: {}", peer_id, e);
+            return None;
+        }
+        self.room_members
+            .entry(proof.room.clone())
+            .or_default()
+            .insert(peer_id)
+            .then_some(GossipEvent::MemberVerified {
+                peer: peer_id,
+                room: proof.room,
+            })
+    }
+    // Peers that proved they're in `room` and haven't left it since
+    pub fn room_members(&self, room: &str) -> Vec<PeerId> {
+        self.room_members
+            .get(room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+    fn member_left(&mut self, peer_id: &PeerId, room: &str) {
+        if let Some(members) = self.room_members.get_mut(room) {
+            members.remove(peer_id);
+        }
+    }
+    // Opts `room` into catch-up: a peer joining it gets the last `limit` messages of its history in its DM room, 0
+    // turns it off again. Password protected rooms never catch up, whoever joins may not know the passphrase.
+    pub fn set_catch_up(&mut self, room: &str, limit: usize) {
+        if limit == 0 {
+            self.catch_up.remove(room);
+        } else {
+            self.catch_up.insert(room.to_string(), limit);
+        }
+    }
+    fn send_catch_up(&mut self, peer_id: PeerId, room: &Room) {
+        let Some(&limit) = self.catch_up.get(room.name()) else {
+            return;
+        };
+        if self.room_keys.contains_key(room.name()) {
+            return;
+        }
+        let messages: Vec<CatchUpMessage> = self
+            .history(room.name(), limit)
+            .into_iter()
+            .map(|data| CatchUpMessage {
+                peer: data.peer,
+                message: data.message.clone(),
+            })
+            .collect();
+        if messages.is_empty() {
+            return;
+        }
+        let catch_up = InteractionMessage::CatchUp(CatchUp {
+            room: room.name().to_string(),
+            messages,
+        });
+        if let Err(e) = self.send_to_peer(peer_id, &catch_up) {
+            tracing::debug!("Catching up {} on {} failed: {:?}", peer_id, room, e);
+        }
+    }
+    // Catch-up is only taken for rooms we're in, and never goes into our own history so it can't be passed on again
+    fn receive_catch_up(&mut self, peer_id: PeerId, mut catch_up: CatchUp) -> Option<GossipEvent> {
+        if self.get_topic_from_name(&catch_up.room).is_none() {
+            tracing::debug!("Catch-up for {} dropped, we're not in it", catch_up.room);
+            return None;
+        }
+        catch_up.messages.truncate(self.history_capacity);
+        Some(GossipEvent::CatchUp {
+            peer: peer_id,
+            room: catch_up.room,
+            messages: catch_up.messages,
+        })
+    }
+    pub fn take_deferred_events(&mut self) -> VecDeque<GossipEvent> {
+        std::mem::take(&mut self.deferred_events)
+    }
+    pub fn share_group_key(&mut self, room: &str, peer_id: PeerId) -> Result<(), GossipError> {
+        let key = self
+            .secret
+            .share_group_key(peer_id, &self.peer_id().to_bytes(), room)?;
+        self.send_to_peer(
+            peer_id,
+            &InteractionMessage::GroupKeyShare(GroupKeyShare::new(room.to_string(), key)),
+        )?;
+        self.group_members
+            .entry(room.to_string())
+            .or_default()
+            .insert(peer_id);
+        Ok(())
+    }
+    // Replaces the key of `room` and hands the new one to the remaining members, so that a member who left can't
+    // read anything sent afterwards
+    pub fn rekey_group(&mut self, room: &str) {
+        self.secret.derive_group_key(room);
+        let members = self.group_members.remove(room).unwrap_or_default();
+        for peer_id in members {
+            if let Err(e) = self.share_group_key(room, peer_id) {
+                tracing::warn!("Error sharing the new key of {room} with {peer_id}: {e:?}");
+            }
+        }
+    }
+    fn group_member_left(&mut self, peer_id: &PeerId, room: Option<&str>) {
+        let rooms: Vec<String> = self
+            .group_members
+            .iter_mut()
+            .filter(|(name, _)| room.is_none_or(|room| room == name.as_str()))
+            .filter_map(|(name, members)| members.remove(peer_id).then(|| name.clone()))
+            .collect();
+        for room in rooms {
+            self.rekey_group(&room);
+        }
+    }
+    // Splits the file into chunks small enough to be published, each encrypted with the shared secret of `peer_id`.
+    // Returns the transfer id.
+    pub fn send_file(&mut self, peer_id: PeerId, path: &Path) -> Result<u64, GossipError> {
+        let content = fs::read(path)?;
+        let id: u64 = rand::random();
+        let total = content.len().div_ceil(FILE_CHUNK_SIZE).max(1);
+        // the peer would refuse the first chunk anyway
+        if content.len() > MAX_FILE_SIZE {
+            return Err(TransferError::TooLarge {
+                total: total.min(u32::MAX as usize) as u32,
+                max: MAX_FILE_CHUNKS,
+            }
+            .into());
+        }
+        let total = total as u32;
+        let topic = self.dm_topic(peer_id)?;
+        let self_peer_id = self.peer_id();
+        for seq in 0..total {
+            let start = seq as usize * FILE_CHUNK_SIZE;
+            let end = (start + FILE_CHUNK_SIZE).min(content.len());
+            let (nonce, data) = self.secret.encrypt(
+                peer_id,
+                &chunk_aad(&self_peer_id, id, seq),
+                &content[start..end],
+            )?;
+            let chunk = FileChunk {
+                id,
+                seq,
+                total,
+                nonce,
+                data,
+            };
+            self.try_gossip(&InteractionMessage::FileChunk(chunk), topic.clone())?;
+        }
+        self.rekey_if_needed(peer_id)?;
+        Ok(id)
+    }
+    // Joins the DM room of `peer_id`, the room everything meant only for that peer is sent to
+    fn dm_topic(&mut self, peer_id: PeerId) -> Result<gossipsub::IdentTopic, GossipError> {
+        let room_name = peer_id.generate_room_name();
+        self.join_dm_room(peer_id)
+            .map_err(|_| GossipError::NotJoined(room_name.clone()))?;
+        self.get_topic_from_name(&room_name)
+            .ok_or(GossipError::NotJoined(room_name))
+    }
+    // Publishes `message` in the DM room of `peer_id`, joining it first if needed
+    pub fn send_to_peer(
+        &mut self,
+        peer_id: PeerId,
+        message: &InteractionMessage,
+    ) -> Result<PublishOutcome, GossipError> {
+        let topic = self.dm_topic(peer_id)?;
+        Ok(self.try_gossip(message, topic)?)
+    }
+    // Starts a new handshake once the session with `peer_id` is due for rotation. Returns whether one was started.
+    pub fn rekey_if_needed(&mut self, peer_id: PeerId) -> Result<bool, GossipError> {
+        if !self.secret.needs_rekey(&peer_id) {
+            return Ok(false);
+        }
+        let (kem_pk, signature, pk) = self.secret.rekey(peer_id)?;
+        let exchange = SharedSecretExchange::new(self.secret.algorithms(), kem_pk, signature, pk);
+        self.send_to_peer(peer_id, &InteractionMessage::SharedSecretExchange(exchange))?;
+        Ok(true)
+    }
+    // Sends `peer_id` a SharedSecretExchange on its DM room, the session is there once it answers
+    pub fn start_handshake(&mut self, peer_id: PeerId) -> Result<PublishOutcome, GossipError> {
+        let (kem_pk, signature, pk) = self.secret.send_shared_secret(peer_id)?;
+        let exchange = SharedSecretExchange::new(self.secret.algorithms(), kem_pk, signature, pk);
+        self.send_to_peer(peer_id, &InteractionMessage::SharedSecretExchange(exchange))
+    }
+    // Like `Secret::decrypt`, but keeps track of failures so a stale session gets dropped, see `session_failed`
+    pub fn decrypt(
+        &mut self,
+        peer_id: PeerId,
+        aad: &[u8],
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    ) -> Result<Vec<u8>, SecretError> {
+        let decrypted = self.secret.decrypt(peer_id, aad, nonce, ciphertext);
+        self.track_decrypt(peer_id, &decrypted);
+        decrypted
+    }
+    pub fn decrypt_verified(
+        &mut self,
+        peer_id: PeerId,
+        aad: &[u8],
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    ) -> Result<Vec<u8>, SecretError> {
+        let decrypted = self
+            .secret
+            .decrypt_verified(peer_id, aad, nonce, ciphertext);
+        self.track_decrypt(peer_id, &decrypted);
+        decrypted
+    }
+    fn track_decrypt(&mut self, peer_id: PeerId, decrypted: &Result<Vec<u8>, SecretError>) {
+        match decrypted {
+            Ok(_) => {
+                self.decrypt_failures.remove(&peer_id);
+            }
+            // a bad signature or an unknown key say nothing about the session itself
+            Err(SecretError::AuthenticationFailed) => {
+                self.metrics.decrypt_failures += 1;
+                self.session_failed(peer_id);
+            }
+            Err(_) => self.metrics.decrypt_failures += 1,
+        }
+    }
+    // A peer that restarted has a new KEM key, everything it sends fails to decrypt with the secret we still hold.
+    // After SESSION_FAILURE_LIMIT failures in a row the session is dropped, SessionExpired is deferred, and if the
+    // peer is still connected a new handshake is started, at most once per REHANDSHAKE_COOLDOWN.
+    fn session_failed(&mut self, peer_id: PeerId) {
+        let failures = self.decrypt_failures.entry(peer_id).or_default();
+        *failures += 1;
+        if *failures < SESSION_FAILURE_LIMIT {
+            return;
+        }
+        self.decrypt_failures.remove(&peer_id);
+        self.secret.drop_session(&peer_id);
+        tracing::warn!(
+            "Session with {} expired after repeated decryption failures",
+            peer_id
+        );
+        self.deferred_events
+            .push_back(GossipEvent::SessionExpired(peer_id));
+        if !self.auto_rehandshake || !self.connected.contains(&peer_id) {
+            return;
+        }
+        if self
+            .rehandshakes
+            .get(&peer_id)
+            .is_some_and(|last| self.clock.now().duration_since(*last) < REHANDSHAKE_COOLDOWN)
+        {
+            tracing::debug!("Not handshaking with {} again yet", peer_id);
+            return;
+        }
+        self.rehandshakes.insert(peer_id, self.clock.now());
+        if let Err(e) = self.start_handshake(peer_id) {
+            tracing::warn!("Error restarting handshake with {}: {:?}", peer_id, e);
+        }
+    }
+    // Incoming transfers that haven't completed yet
+    pub fn transfers(&self) -> Vec<TransferProgress> {
+        self.incoming_files
+            .iter()
+            .map(|((_, id), file)| file.progress(*id))
+            .collect()
+    }
+    // Drops transfers that stopped receiving chunks, so abandoned ones don't hold on to their chunks forever
+    fn expire_transfers(&mut self) -> Vec<GossipEvent> {
+        let now = self.clock.now();
+        let expired: Vec<(PeerId, u64)> = self
+            .incoming_files
+            .iter()
+            .filter(|(_, file)| file.is_expired(now))
+            .map(|(key, _)| *key)
+            .collect();
+        expired
+            .into_iter()
+            .map(|(peer, id)| {
+                self.incoming_files.remove(&(peer, id));
+                tracing::info!("File {id} from {peer} timed out");
+                GossipEvent::FileFailed {
+                    peer,
+                    id,
+                    reason: format!("no chunk for {:?}", FILE_TRANSFER_TIMEOUT),
+                }
+            })
+            .collect()
+    }
+    fn receive_file_chunk(&mut self, peer_id: PeerId, chunk: FileChunk) -> Option<GossipEvent> {
+        let data = match self.decrypt(
+            peer_id,
+            &chunk_aad(&peer_id, chunk.id, chunk.seq),
+            chunk.nonce,
+            chunk.data,
+        ) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::warn!(
+                    "Error decrypting file chunk {} of {}: {}",
+                    chunk.seq,
+                    chunk.id,
+                    e
+                );
+                return None;
+            }
+        };
+        let now = self.clock.now();
+        let key = (peer_id, chunk.id);
+        if !self.incoming_files.contains_key(&key) {
+            let open = self
+                .incoming_files
+                .keys()
+                .filter(|(peer, _)| *peer == peer_id)
+                .count();
+            let file = if open >= MAX_TRANSFERS_PER_PEER {
+                Err(TransferError::TooManyTransfers {
+                    max: MAX_TRANSFERS_PER_PEER,
+                })
+            } else {
+                IncomingFile::new(peer_id, chunk.total, now)
+            };
+            match file {
+                Ok(file) => self.incoming_files.insert(key, file),
+                Err(e) => return Some(self.refuse_file(peer_id, chunk.id, e)),
+            };
+        }
+        let buffered: usize = self
+            .incoming_files
+            .values()
+            .map(IncomingFile::buffered)
+            .sum();
+        if buffered + data.len() > MAX_BUFFERED_BYTES {
+            let e = TransferError::BufferFull {
+                max: MAX_BUFFERED_BYTES,
+            };
+            return Some(self.refuse_file(peer_id, chunk.id, e));
+        }
+        let file = self.incoming_files.get_mut(&key)?;
+        if let Err(e) = file.insert(chunk.seq, chunk.total, data, now) {
+            tracing::warn!("Chunk {} of file {} dropped: {}", chunk.seq, chunk.id, e);
+            return None;
+        }
+        if !file.is_complete() {
+            let progress = file.progress(chunk.id);
+            return Some(GossipEvent::FileProgress {
+                peer: peer_id,
+                id: chunk.id,
+                received: progress.received,
+                total: progress.total,
+            });
+        }
+        let file = self.incoming_files.remove(&(peer_id, chunk.id))?;
+        Some(GossipEvent::FileCompleted {
+            peer: peer_id,
+            id: chunk.id,
+            data: file.assemble(),
+        })
+    }
+    // Drops the transfer, whatever arrived of it is gone
+    fn refuse_file(&mut self, peer_id: PeerId, id: u64, e: TransferError) -> GossipEvent {
+        tracing::warn!("File {} from {} refused: {}", id, peer_id, e);
+        self.incoming_files.remove(&(peer_id, id));
+        GossipEvent::FileFailed {
+            peer: peer_id,
+            id,
+            reason: e.to_string(),
+        }
+    }
+    pub fn pending_acks(&self) -> &HashMap<gossipsub::MessageId, Instant> {
+        &self.pending_acks
     }
     pub fn handle_event(&mut self, event: SwarmEvent<MyBehaviourEvent>) -> Option<GossipEvent> {
+        self.flush_outbound();
         EventHandler::handle(self, event)
     }
+    // Drives the swarm and yields what `handle_event` makes of it, deferred events first, for embedding into an
+    // event loop without touching libp2p types. `heartbeat`, `retry_listeners` and `retry_handshakes` still have to
+    // be called on their own timers, in between polling the stream.
+    pub fn events(&mut self) -> impl Stream<Item = GossipEvent> + Unpin + '_ {
+        Box::pin(stream::unfold(self, |gossip| async move {
+            loop {
+                if let Some(event) = gossip.deferred_events.pop_front() {
+                    return Some((event, gossip));
+                }
+                let event = gossip.swarm.select_next_some().await;
+                if let Some(event) = gossip.handle_event(event) {
+                    return Some((event, gossip));
+                }
+            }
+        }))
+    }
+}
+
+// The event for a connection refused by the limits of `GossipConfig`, None if something else refused it
+fn connection_limit_reached(peer: Option<PeerId>, cause: &ConnectionDenied) -> Option<GossipEvent> {
+    let exceeded = cause.downcast_ref::<connection_limits::Exceeded>()?;
+    Some(GossipEvent::ConnectionLimitReached {
+        peer,
+        reason: exceeded.to_string(),
+    })
+}
+
+fn build_behaviour(
+    key: &identity::Keypair,
+    gossipsub_config: gossipsub::Config,
+    limits: ConnectionLimits,
+    relay: relay::client::Behaviour,
+) -> Result<MyBehaviour, Box<dyn Error + Send + Sync>> {
+    // build a gossipsub network behaviour
+    let gossipsub = gossipsub::Behaviour::new(
+        gossipsub::MessageAuthenticity::Signed(key.clone()),
+        gossipsub_config,
+    )?;
+
+    let mdns = mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?;
+    let mut kad = kad::Behaviour::with_config(
+        key.public().to_peer_id(),
+        kad::store::MemoryStore::new(key.public().to_peer_id()),
+        kad::Config::new(KAD_PROTOCOL.clone()),
+    );
+    // kad only answers queries once it has a confirmed external address, which nodes on a LAN never get. Every node
+    // is a full peer here, so always answer.
+    kad.set_mode(Some(kad::Mode::Server));
+    // peers tell each other their listen addresses and the address they see us on
+    let identify = identify::Behaviour::new(identify::Config::new(
+        IDENTIFY_PROTOCOL.to_string(),
+        key.public(),
+    ));
+    Ok(MyBehaviour {
+        gossipsub,
+        mdns,
+        identify,
+        limits: connection_limits::Behaviour::new(limits),
+        relay,
+        dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
+        kad,
+    })
 }
 
 pub trait GenerateRoomName {
@@ -173,12 +2407,13 @@ pub trait GenerateRoomName {
 
 impl GenerateRoomName for PeerId {
     fn generate_room_name(&self) -> String {
-        // let mut hasher = DefaultHasher::new();
-        // peer_id.hash(&mut hasher);
-        // let hash = hasher.finish();
-        // format!("public_{hash}")
-        let s = self.to_string();
-        let n = s.char_indices().nth_back(4).unwrap().0;
-        s[n..].to_string()
+        generate_room_name(*self)
     }
 }
+
+// The DM room of a peer is `dm_` followed by its full base58 peer id, e.g. `dm_12D3KooW...`.
+// The base58 encoding of a peer id is unique, so two peers share a DM room only if they are the same peer.
+// Other nodes derive the room of a peer the same way, so this format must not change.
+pub fn generate_room_name(peer: PeerId) -> String {
+    format!("dm_{}", peer.to_base58())
+}
@@ -1,43 +1,219 @@
-use rand::{fill, rngs::ThreadRng};
+use libp2p::PeerId;
+use rand::{RngCore, fill};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-static NONCE_LEN: usize = 16;
+use super::clock::{Clock, SystemClock};
 
+// random salt after the sequence number, keeps messages of different peers with the same sequence number, timestamp
+// and content from getting the same gossipsub message id
+static DEFAULT_NONCE_LEN: usize = 4;
+// big-endian sequence number at the start of the header
+static SEQ_LEN: usize = 8;
+// big-endian unix millis following the salt
+static TIMESTAMP_LEN: usize = 8;
+// how far behind the newest sequence number of a peer a message may arrive, gossipsub delivers messages over several
+// paths that don't keep their order
+pub static SEQ_WINDOW: u64 = 64;
+pub static DEFAULT_MAX_MESSAGE_AGE: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum NonceError {
+    TooShort { len: usize, expected: usize },
+    Expired { age: Duration },
+    // the sequence number was already seen from the peer or is more than SEQ_WINDOW behind the newest one, a
+    // duplicate or a replay
+    OutOfOrder { seq: u64, last: u64 },
+}
+
+// The newest sequence number of a peer and which of the SEQ_WINDOW before it were seen, like the anti-replay window
+// of IPsec
+struct SeqWindow {
+    last: u64,
+    // bit n is set once `last - n` was seen
+    seen: u64,
+}
+impl SeqWindow {
+    fn new(seq: u64) -> Self {
+        Self { last: seq, seen: 1 }
+    }
+    fn check(&mut self, seq: u64) -> Result<(), NonceError> {
+        if seq > self.last {
+            let shift = seq - self.last;
+            self.seen = if shift >= SEQ_WINDOW {
+                1
+            } else {
+                (self.seen << shift) | 1
+            };
+            self.last = seq;
+            return Ok(());
+        }
+        let behind = self.last - seq;
+        if behind >= SEQ_WINDOW || self.seen & (1 << behind) != 0 {
+            return Err(NonceError::OutOfOrder {
+                seq,
+                last: self.last,
+            });
+        }
+        self.seen |= 1 << behind;
+        Ok(())
+    }
+}
+
+// Message header: sequence number | salt | timestamp
 pub struct Nonce {
-    pub nonce_thread: ThreadRng,
+    len: usize,
+    next_seq: u64,
+    // sequence numbers received from each peer
+    last_seq: HashMap<PeerId, SeqWindow>,
+    // where salts come from, None is the thread RNG. Behind a mutex since salts are also drawn through `&self`
+    rng: Option<Mutex<Box<dyn RngCore + Send>>>,
+    // timestamps we send and check the age of received ones against
+    clock: Arc<dyn Clock>,
+}
+impl Default for Nonce {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl Nonce {
     pub fn new() -> Self {
+        Self::with_len(DEFAULT_NONCE_LEN)
+    }
+    pub fn with_len(len: usize) -> Self {
+        Nonce {
+            len,
+            // starting from the current time keeps the sequence increasing across restarts, otherwise peers would drop
+            // everything we send until we catch up with the last sequence number they saw
+            next_seq: SystemClock.unix_millis(),
+            last_seq: HashMap::new(),
+            rng: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+    // Draws salts from `rng` instead of the thread RNG, a seeded one makes the salts the same on every run
+    pub fn with_rng(rng: impl RngCore + Send + 'static) -> Self {
+        Nonce {
+            rng: Some(Mutex::new(Box::new(rng))),
+            ..Self::new()
+        }
+    }
+    // Timestamps messages with `clock` and judges their age by it
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Nonce {
-            nonce_thread: rand::rng(),
+            next_seq: clock.unix_millis(),
+            clock,
+            ..Self::new()
+        }
+    }
+    // Salts from `rng`, timestamps from `clock` and sequence numbers counting up from `first_seq`. The same inputs
+    // frame a message to the same bytes every time, e.g. for golden-file tests.
+    pub fn with_rng_and_clock(
+        rng: impl RngCore + Send + 'static,
+        clock: Arc<dyn Clock>,
+        first_seq: u64,
+    ) -> Self {
+        Nonce {
+            next_seq: first_seq,
+            rng: Some(Mutex::new(Box::new(rng))),
+            clock,
+            ..Self::new()
+        }
+    }
+
+    // Length of the random salt, the header also carries a sequence number and a timestamp
+    pub fn nonce_len(&self) -> usize {
+        self.len
+    }
+    pub fn header_len(&self) -> usize {
+        SEQ_LEN + self.len + TIMESTAMP_LEN
+    }
+    pub fn get_seq(&self, message: &[u8]) -> Option<u64> {
+        let seq = message.get(..SEQ_LEN)?;
+        Some(u64::from_be_bytes(seq.try_into().ok()?))
+    }
+    // Records `seq` as seen from `peer_id`, unless it was seen before or is too far behind the newest one
+    pub fn check_seq(&mut self, peer_id: PeerId, seq: u64) -> Result<(), NonceError> {
+        match self.last_seq.get_mut(&peer_id) {
+            Some(window) => window.check(seq),
+            None => {
+                self.last_seq.insert(peer_id, SeqWindow::new(seq));
+                Ok(())
+            }
         }
     }
 
     // Duplicate messages are apparantly not allowed, so we need to add a nonce to the message
-    pub fn add_nonce(&self, message: &[u8]) -> Vec<u8> {
-        let mut nonce = [0; NONCE_LEN];
-        fill(&mut nonce);
-        let mut data = Vec::with_capacity(message.len() + nonce.len());
-        data.extend_from_slice(&nonce);
+    pub fn add_nonce(&mut self, message: &[u8]) -> Vec<u8> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.add_nonce_with_seq(seq, message)
+    }
+    // Like `add_nonce`, with the sequence number the next message will get but without using it up
+    pub fn peek_nonce(&self, message: &[u8]) -> Vec<u8> {
+        self.add_nonce_with_seq(self.next_seq, message)
+    }
+    fn add_nonce_with_seq(&self, seq: u64, message: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(TIMESTAMP_LEN + message.len());
+        data.extend_from_slice(&self.clock.unix_millis().to_be_bytes());
         data.extend_from_slice(message);
-        data
+        let data = self.add_nonce_wsize(&data, self.len);
+        let mut header = Vec::with_capacity(SEQ_LEN + data.len());
+        header.extend_from_slice(&seq.to_be_bytes());
+        header.extend_from_slice(&data);
+        header
     }
-    pub fn remove_nonce(message: &[u8]) -> Vec<u8> {
-        let mut data = Vec::with_capacity(message.len() - NONCE_LEN);
-        data.extend_from_slice(&message[NONCE_LEN..]);
-        data
+    pub fn remove_nonce(&self, message: &[u8]) -> Result<Vec<u8>, NonceError> {
+        Self::remove_nonce_wsize(message, self.header_len())
+    }
+    // Like `remove_nonce`, but rejects messages whose timestamp is older than `max_age`
+    pub fn remove_nonce_checked(
+        &self,
+        message: &[u8],
+        max_age: Duration,
+    ) -> Result<Vec<u8>, NonceError> {
+        let start = SEQ_LEN + self.len;
+        let Some(timestamp) = message.get(start..start + TIMESTAMP_LEN) else {
+            return Err(NonceError::TooShort {
+                len: message.len(),
+                expected: self.header_len(),
+            });
+        };
+        let sent = u64::from_be_bytes(timestamp.try_into().unwrap());
+        // timestamps from the future are only clock skew, they don't make a message stale
+        let age = Duration::from_millis(self.clock.unix_millis().saturating_sub(sent));
+        if age > max_age {
+            return Err(NonceError::Expired { age });
+        }
+        self.remove_nonce(message)
     }
 
     pub fn add_nonce_wsize(&self, message: &[u8], size: usize) -> Vec<u8> {
         let mut nonce = vec![0; size];
-        fill(&mut nonce[..]);
+        match &self.rng {
+            // a poisoned lock only means a panic while filling, the RNG itself is still usable
+            Some(rng) => rng
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .fill_bytes(&mut nonce),
+            None => fill(&mut nonce[..]),
+        }
         let mut data = Vec::with_capacity(message.len() + nonce.len());
         data.extend_from_slice(&nonce);
         data.extend_from_slice(message);
         data
     }
-    pub fn remove_nonce_wsize(message: &[u8], size: usize) -> Vec<u8> {
-        let mut data = Vec::with_capacity(message.len() - size);
-        data.extend_from_slice(&message[size..]);
-        data
+    pub fn remove_nonce_wsize(message: &[u8], size: usize) -> Result<Vec<u8>, NonceError> {
+        // a truncated or malformed frame must not take the node down
+        let Some(data) = message.get(size..) else {
+            return Err(NonceError::TooShort {
+                len: message.len(),
+                expected: size,
+            });
+        };
+        Ok(data.to_vec())
     }
 }
@@ -0,0 +1,34 @@
+use libp2p::Multiaddr;
+use std::{collections::HashSet, time::Instant};
+
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    // every address the peer was discovered on, kept after it goes offline
+    pub addrs: HashSet<Multiaddr>,
+    pub online: bool,
+    // protocols the peer supports, as reported by identify
+    pub protocols: Vec<String>,
+}
+impl PeerInfo {
+    // `now` comes from the clock of the Gossip keeping track of the peer
+    pub fn new(now: Instant) -> Self {
+        Self {
+            first_seen: now,
+            last_seen: now,
+            addrs: HashSet::new(),
+            online: true,
+            protocols: Vec::new(),
+        }
+    }
+    pub fn seen(&mut self, addr: Multiaddr, now: Instant) {
+        self.last_seen = now;
+        self.online = true;
+        self.addrs.insert(addr);
+    }
+    pub fn gone(&mut self, now: Instant) {
+        self.last_seen = now;
+        self.online = false;
+    }
+}
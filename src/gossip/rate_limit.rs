@@ -0,0 +1,44 @@
+use std::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    // sustained number of messages a peer may send
+    pub per_second: f64,
+    // messages a peer may send at once after being quiet for a while
+    pub burst: u32,
+    // a peer is blocked like with `Gossip::block_peer` once this many of its messages were dropped, never if None
+    pub block_after: Option<u64>,
+}
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            per_second: 20.0,
+            burst: 50,
+            block_after: None,
+        }
+    }
+}
+
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+impl TokenBucket {
+    pub fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+    // Takes a token if there is one left, tokens come back at `per_second` up to `burst`
+    pub fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * limit.per_second;
+        self.tokens = (self.tokens + refill).min(limit.burst as f64);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
@@ -1,10 +1,28 @@
+use hmac::{Hmac, Mac};
 use libp2p::{
     PeerId,
     gossipsub::{self, IdentTopic},
 };
-use std::{error::Error, fmt::Display};
+use serde::Serialize;
+use sha2::Sha256;
+use std::fmt::Display;
+
+use super::GossipError;
+use crate::communication::InteractionMessage;
+
+// longest room name `join_room` accepts, in bytes
+pub static MAX_ROOM_NAME_LEN: usize = 256;
+// Well-known room where nodes announce the public rooms they're in, gossipsub can't subscribe to topic prefixes
+pub static PUBLIC_DIRECTORY: &str = "public_directory";
 
 #[derive(Debug)]
+pub enum RoomLookupError {
+    NotFound,
+    // several discovered peers share the same DM room name, we can't tell which one is meant
+    Ambiguous(Vec<PeerId>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum Room {
     PublicRoom(String),
     DirectMessage(String),
@@ -18,10 +36,10 @@ impl Display for Room {
     }
 }
 impl Room {
-    pub fn name(&self) -> String {
+    pub fn name(&self) -> &str {
         match self {
-            Room::PublicRoom(name) => name.clone(),
-            Room::DirectMessage(name) => name.clone(),
+            Room::PublicRoom(name) => name,
+            Room::DirectMessage(name) => name,
         }
     }
     pub fn is_public(&self) -> bool {
@@ -36,13 +54,123 @@ impl Room {
             Room::DirectMessage(_) => true,
         }
     }
+    // The kind going by the name alone, `Gossip::room_kind` also knows about group keys
+    pub fn kind(&self) -> RoomKind {
+        match self {
+            Room::PublicRoom(_) => RoomKind::Public,
+            Room::DirectMessage(_) => RoomKind::DirectMessage,
+        }
+    }
+    // The peer a DM room belongs to, None for public rooms and DM rooms not named after a peer
+    pub fn owner(&self) -> Option<PeerId> {
+        match self {
+            Room::PublicRoom(_) => None,
+            Room::DirectMessage(name) => name.strip_prefix("dm_")?.parse().ok(),
+        }
+    }
+}
+
+// How the traffic in a room is protected, which decides what may be sent in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RoomKind {
+    // plaintext, anyone in the room reads along
+    Public,
+    // a public room we hold a group key for, chat in it has to be encrypted with that key
+    Group,
+    // the DM room of a peer, private traffic in it is encrypted with the pairwise shared secret
+    DirectMessage,
+}
+impl RoomKind {
+    pub fn encrypted(&self) -> bool {
+        !matches!(self, RoomKind::Public)
+    }
+    // Whether `message` belongs in a room of this kind
+    pub fn accepts(&self, message: &InteractionMessage) -> bool {
+        match message {
+            // key rotations are announced to every room we're in, so peers we only share public rooms with learn the
+            // new key too
+            InteractionMessage::Ping(_)
+            | InteractionMessage::Pong(_)
+            | InteractionMessage::KeyRotation(_)
+            | InteractionMessage::MembershipProof(_)
+            | InteractionMessage::Unknown { .. } => true,
+            InteractionMessage::PublicChat(_) | InteractionMessage::RoomAnnounce(_) => {
+                *self == RoomKind::Public
+            }
+            // a public room without a group key yet still takes them, so a missing key can be reported
+            InteractionMessage::GroupMessage(_) => *self != RoomKind::DirectMessage,
+            // pairwise traffic, requesting public keys in a public room would have everyone in it answer
+            InteractionMessage::RequestPublicKey
+            | InteractionMessage::ReplyPublicKey(_)
+            | InteractionMessage::SharedSecretExchange(_)
+            | InteractionMessage::SharedSecretExchangeResponse(_)
+            | InteractionMessage::SharedSecretCommunication(_)
+            | InteractionMessage::SignedSharedSecretCommunication(_)
+            | InteractionMessage::Ack(_)
+            | InteractionMessage::GroupKeyShare(_)
+            | InteractionMessage::FileChunk(_)
+            | InteractionMessage::CatchUp(_) => *self == RoomKind::DirectMessage,
+        }
+    }
 }
 
 pub trait GossipRooms {
-    fn get_peer_from_room_name(&self, room_name: &str) -> Option<&PeerId>;
+    fn get_peer_from_room_name(&self, room_name: &str) -> Result<&PeerId, RoomLookupError>;
     fn get_topic_from_name(&self, topic_self: &str) -> Option<IdentTopic>;
-    fn join_room(&mut self, topic_str: &str) -> Result<(), Box<dyn Error>>;
-    fn leave_room(&mut self, topic_str: &str) -> Result<(), Box<dyn Error>>;
-    fn get_room_from_hash(&self, topic: gossipsub::TopicHash) -> Room;
+    // Low level, joins the topic as named without checking the `public_` / `dm_` conventions. Prefer
+    // `join_public_room` and `join_dm_room`.
+    fn join_room(&mut self, topic_str: &str) -> Result<(), GossipError>;
+    // Joins `public_<name>`, the prefix may already be part of `name`. Returns the full room name.
+    fn join_public_room(&mut self, name: &str) -> Result<String, GossipError>;
+    fn join_dm_room(&mut self, peer: PeerId) -> Result<String, GossipError>;
+    fn leave_room(&mut self, topic_str: &str) -> Result<(), GossipError>;
+    fn get_room_from_hash(&self, topic: gossipsub::TopicHash) -> Option<Room>;
     fn get_room_from_name(&self, topic: String) -> Room;
+    fn list_rooms(&self) -> Vec<Room>;
+}
+
+// Whether `name` can be a room: not empty, at most MAX_ROOM_NAME_LEN bytes, and without control characters or
+// whitespace that would garble the CLI output
+pub fn valid_room_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME_LEN
+        && !name.chars().any(|c| c.is_control() || c.is_whitespace())
+}
+
+// The name of a public room with the `public_` prefix, None if it could be mistaken for a DM room or a peer, or isn't
+// a valid room name
+pub fn public_room_name(name: &str) -> Option<String> {
+    let bare = name.strip_prefix("public_").unwrap_or(name);
+    if bare.is_empty() || bare.starts_with("dm_") || bare.parse::<PeerId>().is_ok() {
+        return None;
+    }
+    let name = format!("public_{}", bare);
+    valid_room_name(&name).then_some(name)
+}
+
+type HmacSha256 = Hmac<Sha256>;
+static ROOM_TAG_LEN: usize = 32;
+
+// Key used to authenticate the messages of a password protected room, bound to the room name so the same passphrase
+// gives different keys in different rooms
+pub fn derive_room_key(room: &str, passphrase: &str) -> [u8; 32] {
+    let mut mac =
+        HmacSha256::new_from_slice(passphrase.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(room.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+// Appends the HMAC of `data` under the room key
+pub fn tag_payload(key: &[u8; 32], mut data: Vec<u8>) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&data);
+    data.extend_from_slice(&mac.finalize().into_bytes());
+    data
+}
+// Strips the tag added by `tag_payload`, None if it's missing or doesn't match
+pub fn verify_payload<'a>(key: &[u8; 32], data: &'a [u8]) -> Option<&'a [u8]> {
+    let (payload, tag) = data.split_at_checked(data.len().checked_sub(ROOM_TAG_LEN)?)?;
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(payload);
+    mac.verify_slice(tag).ok()?;
+    Some(payload)
 }
@@ -1,46 +1,307 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use libp2p::PeerId;
 use oqs::{
     kem::{self, Kem, SharedSecret},
     sig::{self, Sig},
 };
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use zeroize::Zeroize;
 
-use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use super::clock::{Clock, SystemClock};
+use super::verify_cache::{DEFAULT_VERIFY_CACHE_CAPACITY, VerifyCache};
+
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
 use aes_gcm::{Aes256Gcm, Key, Nonce, aead::rand_core::RngCore}; // AES-GCM cipher // Traits and random number generator
 
+pub static DEFAULT_SIG_ALGORITHM: sig::Algorithm = sig::Algorithm::MlDsa87;
+pub static DEFAULT_KEM_ALGORITHM: kem::Algorithm = kem::Algorithm::MlKem1024;
+// a session is rotated after this many messages or this long, whichever comes first
+pub static DEFAULT_REKEY_AFTER_MESSAGES: u64 = 1000;
+pub static DEFAULT_REKEY_AFTER: Duration = Duration::from_secs(60 * 60);
+// how long a peer's signing key stays valid after it rotated to a new one, for messages that were already underway
+pub static DEFAULT_KEY_ROTATION_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+// signed along with the new key in a rotation, so a rotation signature can't be passed off as any other signature
+static KEY_ROTATION_CONTEXT: &[u8] = b"stockchain key rotation";
+// the same for membership proofs, see `sign_membership`
+static MEMBERSHIP_CONTEXT: &[u8] = b"stockchain room membership";
+// key file layout: magic | algorithm name length (u8) | algorithm name | public key length (u32 BE) | public key | private key
+// AES-GCM appends its authentication tag to the ciphertext
+static TAG_LEN: usize = 16;
+static KEY_FILE_MAGIC: &[u8; 4] = b"SCK1";
+// bytes of the SHA-256 of a key kept in its fingerprint
+static FINGERPRINT_LEN: usize = 16;
+
+// There's no error for a malformed nonce: nonces are a fixed `[u8; 12]`, one of the wrong length is already refused
+// when the message is decoded. A corrupted nonce surfaces as AuthenticationFailed.
+#[derive(Debug)]
+pub enum SecretError {
+    AlgorithmMismatch { expected: String, found: String },
+    MalformedKeyFile,
+    // an encoded public key that isn't valid base64 or has the wrong length for our signature algorithm, or a KEM
+    // public key of the wrong length
+    MalformedKey,
+    // a KEM ciphertext of the wrong length for our KEM algorithm, or an AES-GCM ciphertext too short to hold its tag
+    MalformedCiphertext,
+    // AES-GCM rejected the ciphertext: it was tampered with, or the nonce, AAD or key don't match the ones it was
+    // sealed with. GCM can't tell these apart.
+    AuthenticationFailed,
+    // the peer presented a different signing key than the one we pinned for it
+    KeyMismatch(PeerId),
+    // a signed message whose signature doesn't verify against the pinned key of the sender
+    BadSignature(PeerId),
+    // we have no pinned key to verify a signed message of the peer with
+    UnknownKey(PeerId),
+    // no handshake was ever completed with the peer
+    NoSession(PeerId),
+    // a handshake response from a peer we have no request outstanding with
+    NoPendingHandshake(PeerId),
+    // we hold no key for the group room
+    NoGroupKey(String),
+    // a shared group key that isn't 32 bytes
+    MalformedGroupKey,
+    Oqs(oqs::Error),
+}
+impl Display for SecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretError::AlgorithmMismatch { expected, found } => write!(
+                f,
+                "Algorithm mismatch: expected {}, found {}",
+                expected, found
+            ),
+            SecretError::MalformedKeyFile => write!(f, "Malformed key file"),
+            SecretError::MalformedKey => write!(f, "Malformed public key"),
+            SecretError::MalformedCiphertext => write!(f, "Malformed ciphertext"),
+            SecretError::AuthenticationFailed => write!(f, "Ciphertext failed authentication"),
+            SecretError::KeyMismatch(peer_id) => {
+                write!(f, "Public key of {} doesn't match the pinned one", peer_id)
+            }
+            SecretError::BadSignature(peer_id) => write!(f, "Bad signature from {}", peer_id),
+            SecretError::UnknownKey(peer_id) => write!(f, "No public key known for {}", peer_id),
+            SecretError::NoSession(peer_id) => {
+                write!(f, "No shared secret established with {}", peer_id)
+            }
+            SecretError::NoPendingHandshake(peer_id) => {
+                write!(f, "No handshake with {} is pending", peer_id)
+            }
+            SecretError::NoGroupKey(room) => write!(f, "No group key for {}", room),
+            SecretError::MalformedGroupKey => write!(f, "Malformed group key"),
+            SecretError::Oqs(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for SecretError {}
+impl From<oqs::Error> for SecretError {
+    fn from(err: oqs::Error) -> Self {
+        SecretError::Oqs(err)
+    }
+}
+
+struct Session {
+    established: Instant,
+    // messages encrypted or decrypted with the session's secret
+    messages: Cell<u64>,
+}
+impl Session {
+    fn new(established: Instant) -> Self {
+        Self {
+            established,
+            messages: Cell::new(0),
+        }
+    }
+    fn used(&self) {
+        self.messages.set(self.messages.get() + 1);
+    }
+}
+
+// Where the handshake with a peer stands, see `Secret::handshake_state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    None,
+    // we sent a SharedSecretExchange and wait for the response, a session may already be in use while rekeying
+    Initiated,
+    Established,
+}
+
 pub struct Secret {
     sig: Sig,
     kem: Kem,
     pub private_key: oqs::sig::SecretKey,
     pub public_key: oqs::sig::PublicKey,
     pub shared_secret: HashMap<PeerId, SharedSecret>,
+    // the secret a rekey replaced, messages sealed with it may still be on their way. `decrypt` falls back to it
+    // until a message under the new secret decrypts.
+    superseded: RefCell<HashMap<PeerId, SharedSecret>>,
     pub shared_secret_unresponded_requests: HashMap<PeerId, kem::SecretKey>,
+    pub group_keys: HashMap<String, [u8; 32]>,
+    // signing keys of peers, pinned the first time we see them (trust on first use)
+    pub known_keys: HashMap<PeerId, sig::PublicKey>,
+    // keys peers rotated away from and when, still accepted for `key_rotation_grace`
+    retired_keys: HashMap<PeerId, (sig::PublicKey, Instant)>,
+    pub key_rotation_grace: Duration,
+    sessions: HashMap<PeerId, Session>,
+    pub rekey_after_messages: u64,
+    pub rekey_after: Duration,
+    // ML-DSA verification is slow, re-delivered signed messages are answered from here
+    verify_cache: RefCell<VerifyCache>,
+    // what session age and the grace period of retired keys are measured with
+    pub clock: Arc<dyn Clock>,
 }
 impl Secret {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
-        let sig = Sig::new(sig::Algorithm::MlDsa87)?;
+    pub fn new() -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::with_algorithms(DEFAULT_SIG_ALGORITHM, DEFAULT_KEM_ALGORITHM)
+    }
+    // Both sides of a handshake have to use the same algorithms, see `check_algorithms`
+    pub fn with_algorithms(
+        sig_algorithm: sig::Algorithm,
+        kem_algorithm: kem::Algorithm,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let sig = Sig::new(sig_algorithm)?;
         let (public_key, private_key) = sig.keypair()?;
+        Self::from_keys(sig, Kem::new(kem_algorithm)?, public_key, private_key)
+    }
+    // Reuses the signing keypair stored at `path` so the public key other peers cached stays valid across restarts,
+    // otherwise generates a new one and saves it there.
+    pub fn load_or_generate(path: &Path) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let sig = Sig::new(DEFAULT_SIG_ALGORITHM)?;
+        if path.exists() {
+            let mut data = fs::read(path)?;
+            let keys = Self::parse_key_file(&sig, &data);
+            data.zeroize();
+            let (public_key, private_key) = keys?;
+            return Self::from_keys(
+                sig,
+                Kem::new(DEFAULT_KEM_ALGORITHM)?,
+                public_key,
+                private_key,
+            );
+        }
+        let secret = Self::new()?;
+        secret.save_keys(path)?;
+        Ok(secret)
+    }
+    fn from_keys(
+        sig: Sig,
+        kem: Kem,
+        public_key: sig::PublicKey,
+        private_key: sig::SecretKey,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
         Ok(Self {
             sig,
             private_key,
             public_key,
-            kem: Kem::new(kem::Algorithm::MlKem1024)?,
+            kem,
             shared_secret: HashMap::new(),
+            superseded: RefCell::new(HashMap::new()),
             shared_secret_unresponded_requests: HashMap::new(),
+            group_keys: HashMap::new(),
+            known_keys: HashMap::new(),
+            retired_keys: HashMap::new(),
+            key_rotation_grace: DEFAULT_KEY_ROTATION_GRACE,
+            sessions: HashMap::new(),
+            rekey_after_messages: DEFAULT_REKEY_AFTER_MESSAGES,
+            rekey_after: DEFAULT_REKEY_AFTER,
+            verify_cache: RefCell::new(VerifyCache::new(DEFAULT_VERIFY_CACHE_CAPACITY)),
+            clock: Arc::new(SystemClock),
         })
     }
+    pub fn save_keys(&self, path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let name = self.sig.algorithm().name().as_bytes();
+        let public_key = self.public_key.as_ref();
+        let mut data = Vec::with_capacity(
+            KEY_FILE_MAGIC.len() + 1 + name.len() + 4 + public_key.len() + self.private_key.len(),
+        );
+        data.extend_from_slice(KEY_FILE_MAGIC);
+        data.push(name.len() as u8);
+        data.extend_from_slice(name);
+        data.extend_from_slice(&(public_key.len() as u32).to_be_bytes());
+        data.extend_from_slice(public_key);
+        data.extend_from_slice(self.private_key.as_ref());
+        let result = write_private(path, &data);
+        // the buffer holds a copy of the private key, don't leave it lying around in memory
+        data.zeroize();
+        Ok(result?)
+    }
+    fn parse_key_file(
+        sig: &Sig,
+        data: &[u8],
+    ) -> Result<(sig::PublicKey, sig::SecretKey), SecretError> {
+        let data = data
+            .strip_prefix(KEY_FILE_MAGIC)
+            .ok_or(SecretError::MalformedKeyFile)?;
+        let (&name_len, data) = data.split_first().ok_or(SecretError::MalformedKeyFile)?;
+        let (name, data) = data
+            .split_at_checked(name_len as usize)
+            .ok_or(SecretError::MalformedKeyFile)?;
+        let name = String::from_utf8_lossy(name);
+        if name != sig.algorithm().name() {
+            return Err(SecretError::AlgorithmMismatch {
+                expected: sig.algorithm().name().to_string(),
+                found: name.to_string(),
+            });
+        }
+        let (public_key_len, data) = data
+            .split_first_chunk::<4>()
+            .ok_or(SecretError::MalformedKeyFile)?;
+        let (public_key, private_key) = data
+            .split_at_checked(u32::from_be_bytes(*public_key_len) as usize)
+            .ok_or(SecretError::MalformedKeyFile)?;
+        let public_key = sig
+            .public_key_from_bytes(public_key)
+            .ok_or(SecretError::MalformedKeyFile)?;
+        let private_key = sig
+            .secret_key_from_bytes(private_key)
+            .ok_or(SecretError::MalformedKeyFile)?;
+        Ok((public_key.to_owned(), private_key.to_owned()))
+    }
     pub fn keys(&self) -> (oqs::sig::PublicKey, oqs::sig::SecretKey) {
         (self.public_key.clone(), self.private_key.clone())
     }
+    pub fn algorithms(&self) -> (sig::Algorithm, kem::Algorithm) {
+        (self.sig.algorithm(), self.kem.algorithm())
+    }
+    // A peer configured with other algorithms would only fail later on with an opaque verification error
+    pub fn check_algorithms(
+        &self,
+        sig_algorithm: sig::Algorithm,
+        kem_algorithm: kem::Algorithm,
+    ) -> Result<(), SecretError> {
+        if sig_algorithm != self.sig.algorithm() {
+            return Err(SecretError::AlgorithmMismatch {
+                expected: self.sig.algorithm().name().to_string(),
+                found: sig_algorithm.name().to_string(),
+            });
+        }
+        if kem_algorithm != self.kem.algorithm() {
+            return Err(SecretError::AlgorithmMismatch {
+                expected: self.kem.algorithm().name().to_string(),
+                found: kem_algorithm.name().to_string(),
+            });
+        }
+        Ok(())
+    }
     pub fn send_shared_secret(
         &mut self,
         peer_id: PeerId,
     ) -> Result<(kem::PublicKey, sig::Signature, sig::PublicKey), oqs::Error> {
+        // a fresh KEM keypair every time, so a leaked session secret doesn't expose the other sessions
         let (kem_pk, kem_sk) = self.kem.keypair()?;
         let signature = self.sig.sign(kem_pk.as_ref(), &self.private_key)?;
-        self.shared_secret_unresponded_requests
-            .insert(peer_id, kem_sk);
+        if let Some(old) = self
+            .shared_secret_unresponded_requests
+            .insert(peer_id, kem_sk)
+        {
+            old.into_vec().zeroize();
+        }
         // A -> B: kem_pk, signature, pk
         Ok((kem_pk, signature, self.public_key.clone()))
     }
@@ -48,15 +309,23 @@ impl Secret {
     pub fn receive_shared_secret(
         &mut self,
         peer_id: PeerId,
+        algorithms: (sig::Algorithm, kem::Algorithm),
         kem_pk: kem::PublicKey,
         signature: sig::Signature,
         pk: sig::PublicKey,
-    ) -> Result<(kem::Ciphertext, sig::Signature, sig::PublicKey), oqs::Error> {
+    ) -> Result<(kem::Ciphertext, sig::Signature, sig::PublicKey), SecretError> {
+        self.check_algorithms(algorithms.0, algorithms.1)?;
+        // the bytes come straight from the wire, don't let oqs read past them
+        if kem_pk.len() != self.kem.length_public_key() {
+            return Err(SecretError::MalformedKey);
+        }
+        self.check_key(peer_id, &pk)?;
         self.sig.verify(kem_pk.as_ref(), &signature, &pk)?;
         let (kem_ct, kem_ss) = self.kem.encapsulate(&kem_pk)?;
         let signature = self.sig.sign(kem_ct.as_ref(), &self.private_key)?;
 
-        self.shared_secret.insert(peer_id, kem_ss);
+        self.known_keys.entry(peer_id).or_insert(pk);
+        self.install_session(peer_id, kem_ss);
         // B -> A: kem_ct, signature
         Ok((kem_ct, signature, self.public_key.clone()))
     }
@@ -67,63 +336,515 @@ impl Secret {
         kem_ct: kem::Ciphertext,
         signature: sig::Signature,
         pk: sig::PublicKey,
-    ) -> Result<SharedSecret, oqs::Error> {
+    ) -> Result<SharedSecret, SecretError> {
         let kem_sk = self.shared_secret_unresponded_requests.get(&peer_id);
         let Some(kem_sk) = kem_sk else {
             // We didn't request a communication, most likely someone trying to find a bug
-            return Err(oqs::Error::Error);
+            return Err(SecretError::NoPendingHandshake(peer_id));
         };
+        if kem_ct.len() != self.kem.length_ciphertext() {
+            return Err(SecretError::MalformedCiphertext);
+        }
 
+        self.check_key(peer_id, &pk)?;
         self.sig.verify(kem_ct.as_ref(), &signature, &pk)?;
         let shared_secret = self.kem.decapsulate(kem_sk, &kem_ct)?;
-        self.shared_secret.insert(peer_id, shared_secret.clone());
+        // the ephemeral key did its job, drop it so the session can't be recovered from it later
+        if let Some(kem_sk) = self.shared_secret_unresponded_requests.remove(&peer_id) {
+            kem_sk.into_vec().zeroize();
+        }
+        self.known_keys.entry(peer_id).or_insert(pk);
+        self.install_session(peer_id, shared_secret.clone());
         Ok(shared_secret)
     }
+    fn install_session(&mut self, peer_id: PeerId, shared_secret: SharedSecret) {
+        if let Some(old) = self.shared_secret.insert(peer_id, shared_secret)
+            && let Some(older) = self.superseded.get_mut().insert(peer_id, old)
+        {
+            older.into_vec().zeroize();
+        }
+        self.sessions
+            .insert(peer_id, Session::new(self.clock.now()));
+    }
+    // True once the session with `peer_id` was used for too many messages or for too long, and no rekey is
+    // underway yet
+    pub fn needs_rekey(&self, peer_id: &PeerId) -> bool {
+        let Some(session) = self.sessions.get(peer_id) else {
+            return false;
+        };
+        !self
+            .shared_secret_unresponded_requests
+            .contains_key(peer_id)
+            && (session.messages.get() >= self.rekey_after_messages
+                || self.clock.now().duration_since(session.established) >= self.rekey_after)
+    }
+    pub fn handshake_state(&self, peer_id: &PeerId) -> HandshakeState {
+        if self
+            .shared_secret_unresponded_requests
+            .contains_key(peer_id)
+        {
+            HandshakeState::Initiated
+        } else if self.shared_secret.contains_key(peer_id) {
+            HandshakeState::Established
+        } else {
+            HandshakeState::None
+        }
+    }
+    // Both sides sent a SharedSecretExchange before seeing the other's, answering both would leave each side with a
+    // different secret. The side with the smaller peer id stays the initiator: returns false if the exchange of
+    // `peer_id` is to be ignored because ours goes ahead, otherwise our own handshake is dropped and theirs should be
+    // answered.
+    pub fn resolve_handshake_collision(&mut self, local_peer_id: PeerId, peer_id: PeerId) -> bool {
+        if self.handshake_state(&peer_id) != HandshakeState::Initiated {
+            return true;
+        }
+        if local_peer_id.to_bytes() < peer_id.to_bytes() {
+            return false;
+        }
+        self.cancel_handshake(&peer_id);
+        true
+    }
+    // Forgets the KEM key of a handshake the peer never answered, a late response to it is rejected
+    pub fn cancel_handshake(&mut self, peer_id: &PeerId) {
+        if let Some(kem_sk) = self.shared_secret_unresponded_requests.remove(peer_id) {
+            kem_sk.into_vec().zeroize();
+        }
+    }
+    // Starts a new handshake with `peer_id`, the current session stays in use until the peer responds
+    pub fn rekey(
+        &mut self,
+        peer_id: PeerId,
+    ) -> Result<(kem::PublicKey, sig::Signature, sig::PublicKey), oqs::Error> {
+        self.send_shared_secret(peer_id)
+    }
+    // Rejects a key that differs from the one pinned for the peer, a key for an unknown peer is accepted and pinned
+    // once the handshake succeeds. The key a peer rotated away from is still accepted during the grace period.
+    fn check_key(&self, peer_id: PeerId, pk: &sig::PublicKey) -> Result<(), SecretError> {
+        match self.known_keys.get(&peer_id) {
+            Some(known) if known != pk && self.retired_key(&peer_id) != Some(pk) => {
+                Err(SecretError::KeyMismatch(peer_id))
+            }
+            _ => Ok(()),
+        }
+    }
+    fn retired_key(&self, peer_id: &PeerId) -> Option<&sig::PublicKey> {
+        let (pk, retired) = self.retired_keys.get(peer_id)?;
+        (self.clock.now().duration_since(*retired) < self.key_rotation_grace).then_some(pk)
+    }
+    // Replaces our signing keypair with a new one. Returns the old public key, the new one and the new one signed
+    // with the old private key, for peers that pinned the old key to check with `receive_key_rotation`.
+    // Keys saved with `save_keys` have to be saved again.
+    pub fn rotate_signing_key(
+        &mut self,
+    ) -> Result<(sig::PublicKey, sig::PublicKey, sig::Signature), SecretError> {
+        let (public_key, private_key) = self.sig.keypair()?;
+        let signature = self
+            .sig
+            .sign(&rotation_message(&public_key), &self.private_key)?;
+        let old_public_key = std::mem::replace(&mut self.public_key, public_key.clone());
+        self.private_key = private_key;
+        Ok((old_public_key, public_key, signature))
+    }
+    // Pins the peer's new key if the rotation is signed by the key we have pinned for it. The old key stays accepted
+    // for `key_rotation_grace`.
+    pub fn receive_key_rotation(
+        &mut self,
+        peer_id: PeerId,
+        old_pk: sig::PublicKey,
+        new_pk: sig::PublicKey,
+        signature: sig::Signature,
+    ) -> Result<(), SecretError> {
+        let Some(known) = self.known_keys.get(&peer_id) else {
+            return Err(SecretError::UnknownKey(peer_id));
+        };
+        if *known != old_pk {
+            return Err(SecretError::KeyMismatch(peer_id));
+        }
+        self.sig
+            .verify(&rotation_message(&new_pk), &signature, &old_pk)
+            .map_err(|_| SecretError::BadSignature(peer_id))?;
+        self.known_keys.insert(peer_id, new_pk);
+        self.retired_keys
+            .insert(peer_id, (old_pk, self.clock.now()));
+        Ok(())
+    }
+    // Signs that we're in `room` as of `timestamp`, for `verify_membership` on the other side
+    pub fn sign_membership(
+        &self,
+        room: &str,
+        timestamp: u64,
+    ) -> Result<sig::Signature, SecretError> {
+        Ok(self
+            .sig
+            .sign(&membership_message(room, timestamp), &self.private_key)?)
+    }
+    // Checks a membership proof of `peer_id` against its pinned key, or the key it rotated away from during the grace
+    // period
+    pub fn verify_membership(
+        &self,
+        peer_id: PeerId,
+        room: &str,
+        timestamp: u64,
+        signature: &sig::Signature,
+    ) -> Result<(), SecretError> {
+        let Some(pk) = self.known_keys.get(&peer_id) else {
+            return Err(SecretError::UnknownKey(peer_id));
+        };
+        let message = membership_message(room, timestamp);
+        let signed_by = |pk: &sig::PublicKey| self.sig.verify(&message, signature, pk).is_ok();
+        if signed_by(pk) || self.retired_key(&peer_id).is_some_and(signed_by) {
+            Ok(())
+        } else {
+            Err(SecretError::BadSignature(peer_id))
+        }
+    }
+    // Stores a key the peer sent us outside of a handshake, returns false if it was already known
+    pub fn learn_key(&mut self, peer_id: PeerId, pk: sig::PublicKey) -> Result<bool, SecretError> {
+        self.check_key(peer_id, &pk)?;
+        Ok(self.known_keys.insert(peer_id, pk).is_none())
+    }
+    // Our public key as base64, to be handed to others over a channel they trust
+    pub fn public_key_encoded(&self) -> String {
+        BASE64.encode(self.public_key.as_ref())
+    }
+    // Pins a key received out of band through `public_key_encoded`, handshakes with the peer are then only accepted
+    // with that key
+    pub fn pin_key_from_encoded(
+        &mut self,
+        peer_id: PeerId,
+        encoded: &str,
+    ) -> Result<(), SecretError> {
+        let bytes = BASE64
+            .decode(encoded.trim())
+            .map_err(|_| SecretError::MalformedKey)?;
+        let pk = self
+            .sig
+            .public_key_from_bytes(&bytes)
+            .ok_or(SecretError::MalformedKey)?;
+        self.pin_key(peer_id, pk.to_owned());
+        Ok(())
+    }
+    pub fn pin_key(&mut self, peer_id: PeerId, pk: sig::PublicKey) {
+        self.known_keys.insert(peer_id, pk);
+    }
+    pub fn forget_key(&mut self, peer_id: &PeerId) -> Option<sig::PublicKey> {
+        self.known_keys.remove(peer_id)
+    }
+    // The keys we pinned, whether out of band or on first use
+    pub fn pinned_keys(&self) -> impl Iterator<Item = (&PeerId, &sig::PublicKey)> {
+        self.known_keys.iter()
+    }
+    // Short base64 hash of `pk` for two people to compare out of band, it differs if someone swapped the key in
+    // between
+    pub fn fingerprint(pk: &sig::PublicKey) -> String {
+        let hash = Sha256::digest(pk.as_ref());
+        BASE64.encode(&hash[..FINGERPRINT_LEN])
+    }
+    // Peers we currently share a secret with
+    pub fn sessions(&self) -> impl Iterator<Item = &PeerId> {
+        self.shared_secret.keys()
+    }
+    // Forgets the shared secret with `peer_id`, a new handshake is needed to talk to it privately again. Returns
+    // whether there was one.
+    pub fn drop_session(&mut self, peer_id: &PeerId) -> bool {
+        self.sessions.remove(peer_id);
+        if let Some(old) = self.superseded.get_mut().remove(peer_id) {
+            old.into_vec().zeroize();
+        }
+        match self.shared_secret.remove(peer_id) {
+            Some(shared_secret) => {
+                shared_secret.into_vec().zeroize();
+                true
+            }
+            None => false,
+        }
+    }
+    // True once a handshake with `peer_id` completed, `encrypt` and `decrypt` fail with NoSession until then
+    pub fn has_session(&self, peer_id: &PeerId) -> bool {
+        self.shared_secret.contains_key(peer_id)
+    }
+    // `aad` is authenticated but not encrypted, pass the sender's peer id so the ciphertext can't be relayed as
+    // someone else's
     pub fn encrypt(
         &self,
         peer_id: PeerId,
+        aad: &[u8],
         message: &[u8],
-    ) -> Result<([u8; 12], Vec<u8>), oqs::Error> {
-        let kem_ss = self.shared_secret.get(&peer_id);
-        let Some(kem_ss) = kem_ss else {
-            // We don't have a shared secret with this peer, most likely someone trying to find a bug
-            return Err(oqs::Error::Error);
+    ) -> Result<([u8; 12], Vec<u8>), SecretError> {
+        let Some(kem_ss) = self.shared_secret.get(&peer_id) else {
+            return Err(SecretError::NoSession(peer_id));
         };
 
-        let key = Key::<Aes256Gcm>::from_slice(&kem_ss.as_ref());
-        let cipher = Aes256Gcm::new(key);
-
-        let mut nonce_bytes = [0u8; 12];
-        OsRng.fill_bytes(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+        let sealed = seal(kem_ss.as_ref(), aad, message)?;
+        if let Some(session) = self.sessions.get(&peer_id) {
+            session.used();
+        }
+        Ok(sealed)
+    }
+    pub fn decrypt(
+        &self,
+        peer_id: PeerId,
+        aad: &[u8],
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    ) -> Result<Vec<u8>, SecretError> {
+        let Some(kem_ss) = self.shared_secret.get(&peer_id) else {
+            // most likely someone trying to find a bug
+            return Err(SecretError::NoSession(peer_id));
+        };
 
-        let ciphertext = cipher
-            .encrypt(nonce, message)
-            .map_err(|_| oqs::Error::Error)?;
+        let plaintext = match open(kem_ss.as_ref(), aad, nonce, ciphertext.clone()) {
+            Ok(plaintext) => {
+                // the peer moved on to the new secret, nothing sealed with the old one is coming anymore
+                if let Some(old) = self.superseded.borrow_mut().remove(&peer_id) {
+                    old.into_vec().zeroize();
+                }
+                plaintext
+            }
+            Err(SecretError::AuthenticationFailed) => {
+                match self.superseded.borrow().get(&peer_id) {
+                    // sent before the peer saw the rekey
+                    Some(old) => return open(old.as_ref(), aad, nonce, ciphertext),
+                    None => return Err(SecretError::AuthenticationFailed),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(session) = self.sessions.get(&peer_id) {
+            session.used();
+        }
+        Ok(plaintext)
+    }
+    // Signs `message` with our ML-DSA key before encrypting it, so the receiver can tell it was written by us and
+    // not just by someone holding the session secret.
+    // plaintext layout: signature length (u32 BE) | signature | message
+    pub fn encrypt_signed(
+        &self,
+        peer_id: PeerId,
+        aad: &[u8],
+        message: &[u8],
+    ) -> Result<([u8; 12], Vec<u8>), SecretError> {
+        if !self.has_session(&peer_id) {
+            return Err(SecretError::NoSession(peer_id));
+        }
+        let signature = self.sig.sign(message, &self.private_key)?;
+        let signature = signature.as_ref();
+        let mut plaintext = Vec::with_capacity(4 + signature.len() + message.len());
+        plaintext.extend_from_slice(&(signature.len() as u32).to_be_bytes());
+        plaintext.extend_from_slice(signature);
+        plaintext.extend_from_slice(message);
+        let sealed = self.encrypt(peer_id, aad, &plaintext);
+        plaintext.zeroize();
+        sealed
+    }
+    pub fn decrypt_verified(
+        &self,
+        peer_id: PeerId,
+        aad: &[u8],
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    ) -> Result<Vec<u8>, SecretError> {
+        let Some(pk) = self.known_keys.get(&peer_id) else {
+            return Err(SecretError::UnknownKey(peer_id));
+        };
+        let mut plaintext = self.decrypt(peer_id, aad, nonce, ciphertext)?;
+        let mut verified = self.verify_signed(peer_id, pk, aad, &plaintext);
+        // signed before the peer rotated its key
+        if let (Err(SecretError::BadSignature(_)), Some(retired)) =
+            (&verified, self.retired_key(&peer_id))
+        {
+            verified = self.verify_signed(peer_id, retired, aad, &plaintext);
+        }
+        plaintext.zeroize();
+        verified
+    }
+    fn verify_signed(
+        &self,
+        peer_id: PeerId,
+        pk: &sig::PublicKey,
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, SecretError> {
+        let (signature_len, rest) = plaintext
+            .split_first_chunk::<4>()
+            .ok_or(SecretError::BadSignature(peer_id))?;
+        let (signature, message) = rest
+            .split_at_checked(u32::from_be_bytes(*signature_len) as usize)
+            .ok_or(SecretError::BadSignature(peer_id))?;
+        let key = VerifyCache::key(&peer_id, aad, pk.as_ref(), signature, message);
+        let cached = self.verify_cache.borrow_mut().get(&key);
+        let valid = match cached {
+            Some(valid) => valid,
+            None => {
+                let valid = self
+                    .sig
+                    .signature_from_bytes(signature)
+                    .is_some_and(|signature| self.sig.verify(message, signature, pk).is_ok());
+                self.verify_cache.borrow_mut().insert(key, valid);
+                valid
+            }
+        };
+        if !valid {
+            return Err(SecretError::BadSignature(peer_id));
+        }
+        Ok(message.to_vec())
+    }
+    // Forgets every cached signature verification, the next delivery of a signed message is verified again
+    pub fn clear_verify_cache(&self) {
+        self.verify_cache.borrow_mut().clear();
+    }
+    pub fn verify_cache_len(&self) -> usize {
+        self.verify_cache.borrow().len()
+    }
 
-        Ok((nonce_bytes, ciphertext))
+    // Generates a fresh symmetric key for `room`, replacing the previous one if any. The member calling this is the
+    // one distributing it to the others over their pairwise channels, calling it again rekeys the room.
+    pub fn derive_group_key(&mut self, room: &str) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        self.group_keys.insert(room.to_string(), key);
+        key
     }
-    pub fn decrypt(
+    pub fn has_group_key(&self, room: &str) -> bool {
+        self.group_keys.contains_key(room)
+    }
+    // Encrypts our key of `room` for `peer_id`, to be sent as a GroupKeyShare
+    pub fn share_group_key(
         &self,
         peer_id: PeerId,
+        aad: &[u8],
+        room: &str,
+    ) -> Result<([u8; 12], Vec<u8>), SecretError> {
+        let Some(key) = self.group_keys.get(room) else {
+            return Err(SecretError::NoGroupKey(room.to_string()));
+        };
+        self.encrypt(peer_id, aad, key)
+    }
+    pub fn receive_group_key(
+        &mut self,
+        peer_id: PeerId,
+        aad: &[u8],
+        room: &str,
         nonce: [u8; 12],
         ciphertext: Vec<u8>,
-    ) -> Result<Vec<u8>, oqs::Error> {
-        let kem_ss = self.shared_secret.get(&peer_id);
-        let Some(kem_ss) = kem_ss else {
-            // We don't have a shared secret with this peer, most likely someone trying to find a bug
-            return Err(oqs::Error::Error);
+    ) -> Result<(), SecretError> {
+        let mut key = self.decrypt(peer_id, aad, nonce, ciphertext)?;
+        let group_key: Result<[u8; 32], _> = key.as_slice().try_into();
+        key.zeroize();
+        self.group_keys.insert(
+            room.to_string(),
+            group_key.map_err(|_| SecretError::MalformedGroupKey)?,
+        );
+        Ok(())
+    }
+    pub fn encrypt_group(
+        &self,
+        room: &str,
+        aad: &[u8],
+        message: &[u8],
+    ) -> Result<([u8; 12], Vec<u8>), SecretError> {
+        let Some(key) = self.group_keys.get(room) else {
+            return Err(SecretError::NoGroupKey(room.to_string()));
         };
+        Ok(seal(key, aad, message)?)
+    }
+    pub fn decrypt_group(
+        &self,
+        room: &str,
+        aad: &[u8],
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    ) -> Result<Vec<u8>, SecretError> {
+        let Some(key) = self.group_keys.get(room) else {
+            return Err(SecretError::NoGroupKey(room.to_string()));
+        };
+        open(key, aad, nonce, ciphertext)
+    }
+}
 
-        let key = Key::<Aes256Gcm>::from_slice(&kem_ss.as_ref());
-        let cipher = Aes256Gcm::new(key);
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // overwrite the key material so it doesn't linger around in freed memory
+        for (_, shared_secret) in self
+            .shared_secret
+            .drain()
+            .chain(self.superseded.get_mut().drain())
+        {
+            shared_secret.into_vec().zeroize();
+        }
+        for (_, kem_sk) in self.shared_secret_unresponded_requests.drain() {
+            kem_sk.into_vec().zeroize();
+        }
+        for key in self.group_keys.values_mut() {
+            key.zeroize();
+        }
+        let blank = vec![0u8; self.private_key.len()];
+        if let Some(blank) = self.sig.secret_key_from_bytes(&blank) {
+            std::mem::replace(&mut self.private_key, blank.to_owned())
+                .into_vec()
+                .zeroize();
+        }
+    }
+}
 
-        let nonce = Nonce::from_slice(&nonce);
+// Writes a file only we can read, for key material
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)
+}
 
-        let plaintext = cipher
-            .decrypt(nonce, ciphertext.as_ref())
-            .map_err(|_| oqs::Error::Error)?;
+fn seal(key: &[u8], aad: &[u8], message: &[u8]) -> Result<([u8; 12], Vec<u8>), oqs::Error> {
+    let key = Key::<Aes256Gcm>::from_slice(key);
+    let cipher = Aes256Gcm::new(key);
 
-        Ok(plaintext)
+    let mut nonce_bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    let ciphertext = cipher
+        .encrypt(nonce, Payload { msg: message, aad })
+        .map_err(|_| oqs::Error::Error)?;
+
+    Ok((nonce_bytes, ciphertext))
+}
+fn open(
+    key: &[u8],
+    aad: &[u8],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+) -> Result<Vec<u8>, SecretError> {
+    if ciphertext.len() < TAG_LEN {
+        return Err(SecretError::MalformedCiphertext);
     }
+    let key = Key::<Aes256Gcm>::from_slice(key);
+    let cipher = Aes256Gcm::new(key);
+
+    let nonce = Nonce::from_slice(&nonce);
+
+    let plaintext = cipher
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext.as_ref(),
+                aad,
+            },
+        )
+        .map_err(|_| SecretError::AuthenticationFailed)?;
+
+    Ok(plaintext)
+}
+
+fn rotation_message(new_pk: &sig::PublicKey) -> Vec<u8> {
+    [KEY_ROTATION_CONTEXT, new_pk.as_ref()].concat()
+}
+fn membership_message(room: &str, timestamp: u64) -> Vec<u8> {
+    [
+        MEMBERSHIP_CONTEXT,
+        &timestamp.to_be_bytes(),
+        room.as_bytes(),
+    ]
+    .concat()
 }
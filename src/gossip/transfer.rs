@@ -0,0 +1,150 @@
+use libp2p::PeerId;
+use serde::Serialize;
+use std::{
+    error::Error,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+// JSON encodes every byte as up to 4 characters, this keeps a chunk well below gossipsub's max transmit size
+pub static FILE_CHUNK_SIZE: usize = 8 * 1024;
+// biggest file we send or receive, the chunk count comes off the wire and everything for it is allocated up front
+pub static MAX_FILE_SIZE: usize = 64 * 1024 * 1024;
+pub static MAX_FILE_CHUNKS: u32 = MAX_FILE_SIZE.div_ceil(FILE_CHUNK_SIZE) as u32;
+// incomplete transfers that didn't receive a chunk for this long are dropped
+pub static FILE_TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+// incomplete transfers a peer can have at once, each of them lives for FILE_TRANSFER_TIMEOUT before being dropped
+pub static MAX_TRANSFERS_PER_PEER: usize = 4;
+// bytes of incomplete transfers we hold on to, across all peers
+pub static MAX_BUFFERED_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum TransferError {
+    // more chunks than a file of MAX_FILE_SIZE takes
+    TooLarge { total: u32, max: u32 },
+    // a chunk that disagrees with the first one of its transfer on how many chunks there are
+    TotalMismatch { total: u32, expected: u32 },
+    // the peer already has MAX_TRANSFERS_PER_PEER transfers going
+    TooManyTransfers { max: usize },
+    // the chunk would take the incomplete transfers past MAX_BUFFERED_BYTES
+    BufferFull { max: usize },
+}
+impl Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::TooLarge { total, max } => {
+                write!(f, "File of {} chunks, at most {} are allowed", total, max)
+            }
+            TransferError::TotalMismatch { total, expected } => write!(
+                f,
+                "Chunk of a file of {} chunks, expected {}",
+                total, expected
+            ),
+            TransferError::TooManyTransfers { max } => {
+                write!(f, "Peer already has {} transfers going", max)
+            }
+            TransferError::BufferFull { max } => {
+                write!(f, "Incomplete transfers would exceed {} bytes", max)
+            }
+        }
+    }
+}
+impl Error for TransferError {}
+
+pub struct IncomingFile {
+    pub peer: PeerId,
+    pub total: u32,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    // bytes of the chunks received so far
+    buffered: usize,
+    pub started: Instant,
+    pub last_update: Instant,
+}
+
+// Where an incoming transfer stands, counted in chunks
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferProgress {
+    pub peer: PeerId,
+    pub id: u64,
+    pub received: u32,
+    pub total: u32,
+    #[serde(skip)]
+    pub started: Instant,
+}
+
+impl IncomingFile {
+    // Fails for more than MAX_FILE_CHUNKS chunks, before anything is allocated for them
+    pub fn new(peer: PeerId, total: u32, now: Instant) -> Result<Self, TransferError> {
+        if total > MAX_FILE_CHUNKS {
+            return Err(TransferError::TooLarge {
+                total,
+                max: MAX_FILE_CHUNKS,
+            });
+        }
+        Ok(Self {
+            peer,
+            total,
+            chunks: vec![None; total as usize],
+            received: 0,
+            buffered: 0,
+            started: now,
+            last_update: now,
+        })
+    }
+    // Chunks can arrive in any order, duplicates and out of range chunks are ignored. `total` is the chunk count the
+    // chunk came with, it has to match the one the transfer started with.
+    pub fn insert(
+        &mut self,
+        seq: u32,
+        total: u32,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Result<(), TransferError> {
+        if total != self.total {
+            return Err(TransferError::TotalMismatch {
+                total,
+                expected: self.total,
+            });
+        }
+        self.last_update = now;
+        let Some(chunk) = self.chunks.get_mut(seq as usize) else {
+            return Ok(());
+        };
+        if chunk.is_none() {
+            self.buffered += data.len();
+            *chunk = Some(data);
+            self.received += 1;
+        }
+        Ok(())
+    }
+    pub fn progress(&self, id: u64) -> TransferProgress {
+        TransferProgress {
+            peer: self.peer,
+            id,
+            received: self.received,
+            total: self.total,
+            started: self.started,
+        }
+    }
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+    pub fn is_complete(&self) -> bool {
+        self.received == self.total
+    }
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.last_update) > FILE_TRANSFER_TIMEOUT
+    }
+    pub fn assemble(self) -> Vec<u8> {
+        self.chunks.into_iter().flatten().flatten().collect()
+    }
+}
+
+// Binds a chunk's ciphertext to its sender, transfer and position so chunks can't be swapped around
+pub fn chunk_aad(sender: &PeerId, id: u64, seq: u32) -> Vec<u8> {
+    let mut aad = sender.to_bytes();
+    aad.extend_from_slice(&id.to_be_bytes());
+    aad.extend_from_slice(&seq.to_be_bytes());
+    aad
+}
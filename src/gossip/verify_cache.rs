@@ -0,0 +1,79 @@
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+
+pub static DEFAULT_VERIFY_CACHE_CAPACITY: usize = 1024;
+
+// Results of signature verifications, so a payload delivered again isn't verified again.
+// Entries are keyed by a hash of everything the result depends on, see `key`, the least recently used one is evicted
+// once `capacity` is reached.
+pub struct VerifyCache {
+    capacity: usize,
+    entries: HashMap<[u8; 32], (bool, u64)>,
+    // last use of every entry, oldest first
+    order: BTreeMap<u64, [u8; 32]>,
+    tick: u64,
+}
+impl VerifyCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+    // Every input is length prefixed, so no two different inputs hash the same bytes
+    pub fn key(
+        peer_id: &PeerId,
+        aad: &[u8],
+        pk: &[u8],
+        signature: &[u8],
+        message: &[u8],
+    ) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for part in [&peer_id.to_bytes()[..], aad, pk, signature, message] {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+    pub fn get(&mut self, key: &[u8; 32]) -> Option<bool> {
+        let tick = self.next_tick();
+        let (valid, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, *key);
+        *last_used = tick;
+        Some(*valid)
+    }
+    pub fn insert(&mut self, key: [u8; 32], valid: bool) {
+        if self.capacity == 0 {
+            return;
+        }
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(key, (valid, tick)) {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(tick, key);
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
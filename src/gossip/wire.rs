@@ -0,0 +1,90 @@
+use crate::communication::InteractionMessage;
+
+use super::{GossipSendError, compress::CompressError, nonce::NonceError};
+
+// Wire format tags, sent ahead of the nonce so a receiver knows how to deserialize whatever the sender chose
+static FORMAT_JSON: u8 = 0;
+static FORMAT_CBOR: u8 = 1;
+
+// How messages are serialized before they're compressed and sent. CBOR carries the byte strings of post-quantum keys
+// and ciphertexts far more compactly than JSON, JSON stays the default so older peers can still read us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Cbor,
+}
+
+#[derive(Debug)]
+pub enum WireError {
+    // the data was empty or started with an unknown format tag
+    UnknownFormat(Option<u8>),
+    Cbor(ciborium::de::Error<std::io::Error>),
+}
+
+// Why `Gossip::decode_message` couldn't take the bytes apart, by layer
+#[derive(Debug)]
+pub enum DecodeError {
+    Wire(WireError),
+    Nonce(NonceError),
+    Compress(CompressError),
+    Json(serde_json::Error),
+}
+impl From<WireError> for DecodeError {
+    fn from(err: WireError) -> Self {
+        DecodeError::Wire(err)
+    }
+}
+impl From<NonceError> for DecodeError {
+    fn from(err: NonceError) -> Self {
+        DecodeError::Nonce(err)
+    }
+}
+impl From<CompressError> for DecodeError {
+    fn from(err: CompressError) -> Self {
+        DecodeError::Compress(err)
+    }
+}
+impl From<serde_json::Error> for DecodeError {
+    fn from(err: serde_json::Error) -> Self {
+        DecodeError::Json(err)
+    }
+}
+
+impl WireFormat {
+    fn tag(self) -> u8 {
+        match self {
+            WireFormat::Json => FORMAT_JSON,
+            WireFormat::Cbor => FORMAT_CBOR,
+        }
+    }
+    pub fn serialize(self, message: &InteractionMessage) -> Result<Vec<u8>, GossipSendError> {
+        match self {
+            WireFormat::Json => Ok(serde_json::to_vec(message)?),
+            WireFormat::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(message, &mut data)?;
+                Ok(data)
+            }
+        }
+    }
+    // Only for CBOR, JSON payloads are parsed by `InteractionMessage::from_msg` which also takes plain text
+    pub fn deserialize_cbor(data: &[u8]) -> Result<InteractionMessage, WireError> {
+        ciborium::from_reader(data).map_err(WireError::Cbor)
+    }
+}
+
+// Puts the tag of `format` in front of `data`
+pub fn tag(format: WireFormat, data: Vec<u8>) -> Vec<u8> {
+    [&[format.tag()], &data[..]].concat()
+}
+
+// Splits the format tag off `data`
+pub fn untag(data: &[u8]) -> Result<(WireFormat, &[u8]), WireError> {
+    match data.split_first() {
+        Some((&tag, rest)) if tag == FORMAT_JSON => Ok((WireFormat::Json, rest)),
+        Some((&tag, rest)) if tag == FORMAT_CBOR => Ok((WireFormat::Cbor, rest)),
+        Some((&tag, _)) => Err(WireError::UnknownFormat(Some(tag))),
+        None => Err(WireError::UnknownFormat(None)),
+    }
+}
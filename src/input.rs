@@ -0,0 +1,79 @@
+use tokio::sync::mpsc;
+
+// lines queued between the reader and the event loop
+static INPUT_BUFFER: usize = 16;
+#[cfg(feature = "line-editor")]
+static HISTORY_PATH: &str = "history.txt";
+
+// Lines typed by the user, read off the event loop so swarm events keep being handled while they type.
+// With the `line-editor` feature they go through rustyline, with editing and a history kept in HISTORY_PATH,
+// otherwise they're read from stdin as is.
+pub struct Input {
+    lines: mpsc::Receiver<String>,
+}
+impl Input {
+    pub fn spawn() -> Self {
+        let (sender, lines) = mpsc::channel(INPUT_BUFFER);
+        read_lines(sender);
+        Self { lines }
+    }
+    // None once stdin is closed
+    pub async fn next_line(&mut self) -> Option<String> {
+        self.lines.recv().await
+    }
+}
+
+#[cfg(not(feature = "line-editor"))]
+fn read_lines(sender: mpsc::Sender<String>) {
+    use tokio::io::{self, AsyncBufReadExt};
+
+    tokio::spawn(async move {
+        let mut stdin = io::BufReader::new(io::stdin()).lines();
+        while let Ok(Some(line)) = stdin.next_line().await {
+            if sender.send(line).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// rustyline blocks, so it gets a thread of its own
+#[cfg(feature = "line-editor")]
+fn read_lines(sender: mpsc::Sender<String>) {
+    use rustyline::{DefaultEditor, error::ReadlineError};
+
+    std::thread::spawn(move || {
+        let mut editor = match DefaultEditor::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                tracing::warn!("Error starting the line editor: {}", e);
+                return;
+            }
+        };
+        // there is no history file on the first run
+        let _ = editor.load_history(HISTORY_PATH);
+        loop {
+            let line = match editor.readline("> ") {
+                Ok(line) => line,
+                // the terminal is in raw mode, ctrl-c doesn't reach the event loop as a signal
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                    let _ = sender.blocking_send("quit".to_string());
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("Error reading input: {}", e);
+                    break;
+                }
+            };
+            if !line.trim().is_empty() {
+                let _ = editor.add_history_entry(line.as_str());
+                if let Err(e) = editor.save_history(HISTORY_PATH) {
+                    tracing::debug!("Error saving history: {}", e);
+                }
+            }
+            if sender.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+}
@@ -1,156 +1,670 @@
+mod command;
+mod input;
+
 use futures::stream::StreamExt;
-use libp2p::swarm::SwarmEvent;
-use std::error::Error;
-use tokio::{io, io::AsyncBufReadExt, select};
+use libp2p::{PeerId, swarm::SwarmEvent};
+use std::{
+    error::Error,
+    path::Path,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tokio::select;
+use tracing_subscriber::EnvFilter;
+use zeroize::Zeroize;
 
+use command::Command;
+use input::Input;
 use stockchain::{
-    communication::{InteractionMessage, SharedSecretExchange, SharedSecretExchangeResponse},
-    gossip::{Gossip, GossipEvent, MyBehaviourEvent, room::GossipRooms},
+    communication::InteractionMessage,
+    gossip::{
+        GenerateRoomName, Gossip, GossipEvent, MyBehaviourEvent,
+        room::GossipRooms,
+        secret::{Secret, SecretError},
+    },
 };
 
+static BLOCKLIST_PATH: &str = "blocklist.txt";
+static NICKNAMES_PATH: &str = "nicknames.txt";
+// set by `--json` or STOCKCHAIN_JSON=1, every line on stdout is then a JSON object
+static JSON_OUTPUT: OnceLock<bool> = OnceLock::new();
+
+fn json_output() -> bool {
+    *JSON_OUTPUT.get().unwrap_or(&false)
+}
+
+// println! for the human, `{"output": ...}` for a program reading our stdout
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if json_output() {
+            println!("{}", serde_json::json!({ "output": format!($($arg)*) }));
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let json = std::env::args().any(|arg| arg == "--json")
+        || std::env::var("STOCKCHAIN_JSON").is_ok_and(|value| value == "1");
+    JSON_OUTPUT.get_or_init(|| json);
+    // in JSON mode logs go to stderr so they don't end up between the JSON lines
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(move || -> Box<dyn std::io::Write> {
+            if json {
+                Box::new(std::io::stderr())
+            } else {
+                Box::new(std::io::stdout())
+            }
+        })
+        .try_init();
+
     let mut gossip = Gossip::new()?;
-    gossip.join_room("public_test")?;
+    gossip.load_blocklist(Path::new(BLOCKLIST_PATH))?;
+    gossip.load_nicknames(Path::new(NICKNAMES_PATH))?;
+    gossip.join_public_room("test")?;
+    gossip.join_public_directory()?;
     gossip.open_ears()?;
 
     // Read full lines from stdin
-    let rooms: Vec<String> = gossip.topics.iter().map(|x| x.0.clone()).collect();
-    println!("Self Id: {:?}\nRooms joined: {:?}", gossip.peer_id(), rooms);
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    say!(
+        "Self Id: {:?}\nRooms joined: {:?}",
+        gossip.peer_id(),
+        gossip.list_rooms()
+    );
+    let mut input = Input::spawn();
+    let mut heartbeat = tokio::time::interval(gossip.heartbeat_interval);
 
     // Kick it off
     loop {
+        let retry_at = gossip.next_listener_retry();
+        let handshake_retry_at = gossip.next_handshake_retry();
+        let ping_sweep_at = gossip.next_ping_sweep_deadline();
         select! {
-            Ok(Some(line)) = stdin.next_line() => {
-                let data = parse_command(&mut gossip, line.as_str());
+            Some(line) = input.next_line() => {
+                if let "quit" | "q" = line.trim() {
+                    break;
+                }
+                let data = run_command(&mut gossip, line.as_str());
                 let Some(data) = data else {
                     continue;
                 };
                 let room = gossip.get_topic_from_name(&data.1);
                 let Some(room) = room else {
-                    println!("Invalid room given");
+                    say!("Invalid room given");
                     continue;
                 };
-                if let Err(e) = gossip.gossip(&data.0, room) {
-                    println!("Publish error: {e:?}");
+                match gossip.gossip_when_ready(&data.0, room, Duration::from_secs(5)).await {
+                    Ok(outcome) if outcome.mesh_peer_count == 0 => {
+                        say!("Warning: nobody in {} received the message", data.1);
+                    }
+                    Ok(_) => {}
+                    Err(e) => say!("Publish error: {e}"),
+                }
+                for action in gossip.take_deferred_events() {
+                    handle_action(&mut gossip, action);
                 }
             }
             event = gossip.swarm.select_next_some() => handle_event(&mut gossip, event),
+            _ = heartbeat.tick() => {
+                for action in gossip.heartbeat() {
+                    handle_action(&mut gossip, action);
+                }
+            }
+            _ = tokio::time::sleep_until(retry_at.unwrap_or_else(Instant::now).into()), if retry_at.is_some() => {
+                if let Some(action) = gossip.retry_listeners() {
+                    handle_action(&mut gossip, action);
+                }
+            }
+            _ = tokio::time::sleep_until(handshake_retry_at.unwrap_or_else(Instant::now).into()), if handshake_retry_at.is_some() => {
+                for action in gossip.retry_handshakes() {
+                    handle_action(&mut gossip, action);
+                }
+            }
+            _ = tokio::time::sleep_until(ping_sweep_at.unwrap_or_else(Instant::now).into()), if ping_sweep_at.is_some() => {
+                if let Some(action) = gossip.finish_ping_sweep() {
+                    handle_action(&mut gossip, action);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
         }
     }
+    say!("Shutting down");
+    gossip.shutdown().await;
+    Ok(())
 }
 
 fn handle_event(gossip: &mut Gossip, event: SwarmEvent<MyBehaviourEvent>) {
-    let Some(action) = gossip.handle_event(event) else {
-        return;
-    };
+    if let Some(action) = gossip.handle_event(event) {
+        handle_action(gossip, action);
+    }
+    for action in gossip.take_deferred_events() {
+        handle_action(gossip, action);
+    }
+}
+
+fn handle_action(gossip: &mut Gossip, action: GossipEvent) {
+    if json_output() {
+        match serde_json::to_string(&action) {
+            Ok(event) => println!("{{\"event\":{event}}}"),
+            Err(e) => say!("Error serializing event: {e}"),
+        }
+    }
     let GossipEvent::Message((data, message)) = action else {
-        println!("Event: {action:?}");
+        if let GossipEvent::Listening(address) = action {
+            say!("Local node is listening on {address}");
+            return;
+        }
+        if let GossipEvent::SessionEstablished { .. } = action {
+            say!("{action}, encrypted messages can be sent now");
+            return;
+        }
+        if let GossipEvent::SessionExpired(peer) = action {
+            say!(
+                "{action}, run sse on {} if it doesn't come back",
+                peer.generate_room_name()
+            );
+            return;
+        }
+        if let GossipEvent::Subscribed { .. }
+        | GossipEvent::Unsubscribed { .. }
+        | GossipEvent::ConnectionLimitReached { .. }
+        | GossipEvent::PeerFound { .. }
+        | GossipEvent::PeerNotFound(_)
+        | GossipEvent::RelayReserved(_)
+        | GossipEvent::DirectConnectionUpgraded(_) = action
+        {
+            say!("{action}");
+            return;
+        }
+        if let GossipEvent::CatchUp { messages, .. } = &action {
+            say!("{action}:");
+            for message in messages {
+                say!("{}: {}", message.peer, message.message);
+            }
+            return;
+        }
+        if let GossipEvent::PingSweep(round_trips) = &action {
+            say!("{action}:");
+            for (peer, rtt) in round_trips {
+                let name = gossip
+                    .nickname(peer)
+                    .map_or_else(|| peer.to_string(), str::to_string);
+                match rtt {
+                    Some(rtt) => say!("  {name}  {rtt:?}"),
+                    None => say!("  {name}  no response"),
+                }
+            }
+            return;
+        }
+        if let GossipEvent::FileCompleted { peer, id, data } = action {
+            let path = format!("received_{id}");
+            match std::fs::write(&path, &data) {
+                Ok(()) => say!("File from {peer} saved to {path} ({} bytes)", data.len()),
+                Err(e) => say!("Error saving file from {peer}: {e:?}"),
+            }
+            return;
+        }
+        say!("Event: {action:?}");
         return;
     };
     match message {
-        InteractionMessage::Ping => println!("Ping received"),
-        InteractionMessage::SharedSecretExchange(shared_secret_exchange) => {
-            println!("Shared secret exchange");
-            let Ok(response) = gossip.secret.receive_shared_secret(
+        InteractionMessage::Ping(nonce) => {
+            say!("Ping received");
+            data.reply_to_peer(gossip, &InteractionMessage::Pong(nonce));
+        }
+        InteractionMessage::Pong(_) => match gossip.latency(&data.peer) {
+            Some(rtt) => say!("Pong from {}, round trip {:?}", data.peer, rtt),
+            None => say!("Pong from {}", data.peer),
+        },
+        InteractionMessage::SharedSecretCommunication(communication) => {
+            say!("Shared secret communication");
+            let mut decrypted = match gossip.decrypt(
                 data.peer,
-                shared_secret_exchange.kem_pk,
-                shared_secret_exchange.signature,
-                shared_secret_exchange.pk,
-            ) else {
-                println!("Error receiving shared secret");
-                return;
+                &data.peer.to_bytes(),
+                communication.0,
+                communication.1,
+            ) {
+                Ok(decrypted) => decrypted,
+                Err(e) => {
+                    say!("Error decrypting data: {e}");
+                    return;
+                }
             };
-            data.reply_to_peer(
-                gossip,
-                &InteractionMessage::SharedSecretExchangeResponse(
-                    SharedSecretExchangeResponse::new(response.0, response.1, response.2),
-                ),
-            );
+            say!("Decrypted data: {:?}", String::from_utf8_lossy(&decrypted));
+            decrypted.zeroize();
+            data.reply_to_peer(gossip, &InteractionMessage::Ack(data.id.clone()));
+            if let Err(e) = gossip.rekey_if_needed(data.peer) {
+                say!("Error rekeying session: {e:?}");
+            }
         }
-        InteractionMessage::SharedSecretExchangeResponse(response) => {
-            println!("Shared secret exchange response");
-            let Err(e) = gossip.secret.receive_shared_secret_response(
+        InteractionMessage::SignedSharedSecretCommunication(communication) => {
+            say!("Signed shared secret communication");
+            let mut decrypted = match gossip.decrypt_verified(
                 data.peer,
-                response.kem_ct,
-                response.signature,
-                response.pk,
-            ) else {
-                return;
+                &data.peer.to_bytes(),
+                communication.0,
+                communication.1,
+            ) {
+                Ok(decrypted) => decrypted,
+                Err(e) => {
+                    say!("Error decrypting data: {e}");
+                    return;
+                }
             };
-            println!("Error receiving shared secret response {e:?}");
+            say!(
+                "Decrypted data signed by {}: {:?}",
+                data.peer,
+                String::from_utf8_lossy(&decrypted)
+            );
+            decrypted.zeroize();
+            data.reply_to_peer(gossip, &InteractionMessage::Ack(data.id.clone()));
+            if let Err(e) = gossip.rekey_if_needed(data.peer) {
+                say!("Error rekeying session: {e:?}");
+            }
         }
-        InteractionMessage::SharedSecretCommunication(communication) => {
-            println!("Shared secret communication");
-            let Ok(data) = gossip
-                .secret
-                .decrypt(data.peer, communication.0, communication.1)
-            else {
-                println!("Error decrypting data");
-                return;
+        InteractionMessage::Ack(id) => say!("Ack received for {id}"),
+        InteractionMessage::GroupKeyShare(share) => {
+            say!("Group key received for {}", share.room);
+            if let Err(e) = gossip.secret.receive_group_key(
+                data.peer,
+                &data.peer.to_bytes(),
+                &share.room,
+                share.key.0,
+                share.key.1,
+            ) {
+                say!("Error receiving group key: {e:?}");
+            }
+        }
+        InteractionMessage::GroupMessage(message) => {
+            let mut decrypted = match gossip.secret.decrypt_group(
+                data.room.name(),
+                &data.peer.to_bytes(),
+                message.0,
+                message.1,
+            ) {
+                Ok(decrypted) => decrypted,
+                Err(e) => {
+                    say!("Error decrypting group message: {e}");
+                    return;
+                }
             };
-            println!("Decrypted data: {:?}", String::from_utf8(data));
+            say!(
+                "Group message in {}: {:?}",
+                data.room,
+                String::from_utf8_lossy(&decrypted)
+            );
+            decrypted.zeroize();
         }
         InteractionMessage::RequestPublicKey => {
-            println!("Request public key received");
+            say!("Request public key received");
             data.reply_to_room(
                 gossip,
                 &InteractionMessage::ReplyPublicKey(gossip.secret.public_key.clone()),
             );
         }
-        InteractionMessage::ReplyPublicKey(public_key) => {
-            println!("Reply public key received: {:?}", public_key)
+        // handled by gossip, they arrive as GossipEvent::PublicKeyLearned, GossipEvent::SessionEstablished,
+        // GossipEvent::FileProgress / FileCompleted, GossipEvent::PeerKeyRotated, GossipEvent::CatchUp and
+        // GossipEvent::MemberVerified, announced rooms are listed by the directory command
+        InteractionMessage::ReplyPublicKey(_)
+        | InteractionMessage::SharedSecretExchange(_)
+        | InteractionMessage::SharedSecretExchangeResponse(_)
+        | InteractionMessage::FileChunk(_)
+        | InteractionMessage::KeyRotation(_)
+        | InteractionMessage::RoomAnnounce(_)
+        | InteractionMessage::CatchUp(_)
+        | InteractionMessage::MembershipProof(_) => {}
+        InteractionMessage::PublicChat(text) => {
+            say!("{} in {}: {}", data.sender(), data.room, text)
         }
-        InteractionMessage::Other(e) => println!("Other message received: {:?}", e),
+        InteractionMessage::Unknown { raw } => say!("Unexpected message received: {:?}", raw),
     }
 }
 
-fn parse_command(gossip: &mut Gossip, command: &str) -> Option<(InteractionMessage, String)> {
-    let args: Vec<String> = command.split(" ").map(|s| s.to_string()).collect();
-    if args.len() < 2 {
-        println!("<cmd> <room> <info?>");
-        return None;
-    }
-    let cmd = match args[0].as_str() {
-        "ping" | "p" => InteractionMessage::Ping,
-        "join_room" | "jr" => {
-            println!("{:?}", gossip.join_room(&args[1]));
+fn run_command(gossip: &mut Gossip, line: &str) -> Option<(InteractionMessage, String)> {
+    let command = match Command::parse(line) {
+        Ok(command) => command,
+        Err(e) => {
+            say!("{e}");
+            return None;
+        }
+    };
+    let (message, room) = match command {
+        Command::PendingAcks => {
+            for (id, sent) in gossip.pending_acks() {
+                say!("{id}: sent {:?} ago", sent.elapsed());
+            }
+            return None;
+        }
+        Command::PublicKey => {
+            say!("{}", gossip.secret.public_key_encoded());
+            return None;
+        }
+        Command::Keys => {
+            say!(
+                "{} (us): {}",
+                gossip.peer_id(),
+                Secret::fingerprint(&gossip.secret.public_key)
+            );
+            for (peer_id, pk) in gossip.secret.pinned_keys() {
+                say!("{peer_id}: {}", Secret::fingerprint(pk));
+            }
+            return None;
+        }
+        Command::Addrs => {
+            for addr in gossip.dial_addrs() {
+                say!("{addr}");
+            }
+            return None;
+        }
+        Command::Dial { addr } => {
+            if let Err(e) = gossip.dial(addr) {
+                say!("Error dialing: {e}");
+            }
+            return None;
+        }
+        Command::Find { peer } => {
+            gossip.find_peer(peer);
+            say!("Looking for {peer}");
+            return None;
+        }
+        Command::Relay { addr } => {
+            if let Err(e) = gossip.use_relay(addr) {
+                say!("Error using relay: {e}");
+            }
+            return None;
+        }
+        Command::Info => {
+            say!("{}", gossip.describe());
+            return None;
+        }
+        Command::RotateKey => {
+            match gossip.rotate_signing_key() {
+                Ok(results) => {
+                    say!("New public key: {}", gossip.secret.public_key_encoded());
+                    for (room, result) in results {
+                        if let Err(e) = result {
+                            say!("Announcing the new key in {room} failed: {e:?}");
+                        }
+                    }
+                }
+                Err(e) => say!("Error rotating key: {e:?}"),
+            }
+            return None;
+        }
+        Command::Sessions => {
+            for peer_id in gossip.secret.sessions() {
+                say!("{peer_id}");
+            }
+            return None;
+        }
+        Command::Peers => {
+            for (peer_id, info) in gossip.peers() {
+                let status = if info.online { "online" } else { "offline" };
+                say!(
+                    "{peer_id}: {status}, last seen {:?} ago",
+                    info.last_seen.elapsed()
+                );
+            }
+            return None;
+        }
+        Command::Transfers => {
+            for transfer in gossip.transfers() {
+                say!(
+                    "{} from {}: {}/{} chunks, started {:?} ago",
+                    transfer.id,
+                    transfer.peer,
+                    transfer.received,
+                    transfer.total,
+                    transfer.started.elapsed()
+                );
+            }
+            return None;
+        }
+        Command::Directory => {
+            for (room, peers) in gossip.known_public_rooms() {
+                say!("{room}: {} peers", peers.len());
+            }
+            return None;
+        }
+        Command::Rejoin => {
+            let rooms = gossip.leave_all_rooms();
+            say!("{:?}", gossip.rejoin_all(&rooms));
+            return None;
+        }
+        Command::Metrics => {
+            let metrics = gossip.metrics();
+            if json_output() {
+                match serde_json::to_string(&metrics) {
+                    Ok(metrics) => println!("{{\"metrics\":{metrics}}}"),
+                    Err(e) => say!("Error serializing metrics: {e}"),
+                }
+            } else {
+                say!("{metrics:#?}");
+            }
+            return None;
+        }
+        Command::Ping { room } => (gossip.ping_message(), room),
+        Command::PingAll => {
+            match gossip.ping_all() {
+                0 => say!("No peers to ping"),
+                pinged => say!("Pinged {pinged} peers"),
+            }
+            return None;
+        }
+        Command::JoinRoom { room } => {
+            say!("{:?}", gossip.join_public_room(&room));
+            return None;
+        }
+        Command::JoinRoomWithPassword { room, passphrase } => {
+            say!("{:?}", gossip.join_room_with_password(&room, &passphrase));
+            return None;
+        }
+        Command::LeaveRoom { room } => {
+            say!("{:?}", gossip.leave_room(&room));
+            return None;
+        }
+        Command::SubscribedPeers { room } => {
+            match gossip.subscribed_peers(&room) {
+                Some(peers) => say!("{peers:?}"),
+                None => say!("Not in room {}", room),
+            }
+            return None;
+        }
+        Command::Block { peer } => {
+            let peer_id = resolve_peer(gossip, &peer)?;
+            gossip.block_peer(peer_id);
+            save_blocklist(gossip);
+            return None;
+        }
+        Command::Unblock { peer } => {
+            let peer_id = resolve_peer(gossip, &peer)?;
+            gossip.unblock_peer(&peer_id);
+            save_blocklist(gossip);
+            return None;
+        }
+        Command::Nick { room, name } => {
+            let peer_id = resolve_peer(gossip, &room)?;
+            match name {
+                Some(name) => match gossip.set_nickname(peer_id, &name) {
+                    Ok(()) => say!("{peer_id} is now {name}"),
+                    Err(e) => say!("Error setting nickname: {e}"),
+                },
+                None => {
+                    gossip.clear_nickname(&peer_id);
+                    say!("Cleared the nickname of {peer_id}");
+                }
+            }
+            if let Err(e) = gossip.save_nicknames(Path::new(NICKNAMES_PATH)) {
+                say!("Error saving nicknames: {e:?}");
+            }
+            return None;
+        }
+        Command::Forget { room } => {
+            let peer_id = peer_of_room(gossip, &room)?;
+            if !gossip.secret.drop_session(&peer_id) {
+                say!("No session with {peer_id}");
+            }
+            return None;
+        }
+        Command::PinKey { room, key } => {
+            let peer_id = peer_of_room(gossip, &room)?;
+            match gossip.secret.pin_key_from_encoded(peer_id, &key) {
+                Ok(()) => say!("Pinned key of {peer_id}"),
+                Err(e) => say!("Error pinning key: {e}"),
+            }
+            return None;
+        }
+        Command::History { room } => {
+            for message in gossip.history(&room, 20) {
+                say!("{}: {}", message.sender(), message.message);
+            }
+            return None;
+        }
+        Command::ProveMembership { room } => {
+            say!("{:?}", gossip.prove_membership(&room));
+            return None;
+        }
+        Command::Members { room } => {
+            for peer_id in gossip.room_members(&room) {
+                say!("{peer_id}");
+            }
+            return None;
+        }
+        Command::CatchUp { room, limit } => {
+            gossip.set_catch_up(&room, limit);
             return None;
         }
-        "request_public_key" | "rpk" => InteractionMessage::RequestPublicKey,
-        "shared_secret_exchange" | "sse" => {
-            let Some(peer_id) = gossip.get_peer_from_room_name(&args[1]) else {
-                println!("Invalid peer id");
-                return None;
+        Command::RequestPublicKey { room } => (InteractionMessage::RequestPublicKey, room),
+        Command::SharedSecretExchange { room } => {
+            let peer_id = peer_of_room(gossip, &room)?;
+            match gossip.start_handshake(peer_id) {
+                Ok(outcome) if outcome.mesh_peer_count == 0 => {
+                    say!("Warning: nobody in {room} received the message");
+                }
+                Ok(_) => {}
+                Err(e) => say!("Error sending shared secret: {e:?}"),
+            }
+            return None;
+        }
+        Command::SharedSecretCommunication {
+            room,
+            mut message,
+            signed,
+        } => {
+            let peer_id = peer_of_room(gossip, &room)?;
+            let aad = gossip.peer_id().to_bytes();
+            let data = if signed {
+                gossip
+                    .secret
+                    .encrypt_signed(peer_id, &aad, message.as_bytes())
+            } else {
+                gossip.secret.encrypt(peer_id, &aad, message.as_bytes())
             };
-            let Ok((kem_pk, signature, pk)) = gossip.secret.send_shared_secret(*peer_id) else {
-                println!("Error sending shared secret");
-                return None;
+            message.zeroize();
+            let data = match data {
+                Ok(data) => data,
+                Err(SecretError::NoSession(_)) => {
+                    say!("No shared secret with {peer_id} yet, run `sse {room}` first");
+                    return None;
+                }
+                Err(e) => {
+                    say!("Error encrypting data: {e}");
+                    return None;
+                }
             };
-            InteractionMessage::SharedSecretExchange(SharedSecretExchange::new(
-                kem_pk, signature, pk,
-            ))
-        }
-        "shared_secret_communication" | "ssc" => {
-            let Some(peer_id) = gossip.get_peer_from_room_name(&args[1]) else {
-                println!("Invalid peer id");
-                return None;
+            let message = if signed {
+                InteractionMessage::SignedSharedSecretCommunication(data)
+            } else {
+                InteractionMessage::SharedSecretCommunication(data)
             };
-            let Ok(data) = gossip.secret.encrypt(*peer_id, get_msg(&args).as_bytes()) else {
-                println!("Error encrypting data");
-                return None;
+            // published before the rekey, so the peer still has the secret the message was sealed with
+            match gossip.send_to_peer(peer_id, &message) {
+                Ok(outcome) if outcome.mesh_peer_count == 0 => {
+                    say!("Warning: nobody in {room} received the message");
+                }
+                Ok(_) => {}
+                Err(e) => say!("Publish error: {e:?}"),
+            }
+            if let Err(e) = gossip.rekey_if_needed(peer_id) {
+                say!("Error rekeying session: {e:?}");
+            }
+            return None;
+        }
+        Command::SendFile { room, path } => {
+            let peer_id = peer_of_room(gossip, &room)?;
+            match gossip.send_file(peer_id, Path::new(&path)) {
+                Ok(id) => say!("Sending file {id}"),
+                Err(e) => say!("Error sending file: {e:?}"),
+            }
+            return None;
+        }
+        Command::GroupKey { room } => {
+            gossip.secret.derive_group_key(&room);
+            say!("Generated a new key for {}", room);
+            return None;
+        }
+        Command::ShareGroupKey { room, peer_room } => {
+            let peer_id = peer_of_room(gossip, &peer_room)?;
+            if let Err(e) = gossip.share_group_key(&room, peer_id) {
+                say!("Error sharing group key: {e:?}");
+            }
+            return None;
+        }
+        Command::GroupMessage { room, mut message } => {
+            let data = gossip.secret.encrypt_group(
+                &room,
+                &gossip.peer_id().to_bytes(),
+                message.as_bytes(),
+            );
+            message.zeroize();
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    say!("Error encrypting group message: {e}");
+                    return None;
+                }
             };
-            InteractionMessage::SharedSecretCommunication(data)
+            (InteractionMessage::GroupMessage(data), room)
+        }
+        Command::Chat { room, message } => (InteractionMessage::PublicChat(message), room),
+        Command::Broadcast { message } => {
+            let results = gossip.broadcast_public(&InteractionMessage::PublicChat(message));
+            for (room, result) in results {
+                match result {
+                    Ok(outcome) => say!("Published to {room} ({} peers)", outcome.mesh_peer_count),
+                    Err(e) => say!("Publish to {room} failed: {e:?}"),
+                }
+            }
+            return None;
         }
-        _ => InteractionMessage::Other("fuck".to_string()),
     };
-    Some((cmd, args[1].clone()))
+    Some((message, room))
+}
+
+// The peer a DM room belongs to, printing why if there is none
+fn peer_of_room(gossip: &Gossip, room: &str) -> Option<PeerId> {
+    match gossip.get_peer_from_room_name(room) {
+        Ok(peer_id) => Some(*peer_id),
+        Err(e) => {
+            say!("Invalid peer id: {e:?}");
+            None
+        }
+    }
+}
+
+// Either a peer id or a DM room name
+fn resolve_peer(gossip: &Gossip, peer: &str) -> Option<PeerId> {
+    match peer.parse() {
+        Ok(peer_id) => Some(peer_id),
+        Err(_) => peer_of_room(gossip, peer),
+    }
 }
 
-fn get_msg(args: &Vec<String>) -> String {
-    if args.len() < 3 {
-        return "BLANK_MSG".to_string();
+fn save_blocklist(gossip: &Gossip) {
+    if let Err(e) = gossip.save_blocklist(Path::new(BLOCKLIST_PATH)) {
+        say!("Error saving blocklist: {e:?}");
     }
-    args[2..].join(" ")
 }
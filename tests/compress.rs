@@ -0,0 +1,35 @@
+use stockchain::gossip::compress::{self, CompressError};
+
+#[test]
+fn small_payloads_are_sent_raw() {
+    let framed = compress::frame(b"hello", Some(1024));
+    assert_eq!(framed.len(), b"hello".len() + 1);
+    assert_eq!(compress::unframe(&framed).unwrap(), b"hello");
+}
+
+#[test]
+fn large_payloads_are_deflated() {
+    let payload = "{\"PublicChat\":\"aaaa\"}".repeat(200);
+    let framed = compress::frame(payload.as_bytes(), Some(1024));
+    assert!(framed.len() < payload.len());
+    assert_eq!(compress::unframe(&framed).unwrap(), payload.as_bytes());
+    // disabled compression frames it raw no matter the size
+    let framed = compress::frame(payload.as_bytes(), None);
+    assert_eq!(framed.len(), payload.len() + 1);
+}
+
+#[test]
+fn unknown_frames_are_rejected() {
+    assert!(matches!(
+        compress::unframe(b""),
+        Err(CompressError::UnknownFrame(None))
+    ));
+    assert!(matches!(
+        compress::unframe(b"{\"Ping\":1}"),
+        Err(CompressError::UnknownFrame(Some(b'{')))
+    ));
+    assert!(matches!(
+        compress::unframe(&[1, 0xff, 0xff]),
+        Err(CompressError::Inflate(_))
+    ));
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use stockchain::{
+    communication::InteractionMessage,
+    gossip::{Gossip, GossipError, GossipEvent, TransportConfig},
+};
+use tokio::sync::mpsc;
+
+static ROOM: &str = "public_handle";
+static TIMEOUT: Duration = Duration::from_secs(30);
+
+async fn next_matching<T>(
+    events: &mut mpsc::Receiver<GossipEvent>,
+    mut matching: impl FnMut(GossipEvent) -> Option<T>,
+) -> T {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            let event = events.recv().await.expect("gossip task stopped");
+            if let Some(found) = matching(event) {
+                return found;
+            }
+        }
+    })
+    .await
+    .expect("event not received in time")
+}
+
+// Both nodes run on tasks of their own and are only reached through their handles
+#[tokio::test]
+async fn spawned_nodes_exchange_messages() {
+    let mut a = Gossip::new().unwrap();
+    let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+    a.open_ears_on(&[addr], TransportConfig::Tcp).unwrap();
+    let (a, mut a_events) = a.spawn();
+    let (b, mut b_events) = Gossip::new().unwrap().spawn();
+    a.join_room(ROOM).await.unwrap();
+    b.join_room(ROOM).await.unwrap();
+
+    let addr = next_matching(&mut a_events, |event| match event {
+        GossipEvent::Listening(addr) => Some(addr),
+        _ => None,
+    })
+    .await;
+    b.dial(addr).await.unwrap();
+
+    // gossipsub only publishes once a's subscription reached b
+    let sender = b.clone();
+    let publishing = tokio::spawn(async move {
+        let chat = || InteractionMessage::PublicChat("hello".to_string());
+        while sender.send(chat(), ROOM).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    });
+    let (peer, text) = next_matching(&mut a_events, |event| match event {
+        GossipEvent::Message((data, InteractionMessage::PublicChat(text))) => {
+            Some((data.peer, text))
+        }
+        _ => None,
+    })
+    .await;
+    publishing.await.unwrap();
+    assert_eq!((peer, text.as_str()), (b.peer_id(), "hello"));
+    // b's task waits for its events to be read once the channel is full
+    while b_events.try_recv().is_ok() {}
+
+    a.clone().shutdown().await;
+    assert!(matches!(a.join_room(ROOM).await, Err(GossipError::Stopped)));
+}
@@ -0,0 +1,120 @@
+use libp2p::PeerId;
+use rand::{SeedableRng, rngs::StdRng};
+use std::{sync::Arc, time::Duration};
+use stockchain::gossip::{
+    clock::MockClock,
+    nonce::{DEFAULT_MAX_MESSAGE_AGE, Nonce, NonceError, SEQ_WINDOW},
+};
+
+fn round_trip(payload: &[u8]) {
+    let mut nonce = Nonce::new();
+    let framed = nonce.add_nonce(payload);
+    assert_eq!(framed.len(), nonce.header_len() + payload.len());
+    assert_eq!(nonce.remove_nonce(&framed).unwrap(), payload);
+}
+
+#[test]
+fn round_trip_preserves_payload() {
+    round_trip(b"");
+    round_trip(b"hello");
+    round_trip(&vec![0xab; 64 * 1024]);
+}
+
+// gossipsub drops messages with the same id, identical payloads must not frame to identical bytes
+#[test]
+fn identical_payloads_differ_on_the_wire() {
+    let mut nonce = Nonce::new();
+    let first = nonce.add_nonce(b"same");
+    let second = nonce.add_nonce(b"same");
+    assert_ne!(first, second);
+    assert!(nonce.get_seq(&second).unwrap() > nonce.get_seq(&first).unwrap());
+}
+
+#[test]
+fn short_message_is_an_error() {
+    let nonce = Nonce::new();
+    let short = vec![0; nonce.header_len() - 1];
+    assert!(matches!(
+        nonce.remove_nonce(&short),
+        Err(NonceError::TooShort { len, expected }) if len == short.len() && expected == nonce.header_len()
+    ));
+    assert!(nonce.get_seq(&[1, 2, 3]).is_none());
+}
+
+// salts from the same seed are the same, so framing can be checked byte for byte
+#[test]
+fn seeded_rng_gives_the_same_salts() {
+    let first = Nonce::with_rng(StdRng::seed_from_u64(7));
+    let second = Nonce::with_rng(StdRng::seed_from_u64(7));
+    let framed = first.add_nonce_wsize(b"payload", 4);
+    assert_eq!(framed, second.add_nonce_wsize(b"payload", 4));
+    assert_eq!(&framed[4..], b"payload");
+    // the next salt is drawn further along the stream
+    assert_ne!(framed, first.add_nonce_wsize(b"payload", 4));
+
+    let mut first = Nonce::with_rng(StdRng::seed_from_u64(7));
+    let mut second = Nonce::with_rng(StdRng::seed_from_u64(7));
+    let salt = 8..8 + first.nonce_len();
+    assert_eq!(
+        first.add_nonce(b"payload")[salt.clone()],
+        second.add_nonce(b"payload")[salt]
+    );
+}
+
+// with the salt, sequence number and timestamp all pinned a framed message is the same on every run
+#[test]
+fn seeded_rng_and_clock_frame_exact_bytes() {
+    let clock = MockClock::starting_at(1_700_000_000_000);
+    let framed = || {
+        let mut nonce =
+            Nonce::with_rng_and_clock(StdRng::seed_from_u64(7), Arc::new(clock.clone()), 42);
+        nonce.add_nonce(b"payload")
+    };
+    // sequence number 42 | salt | 1_700_000_000_000 millis | payload
+    assert_eq!(
+        framed(),
+        b"\x00\x00\x00\x00\x00\x00\x00\x2a\xbe\xfb\xa8\x6a\x00\x00\x01\x8b\xcf\xe5\x68\x00payload"
+    );
+}
+
+#[test]
+fn messages_expire_when_the_clock_passes_max_age() {
+    let clock = MockClock::new();
+    let mut nonce = Nonce::with_clock(Arc::new(clock.clone()));
+    let framed = nonce.add_nonce(b"hello");
+    clock.advance(DEFAULT_MAX_MESSAGE_AGE);
+    assert_eq!(
+        nonce
+            .remove_nonce_checked(&framed, DEFAULT_MAX_MESSAGE_AGE)
+            .unwrap(),
+        b"hello"
+    );
+    clock.advance(Duration::from_millis(1));
+    assert!(matches!(
+        nonce.remove_nonce_checked(&framed, DEFAULT_MAX_MESSAGE_AGE),
+        Err(NonceError::Expired { age }) if age > DEFAULT_MAX_MESSAGE_AGE
+    ));
+}
+
+// messages take different paths through the mesh, a late one is fine as long as it wasn't seen before
+#[test]
+fn sequence_numbers_may_arrive_out_of_order_once() {
+    let mut nonce = Nonce::new();
+    let peer = PeerId::random();
+    nonce.check_seq(peer, 10).unwrap();
+    nonce.check_seq(peer, 12).unwrap();
+    nonce.check_seq(peer, 11).unwrap();
+    for replayed in [10, 11, 12] {
+        assert!(matches!(
+            nonce.check_seq(peer, replayed),
+            Err(NonceError::OutOfOrder { last: 12, .. })
+        ));
+    }
+    let last = 13 + SEQ_WINDOW;
+    nonce.check_seq(peer, last).unwrap();
+    // 13 was never seen but fell out of the window
+    assert!(nonce.check_seq(peer, 13).is_err());
+    nonce.check_seq(peer, 14).unwrap();
+    // other peers count on their own
+    nonce.check_seq(PeerId::random(), 10).unwrap();
+}
@@ -0,0 +1,28 @@
+use std::time::Duration;
+use stockchain::gossip::{
+    clock::{Clock, MockClock},
+    rate_limit::{RateLimit, TokenBucket},
+};
+
+#[test]
+fn tokens_come_back_as_the_clock_moves() {
+    let limit = RateLimit {
+        per_second: 2.0,
+        burst: 2,
+        block_after: None,
+    };
+    let clock = MockClock::new();
+    let mut bucket = TokenBucket::new(&limit, clock.now());
+    assert!(bucket.take(&limit, clock.now()));
+    assert!(bucket.take(&limit, clock.now()));
+    assert!(!bucket.take(&limit, clock.now()));
+    clock.advance(Duration::from_millis(500));
+    assert!(bucket.take(&limit, clock.now()));
+    assert!(!bucket.take(&limit, clock.now()));
+    // never more than the burst, however long it was quiet
+    clock.advance(Duration::from_secs(60));
+    for _ in 0..limit.burst {
+        assert!(bucket.take(&limit, clock.now()));
+    }
+    assert!(!bucket.take(&limit, clock.now()));
+}
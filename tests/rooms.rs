@@ -0,0 +1,262 @@
+use libp2p::{
+    PeerId,
+    gossipsub::{self, IdentTopic, MessageId},
+    swarm::SwarmEvent,
+};
+use stockchain::{
+    communication::InteractionMessage,
+    gossip::{
+        GenerateRoomName, Gossip, GossipConfig, GossipError, MyBehaviourEvent,
+        room::{GossipRooms, MAX_ROOM_NAME_LEN, Room, RoomKind, public_room_name},
+    },
+};
+
+#[tokio::test]
+async fn joining_past_max_rooms_fails() {
+    let config = GossipConfig {
+        max_rooms: 3,
+        ..GossipConfig::default()
+    };
+    let mut gossip = Gossip::with_config(config).unwrap();
+    // the DM room counts against the limit
+    gossip.open_ears().unwrap();
+    gossip.join_room("public_1").unwrap();
+    gossip.join_room("public_2").unwrap();
+    assert!(matches!(
+        gossip.join_room("public_3"),
+        Err(GossipError::TooManyRooms(3))
+    ));
+    // rejoining a room we're in isn't a new room
+    gossip.join_room("public_2").unwrap();
+    gossip.leave_room("public_1").unwrap();
+    gossip.join_room("public_3").unwrap();
+}
+
+#[tokio::test]
+async fn topic_hash_follows_join_and_leave() {
+    let mut gossip = Gossip::new().unwrap();
+    assert!(gossip.topic_hash("public_a").is_none());
+    assert!(!gossip.is_subscribed("public_a"));
+    gossip.join_room("public_a").unwrap();
+    assert!(gossip.is_subscribed("public_a"));
+    let hash = gossip.topic_hash("public_a").unwrap();
+    assert_eq!(
+        gossip.get_room_from_hash(hash.clone()).unwrap().name(),
+        "public_a"
+    );
+    gossip.leave_room("public_a").unwrap();
+    assert!(!gossip.is_subscribed("public_a"));
+    assert!(gossip.topic_hash("public_a").is_none());
+    assert!(gossip.get_room_from_hash(hash).is_none());
+}
+
+#[test]
+fn room_kinds_gate_messages() {
+    let chat = InteractionMessage::PublicChat("hi".to_string());
+    let ack = InteractionMessage::Ack(MessageId::from("id"));
+    let group = InteractionMessage::GroupMessage(([0; 12], Vec::new()));
+    assert!(RoomKind::Public.accepts(&chat));
+    assert!(!RoomKind::Public.accepts(&ack));
+    assert!(RoomKind::Public.accepts(&group));
+    // a room with a group key only takes encrypted chat
+    assert!(!RoomKind::Group.accepts(&chat));
+    assert!(RoomKind::Group.accepts(&group));
+    assert!(RoomKind::DirectMessage.accepts(&ack));
+    assert!(!RoomKind::DirectMessage.accepts(&group));
+    assert!(!RoomKind::Public.encrypted());
+    assert!(RoomKind::Group.encrypted());
+}
+
+#[tokio::test]
+async fn room_kind_follows_group_keys() {
+    let mut gossip = Gossip::new().unwrap();
+    let peer = PeerId::random();
+    let dm = Room::DirectMessage(peer.generate_room_name());
+    assert_eq!(gossip.room_kind(&dm), RoomKind::DirectMessage);
+    assert_eq!(dm.owner(), Some(peer));
+
+    let public = Room::PublicRoom("public_a".to_string());
+    assert_eq!(gossip.room_kind(&public), RoomKind::Public);
+    assert_eq!(public.owner(), None);
+    gossip.secret.derive_group_key("public_a");
+    assert_eq!(gossip.room_kind(&public), RoomKind::Group);
+}
+
+#[tokio::test]
+async fn rejoin_all_restores_left_rooms() {
+    let config = GossipConfig {
+        max_rooms: 2,
+        ..GossipConfig::default()
+    };
+    let mut gossip = Gossip::with_config(config).unwrap();
+    gossip.join_room("public_a").unwrap();
+    let own_room = gossip.join_dm_room(gossip.peer_id()).unwrap();
+    let rooms = gossip.leave_all_rooms();
+    assert_eq!(rooms, ["public_a".to_string(), own_room.clone()]);
+    assert!(gossip.list_rooms().is_empty());
+
+    // with one slot taken in the meantime our DM room still gets back in
+    gossip.join_room("public_b").unwrap();
+    assert!(matches!(
+        gossip.rejoin_all(&rooms),
+        Err(GossipError::TooManyRooms(2))
+    ));
+    let names: Vec<String> = gossip
+        .list_rooms()
+        .iter()
+        .map(|room| room.name().to_string())
+        .collect();
+    assert_eq!(names, ["public_b".to_string(), own_room]);
+}
+
+#[tokio::test]
+async fn malformed_room_names_are_rejected() {
+    let mut gossip = Gossip::new().unwrap();
+    let too_long = format!("public_{}", "a".repeat(MAX_ROOM_NAME_LEN));
+    for name in [
+        "",
+        too_long.as_str(),
+        "public_two\nlines",
+        "public_a b",
+        "public_tab\there",
+        "public_bell\u{7}",
+        "public_nbsp\u{a0}",
+    ] {
+        assert!(
+            matches!(gossip.join_room(name), Err(GossipError::InvalidRoomName(n)) if n == name),
+            "{:?} was accepted",
+            name
+        );
+        assert!(public_room_name(name).is_none());
+    }
+    assert!(gossip.list_rooms().is_empty());
+    // right at the limit is fine
+    let longest = "a".repeat(MAX_ROOM_NAME_LEN);
+    gossip.join_room(&longest).unwrap();
+    assert!(matches!(
+        gossip.join_public_room("x\r"),
+        Err(GossipError::InvalidRoomName(_))
+    ));
+}
+
+#[tokio::test]
+async fn nicknames_survive_a_restart() {
+    let path = std::env::temp_dir().join(format!("nicknames_{}.txt", PeerId::random()));
+    let alice = PeerId::random();
+    let bob = PeerId::random();
+    let mut gossip = Gossip::new().unwrap();
+    gossip.set_nickname(alice, "alice").unwrap();
+    gossip.set_nickname(bob, "bob the builder").unwrap();
+    assert!(matches!(
+        gossip.set_nickname(bob, "  "),
+        Err(GossipError::InvalidNickname(_))
+    ));
+    assert!(matches!(
+        gossip.set_nickname(bob, "bo\nb"),
+        Err(GossipError::InvalidNickname(_))
+    ));
+    gossip.save_nicknames(&path).unwrap();
+
+    let mut restarted = Gossip::new().unwrap();
+    restarted.load_nicknames(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(restarted.nickname(&alice), Some("alice"));
+    assert_eq!(restarted.nickname(&bob), Some("bob the builder"));
+    assert_eq!(restarted.clear_nickname(&alice).as_deref(), Some("alice"));
+    assert_eq!(restarted.nickname(&alice), None);
+}
+
+// gossipsub can still deliver messages for a room we just left, they're skipped rather than taking the node down
+#[tokio::test]
+async fn message_on_an_unknown_topic_is_skipped() {
+    let mut gossip = Gossip::new().unwrap();
+    let message = gossipsub::Message {
+        source: Some(PeerId::random()),
+        data: b"hello".to_vec(),
+        sequence_number: Some(1),
+        topic: IdentTopic::new("public_never_joined").hash(),
+    };
+    let event = SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(gossipsub::Event::Message {
+        propagation_source: PeerId::random(),
+        message_id: MessageId::from("unknown"),
+        message,
+    }));
+    assert!(gossip.handle_event(event).is_none());
+    assert_eq!(gossip.metrics().messages_received, 0);
+}
+
+#[tokio::test]
+async fn joining_twice_subscribes_once() {
+    let mut gossip = Gossip::new().unwrap();
+    gossip.join_room("public_twice").unwrap();
+    gossip.join_room("public_twice").unwrap();
+    assert_eq!(gossip.topics.len(), 1);
+    assert_eq!(gossip.list_rooms().len(), 1);
+}
+
+// our own DM room joined by hand isn't joined a second time by open_ears
+#[tokio::test]
+async fn open_ears_keeps_a_dm_room_joined_before() {
+    let mut gossip = Gossip::new().unwrap();
+    let own_room = gossip.join_dm_room(gossip.peer_id()).unwrap();
+    gossip.open_ears().unwrap();
+    let joined: Vec<&String> = gossip.topics.iter().map(|(name, _)| name).collect();
+    assert_eq!(joined, [&own_room]);
+}
+
+#[tokio::test]
+async fn leaving_a_room_forgets_its_peers() {
+    let mut gossip = Gossip::new().unwrap();
+    let peer = PeerId::random();
+    let room = gossip.join_dm_room(peer).unwrap();
+    // what discovering the peer would have recorded
+    gossip
+        .room_peers
+        .entry(room.clone())
+        .or_default()
+        .insert(peer);
+    assert_eq!(gossip.get_peer_from_room_name(&room).unwrap(), &peer);
+
+    gossip.leave_room(&room).unwrap();
+    assert!(gossip.topics.is_empty());
+    assert!(!gossip.room_peers.contains_key(&room));
+    assert!(gossip.get_peer_from_room_name(&room).is_err());
+    assert!(matches!(
+        gossip.leave_room(&room),
+        Err(GossipError::NotJoined(name)) if name == room
+    ));
+    assert!(matches!(
+        gossip.leave_room("public_never_joined"),
+        Err(GossipError::NotJoined(_))
+    ));
+}
+
+#[test]
+fn peers_sharing_a_suffix_get_different_dm_rooms() {
+    // sha256 peer ids whose digests differ by 58^5, so their base58 forms end in the same five characters
+    let peer = |offset: u32| {
+        let mut bytes = vec![0x12, 0x20];
+        bytes.extend_from_slice(&[0xab; 28]);
+        bytes.extend_from_slice(&offset.to_be_bytes());
+        PeerId::from_bytes(&bytes).unwrap()
+    };
+    let (a, b) = (peer(0), peer(58u32.pow(5)));
+    let suffix = |peer: PeerId| {
+        let base58 = peer.to_base58();
+        base58[base58.len() - 5..].to_string()
+    };
+    assert_ne!(a, b);
+    assert_eq!(suffix(a), suffix(b));
+    assert_ne!(a.generate_room_name(), b.generate_room_name());
+}
+
+// other nodes derive the DM room of a peer on their own, the format can't change without breaking DMs
+#[test]
+fn dm_room_name_format_is_pinned() {
+    let keypair = libp2p::identity::Keypair::ed25519_from_bytes([7; 32]).unwrap();
+    let peer = keypair.public().to_peer_id();
+    assert_eq!(
+        peer.generate_room_name(),
+        "dm_12D3KooWRawPbxPtP1eZaJpumGnyWX2DcUyd3RQnydr3eAto4Az7"
+    );
+}
@@ -0,0 +1,713 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::{Multiaddr, multiaddr::Protocol};
+use stockchain::{
+    communication::InteractionMessage,
+    gossip::{
+        Gossip, GossipConfig, GossipEvent, RetryPolicy, TransportConfig, room::GossipRooms,
+        secret::Secret, wire::WireFormat,
+    },
+};
+
+static ROOM: &str = "public_round_trip";
+static TIMEOUT: Duration = Duration::from_secs(30);
+
+async fn next_event(gossip: &mut Gossip) -> Option<GossipEvent> {
+    let event = gossip.swarm.select_next_some().await;
+    gossip.handle_event(event)
+}
+
+async fn listen_on_loopback(gossip: &mut Gossip) -> Multiaddr {
+    let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+    gossip.open_ears_on(&[addr], TransportConfig::Tcp).unwrap();
+    loop {
+        if let Some(GossipEvent::Listening(addr)) = next_event(gossip).await {
+            return addr;
+        }
+    }
+}
+
+// Two nodes on loopback, one dials the other and pings it in a shared room
+#[tokio::test]
+async fn ping_reaches_the_other_node() {
+    let mut a = Gossip::new().unwrap();
+    let mut b = Gossip::new().unwrap();
+    a.join_room(ROOM).unwrap();
+    b.join_room(ROOM).unwrap();
+    let addr = listen_on_loopback(&mut a).await;
+    listen_on_loopback(&mut b).await;
+    b.dial(addr).unwrap();
+
+    let topic = b.get_topic_from_name(ROOM).unwrap();
+    let mut sent = None;
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    let received = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                Some(event) = next_event(&mut a) => {
+                    if let GossipEvent::Message((data, InteractionMessage::Ping(nonce))) = event {
+                        return (data.peer, nonce);
+                    }
+                }
+                _ = next_event(&mut b) => {}
+                _ = tick.tick() => {
+                    // gossipsub only publishes once a's subscription reached b
+                    if sent.is_none() && !b.subscribed_peers(ROOM).unwrap().is_empty() {
+                        let InteractionMessage::Ping(nonce) = b.ping_message() else {
+                            unreachable!();
+                        };
+                        b.try_gossip(&InteractionMessage::Ping(nonce), topic.clone()).unwrap();
+                        sent = Some(nonce);
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("ping not received in time");
+    assert_eq!(received, (b.peer_id(), sent.unwrap()));
+    let (sent, received) = (b.metrics(), a.metrics());
+    assert_eq!(sent.messages_sent, 1);
+    assert!(received.messages_received >= 1);
+    assert!(received.bytes_received >= sent.bytes_sent);
+    assert_eq!(received.connected_peers, 1);
+    assert_eq!(received.publish_errors, 0);
+}
+
+// a node sending CBOR is understood by one sending the default JSON
+#[tokio::test]
+async fn cbor_messages_reach_a_json_node() {
+    let mut a = Gossip::new().unwrap();
+    let config = GossipConfig {
+        wire_format: WireFormat::Cbor,
+        ..GossipConfig::default()
+    };
+    let mut b = Gossip::with_config(config).unwrap();
+    a.join_room(ROOM).unwrap();
+    b.join_room(ROOM).unwrap();
+    let addr = listen_on_loopback(&mut a).await;
+    listen_on_loopback(&mut b).await;
+    b.dial(addr).unwrap();
+
+    let topic = b.get_topic_from_name(ROOM).unwrap();
+    let mut sent = false;
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    let received = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                Some(event) = next_event(&mut a) => {
+                    if let GossipEvent::Message((data, InteractionMessage::PublicChat(text))) = event {
+                        return (data.message, text);
+                    }
+                }
+                _ = next_event(&mut b) => {}
+                _ = tick.tick() => {
+                    if !sent && !b.subscribed_peers(ROOM).unwrap().is_empty() {
+                        let chat = InteractionMessage::PublicChat("compact".to_string());
+                        b.try_gossip(&chat, topic.clone()).unwrap();
+                        sent = true;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("message not received in time");
+    assert_eq!(
+        received,
+        (
+            "{\"PublicChat\":\"compact\"}".to_string(),
+            "compact".to_string()
+        )
+    );
+}
+
+// a peer joining and leaving a room we're in shows up as events
+#[tokio::test]
+async fn subscriptions_of_peers_are_reported() {
+    let mut a = Gossip::new().unwrap();
+    let mut b = Gossip::new().unwrap();
+    a.join_room(ROOM).unwrap();
+    b.join_room(ROOM).unwrap();
+    let addr = listen_on_loopback(&mut a).await;
+    listen_on_loopback(&mut b).await;
+    b.dial(addr).unwrap();
+
+    let b_id = b.peer_id();
+    let mut left = false;
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    let events = tokio::time::timeout(TIMEOUT, async {
+        let mut events = Vec::new();
+        loop {
+            tokio::select! {
+                Some(event) = next_event(&mut a) => match event {
+                    GossipEvent::Subscribed { peer, room } if peer == b_id => {
+                        events.push(format!("joined {}", room.name()));
+                    }
+                    GossipEvent::Unsubscribed { peer, room } if peer == b_id && left => {
+                        events.push(format!("left {}", room.name()));
+                        return events;
+                    }
+                    _ => {}
+                },
+                _ = next_event(&mut b) => {}
+                _ = tick.tick() => {
+                    // like sending, leaving is only reliably seen once b knows about a
+                    if !left && !events.is_empty() && !b.subscribed_peers(ROOM).unwrap().is_empty() {
+                        b.leave_room(ROOM).unwrap();
+                        left = true;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("subscriptions not reported in time");
+    assert_eq!(events, [format!("joined {ROOM}"), format!("left {ROOM}")]);
+}
+
+// b proves it's in a password protected room, a knows b's key and lists it as a member
+#[tokio::test]
+async fn membership_proof_lists_the_member() {
+    let mut a = Gossip::new().unwrap();
+    let mut b = Gossip::new().unwrap();
+    let room = "public_members";
+    a.join_room_with_password(room, "hunter2").unwrap();
+    b.join_room_with_password(room, "hunter2").unwrap();
+    a.secret.pin_key(b.peer_id(), b.secret.public_key.clone());
+    let addr = listen_on_loopback(&mut a).await;
+    listen_on_loopback(&mut b).await;
+    b.dial(addr).unwrap();
+
+    let mut sent = false;
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    let verified = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                Some(event) = next_event(&mut a) => {
+                    if let GossipEvent::MemberVerified { peer, room } = event {
+                        return (peer, room);
+                    }
+                }
+                _ = next_event(&mut b) => {}
+                _ = tick.tick() => {
+                    if !sent && !b.subscribed_peers(room).unwrap().is_empty() {
+                        b.prove_membership(room).unwrap();
+                        sent = true;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("membership not verified in time");
+    assert_eq!(verified, (b.peer_id(), room.to_string()));
+    assert_eq!(a.room_members(room), [b.peer_id()]);
+    a.leave_room(room).unwrap();
+    assert!(a.room_members(room).is_empty());
+}
+
+// `events` yields the same high level events as driving the swarm by hand
+#[tokio::test]
+async fn events_stream_reports_listening() {
+    let mut gossip = Gossip::new().unwrap();
+    let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+    gossip.open_ears_on(&[addr], TransportConfig::Tcp).unwrap();
+    let mut events = gossip.events();
+    let listening = tokio::time::timeout(TIMEOUT, async {
+        while let Some(event) = events.next().await {
+            if let GossipEvent::Listening(addr) = event {
+                return addr;
+            }
+        }
+        unreachable!("the event stream doesn't end");
+    })
+    .await
+    .expect("not listening in time");
+    assert!(listening.to_string().starts_with("/ip4/127.0.0.1/tcp/"));
+}
+
+// one node reachable over IPv4 and IPv6 at the same time, dialed over IPv6
+#[tokio::test]
+async fn listens_on_both_stacks() {
+    let mut a = Gossip::new().unwrap();
+    let addrs = [
+        "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+        "/ip6/::1/tcp/0".parse().unwrap(),
+    ];
+    a.open_ears_on(&addrs, TransportConfig::Tcp).unwrap();
+    let (mut ip4, mut ip6) = (None, None);
+    tokio::time::timeout(TIMEOUT, async {
+        while ip4.is_none() || ip6.is_none() {
+            if let Some(GossipEvent::Listening(addr)) = next_event(&mut a).await {
+                match addr.iter().next() {
+                    Some(Protocol::Ip4(_)) => ip4 = Some(addr),
+                    Some(Protocol::Ip6(_)) => ip6 = Some(addr),
+                    _ => {}
+                }
+            }
+        }
+    })
+    .await
+    .expect("not listening on both stacks in time");
+
+    let mut b = Gossip::new().unwrap();
+    b.dial(ip6.unwrap()).unwrap();
+    let connected = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                Some(GossipEvent::PeerConnected(peer)) = next_event(&mut b) => return peer,
+                _ = next_event(&mut a) => {}
+            }
+        }
+    })
+    .await
+    .expect("not connected over IPv6 in time");
+    assert_eq!(connected, a.peer_id());
+}
+
+// the filter sees every message before it's emitted, rejected ones never show up as events
+#[tokio::test]
+async fn message_filter_drops_rejected_messages() {
+    let mut a = Gossip::new().unwrap();
+    let mut b = Gossip::new().unwrap();
+    a.join_room(ROOM).unwrap();
+    b.join_room(ROOM).unwrap();
+    a.set_message_filter(Box::new(|data| !data.message.contains("banned")));
+    let addr = listen_on_loopback(&mut a).await;
+    listen_on_loopback(&mut b).await;
+    b.dial(addr).unwrap();
+
+    let topic = b.get_topic_from_name(ROOM).unwrap();
+    let mut sent = false;
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    let received = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                Some(event) = next_event(&mut a) => {
+                    if let GossipEvent::Message((_, InteractionMessage::PublicChat(text))) = event {
+                        return text;
+                    }
+                }
+                _ = next_event(&mut b) => {}
+                _ = tick.tick() => {
+                    if !sent && !b.subscribed_peers(ROOM).unwrap().is_empty() {
+                        for text in ["banned words", "fine words"] {
+                            let chat = InteractionMessage::PublicChat(text.to_string());
+                            b.try_gossip(&chat, topic.clone()).unwrap();
+                        }
+                        sent = true;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("message not received in time");
+    assert_eq!(received, "fine words");
+}
+
+// b rejects a's handshakes, a resends them until it runs out of attempts
+#[tokio::test]
+async fn unanswered_handshake_fails_after_retries() {
+    let mut a = Gossip::new().unwrap();
+    let mut b = Gossip::new().unwrap();
+    a.handshake_timeout = Duration::from_millis(100);
+    a.handshake_retry = RetryPolicy {
+        initial_delay: Duration::from_millis(50),
+        max_delay: Duration::from_millis(100),
+        max_attempts: Some(2),
+    };
+    // a key a doesn't have, b answers no handshake of a's with it pinned
+    let (other_key, _) = Secret::new().unwrap().keys();
+    b.secret.pin_key(a.peer_id(), other_key);
+    let addr = listen_on_loopback(&mut a).await;
+    listen_on_loopback(&mut b).await;
+    b.dial(addr).unwrap();
+
+    let b_id = b.peer_id();
+    let mut started = false;
+    let mut tick = tokio::time::interval(Duration::from_millis(50));
+    let failed = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                Some(event) = next_event(&mut a) => {
+                    assert!(!matches!(event, GossipEvent::SessionEstablished { .. }));
+                }
+                _ = next_event(&mut b) => {}
+                _ = tick.tick() => {
+                    if !started {
+                        started = a.start_handshake(b_id).is_ok();
+                    }
+                    if let Some(GossipEvent::HandshakeFailed(peer)) = a.retry_handshakes().pop() {
+                        return peer;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("handshake didn't fail in time");
+    assert_eq!(failed, b_id);
+    assert!(a.next_handshake_retry().is_none());
+}
+
+// rooms announced in the directory show up on the other side without joining them
+#[tokio::test]
+async fn public_rooms_are_announced_in_the_directory() {
+    let mut a = Gossip::new().unwrap();
+    let mut b = Gossip::new().unwrap();
+    a.join_room(ROOM).unwrap();
+    a.join_room_with_password("public_secret", "hunter2")
+        .unwrap();
+    a.join_public_directory().unwrap();
+    b.join_public_directory().unwrap();
+    let addr = listen_on_loopback(&mut a).await;
+    listen_on_loopback(&mut b).await;
+    b.dial(addr).unwrap();
+
+    let a_id = a.peer_id();
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    tokio::time::timeout(TIMEOUT, async {
+        while b.known_public_rooms().is_empty() {
+            tokio::select! {
+                _ = next_event(&mut a) => {}
+                _ = next_event(&mut b) => {}
+                _ = tick.tick() => {
+                    let _ = a.announce_rooms();
+                }
+            }
+        }
+    })
+    .await
+    .expect("rooms not announced in time");
+    let rooms = b.known_public_rooms();
+    assert_eq!(rooms.len(), 1);
+    assert!(rooms[ROOM].contains(&a_id));
+}
+
+// c joins after b spoke in the room, a catches c up on what it missed
+#[tokio::test]
+async fn late_joiner_is_caught_up() {
+    let mut a = Gossip::new().unwrap();
+    let mut b = Gossip::new().unwrap();
+    let mut c = Gossip::new().unwrap();
+    a.join_room(ROOM).unwrap();
+    b.join_room(ROOM).unwrap();
+    a.set_catch_up(ROOM, 10);
+    let addr = listen_on_loopback(&mut a).await;
+    listen_on_loopback(&mut b).await;
+    listen_on_loopback(&mut c).await;
+    b.dial(addr.clone()).unwrap();
+    c.dial(addr).unwrap();
+
+    let topic = b.get_topic_from_name(ROOM).unwrap();
+    let (mut sent, mut joined) = (false, false);
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    let caught_up = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                Some(event) = next_event(&mut a) => {
+                    if let GossipEvent::Message((_, InteractionMessage::PublicChat(_))) = event {
+                        c.join_room(ROOM).unwrap();
+                        joined = true;
+                    }
+                }
+                _ = next_event(&mut b) => {}
+                Some(event) = next_event(&mut c) => {
+                    if let GossipEvent::CatchUp { peer, room, messages } = event {
+                        return (peer, room, messages);
+                    }
+                }
+                _ = tick.tick() => {
+                    if !sent && !b.subscribed_peers(ROOM).unwrap().is_empty() {
+                        let chat = InteractionMessage::PublicChat("before c".to_string());
+                        b.try_gossip(&chat, topic.clone()).unwrap();
+                        sent = true;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("not caught up in time");
+    assert!(joined);
+    let (peer, room, messages) = caught_up;
+    assert_eq!((peer, room.as_str()), (a.peer_id(), ROOM));
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].peer, b.peer_id());
+    assert!(messages[0].message.contains("before c"));
+    // what c was caught up on isn't part of its history, it can't be passed on again
+    assert!(c.history(ROOM, 10).is_empty());
+}
+
+// a node that already has as many connections as it allows refuses the next one
+#[tokio::test]
+async fn connections_past_the_limit_are_refused() {
+    let config = GossipConfig {
+        max_established: Some(1),
+        ..GossipConfig::default()
+    };
+    let mut a = Gossip::with_config(config).unwrap();
+    let mut b = Gossip::new().unwrap();
+    let mut c = Gossip::new().unwrap();
+    let addr = listen_on_loopback(&mut a).await;
+    b.dial(addr.clone()).unwrap();
+    c.dial(addr).unwrap();
+
+    let reason = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                Some(event) = next_event(&mut a) => {
+                    if let GossipEvent::ConnectionLimitReached { reason, .. } = event {
+                        return reason;
+                    }
+                }
+                _ = next_event(&mut b) => {}
+                _ = next_event(&mut c) => {}
+            }
+        }
+    })
+    .await
+    .expect("no connection refused in time");
+    assert!(reason.contains("at most 1"), "{reason}");
+}
+
+// a dial that can't even start is reported too
+#[tokio::test]
+async fn dials_past_the_pending_limit_are_refused() {
+    let config = GossipConfig {
+        max_pending: Some(0),
+        ..GossipConfig::default()
+    };
+    let mut a = Gossip::with_config(config).unwrap();
+    let mut b = Gossip::new().unwrap();
+    let addr = listen_on_loopback(&mut b).await;
+    assert!(a.dial(addr).is_err());
+    let event = a.events().next().await;
+    assert!(matches!(
+        event,
+        Some(GossipEvent::ConnectionLimitReached { peer: None, .. })
+    ));
+}
+
+// ping_all lists the round trip of a peer that answers
+#[tokio::test]
+async fn ping_all_reports_answering_peers() {
+    let mut a = Gossip::new().unwrap();
+    let mut b = Gossip::new().unwrap();
+    listen_on_loopback(&mut a).await;
+    let addr = listen_on_loopback(&mut b).await;
+    a.dial(addr).unwrap();
+
+    let b_id = b.peer_id();
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    let rtt = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                _ = next_event(&mut a) => {}
+                Some(event) = next_event(&mut b) => {
+                    if let GossipEvent::Message((data, InteractionMessage::Ping(nonce))) = event {
+                        data.reply_to_peer(&mut b, &InteractionMessage::Pong(nonce));
+                    }
+                }
+                _ = tick.tick() => {
+                    // a sweep ends early once everyone answered, otherwise when its time is up
+                    let mut events = a.take_deferred_events();
+                    events.extend(a.finish_ping_sweep());
+                    for event in events {
+                        if let GossipEvent::PingSweep(round_trips) = event
+                            && let Some((_, Some(rtt))) = round_trips.into_iter().find(|(peer, _)| *peer == b_id)
+                        {
+                            return rtt;
+                        }
+                    }
+                    // the first pings can go out before the DM room of b reached a, so sweep until b answers
+                    if a.next_ping_sweep_deadline().is_none() {
+                        a.ping_all();
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("no answer to ping_all in time");
+    assert_eq!(a.latency(&b_id), Some(rtt));
+}
+
+// a node behind a relay gets a circuit address other nodes can dial
+#[tokio::test]
+async fn relay_reservation_gives_a_circuit_address() {
+    let mut relay_node = libp2p::SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(
+            libp2p::tcp::Config::default(),
+            libp2p::noise::Config::new,
+            libp2p::yamux::Config::default,
+        )
+        .unwrap()
+        .with_behaviour(|key| {
+            libp2p::relay::Behaviour::new(key.public().to_peer_id(), Default::default())
+        })
+        .unwrap()
+        .build();
+    relay_node
+        .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    let relay_addr = loop {
+        if let libp2p::swarm::SwarmEvent::NewListenAddr { address, .. } =
+            relay_node.select_next_some().await
+        {
+            break address;
+        }
+    };
+    // the relay hands out its external addresses with a reservation
+    relay_node.add_external_address(relay_addr.clone());
+    let relay_id = *relay_node.local_peer_id();
+
+    let mut a = Gossip::new().unwrap();
+    assert!(a.use_relay(relay_addr.clone()).is_err());
+    a.use_relay(relay_addr.with(Protocol::P2p(relay_id)))
+        .unwrap();
+    let reserved = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                event = next_event(&mut a) => {
+                    if let Some(GossipEvent::RelayReserved(relay)) = event {
+                        return relay;
+                    }
+                }
+                _ = relay_node.select_next_some() => {}
+            }
+        }
+    })
+    .await
+    .expect("no reservation in time");
+    assert_eq!(reserved, relay_id);
+    assert!(
+        a.dial_addrs()
+            .iter()
+            .any(|addr| addr.iter().any(|protocol| protocol == Protocol::P2pCircuit))
+    );
+}
+
+// a finds c through the DHT, they only share the bootstrap node b
+#[tokio::test]
+async fn find_peer_goes_through_the_dht() {
+    let mut b = Gossip::new().unwrap();
+    let b_addr = listen_on_loopback(&mut b)
+        .await
+        .with(Protocol::P2p(b.peer_id()));
+    let mut c = Gossip::new().unwrap();
+    listen_on_loopback(&mut c).await;
+    c.bootstrap(std::slice::from_ref(&b_addr)).unwrap();
+    let config = GossipConfig {
+        bootstrap_nodes: vec![b_addr],
+        ..GossipConfig::default()
+    };
+    let mut a = Gossip::with_config(config).unwrap();
+
+    let c_id = c.peer_id();
+    let mut looking = false;
+    let mut tick = tokio::time::interval(Duration::from_millis(500));
+    let addrs = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                event = next_event(&mut a) => match event {
+                    Some(GossipEvent::PeerFound { peer, addrs }) if peer == c_id => return addrs,
+                    // b may not have heard of c yet
+                    Some(GossipEvent::PeerNotFound(_)) => looking = false,
+                    _ => {}
+                },
+                _ = next_event(&mut b) => {}
+                _ = next_event(&mut c) => {}
+                _ = tick.tick() => {
+                    if !looking {
+                        a.find_peer(c_id);
+                        looking = true;
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("c not found in time");
+    assert!(!addrs.is_empty());
+    assert!(a.peers[&c_id].addrs.iter().all(|addr| addrs.contains(addr)));
+}
+
+// a and c can only reach each other through b, which relays what c says in its DM room to a but can't speak there
+// itself
+#[tokio::test]
+async fn dm_room_messages_are_relayed_by_a_third_node() {
+    let single_connection = || GossipConfig {
+        max_established: Some(1),
+        ..GossipConfig::default()
+    };
+    let mut a = Gossip::with_config(single_connection()).unwrap();
+    let mut b = Gossip::new().unwrap();
+    let b_addr = listen_on_loopback(&mut b).await;
+    a.dial(b_addr.clone()).unwrap();
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                event = next_event(&mut a) => {
+                    if let Some(GossipEvent::PeerConnected(_)) = event {
+                        return;
+                    }
+                }
+                _ = next_event(&mut b) => {}
+            }
+        }
+    })
+    .await
+    .expect("a didn't connect to b in time");
+    // a is already at its limit, c can't connect to it directly
+    let mut c = Gossip::with_config(single_connection()).unwrap();
+    c.dial(b_addr).unwrap();
+    let (a_id, c_id) = (a.peer_id(), c.peer_id());
+    let room = a.join_dm_room(c_id).unwrap();
+    b.join_dm_room(c_id).unwrap();
+    c.join_room(&room).unwrap();
+    let topic = c.get_topic_from_name(&room).unwrap();
+
+    let mut b_nonce = None;
+    let mut c_nonce = None;
+    let mut tick = tokio::time::interval(Duration::from_millis(200));
+    let (author, nonce) = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            tokio::select! {
+                event = next_event(&mut a) => {
+                    if let Some(GossipEvent::Message((data, InteractionMessage::Ping(nonce)))) = event {
+                        return (data.peer, nonce);
+                    }
+                }
+                _ = next_event(&mut b) => {}
+                _ = next_event(&mut c) => {}
+                _ = tick.tick() => {
+                    // b speaks first and straight to a, it would arrive first if it got through
+                    if b_nonce.is_none() && b.subscribed_peers(&room).unwrap().contains(&a_id) {
+                        let nonce = rand::random();
+                        b.try_gossip(&InteractionMessage::Ping(nonce), topic.clone()).unwrap();
+                        b_nonce = Some(nonce);
+                    }
+                    if b_nonce.is_some()
+                        && c_nonce.is_none()
+                        && !c.subscribed_peers(&room).unwrap().is_empty()
+                    {
+                        let nonce = rand::random();
+                        c.try_gossip(&InteractionMessage::Ping(nonce), topic.clone()).unwrap();
+                        c_nonce = Some(nonce);
+                    }
+                }
+            }
+        }
+    })
+    .await
+    .expect("c's message wasn't relayed to a in time");
+    assert_eq!(nonce, c_nonce.unwrap());
+    // b only passed it on
+    assert_eq!(author, c_id);
+    assert!(!a.is_connected(&c_id));
+}
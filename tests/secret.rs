@@ -0,0 +1,375 @@
+use libp2p::PeerId;
+use serde::{Serialize, de::DeserializeOwned};
+use std::sync::Arc;
+use stockchain::gossip::{
+    clock::MockClock,
+    secret::{HandshakeState, Secret, SecretError},
+};
+
+// Runs a handshake between two fresh secrets, returning them with the peer id each one knows the other by
+fn paired() -> (Secret, PeerId, Secret, PeerId) {
+    let (alice_id, bob_id) = (PeerId::random(), PeerId::random());
+    let mut alice = Secret::new().unwrap();
+    let mut bob = Secret::new().unwrap();
+    let (kem_pk, signature, pk) = alice.send_shared_secret(bob_id).unwrap();
+    let (kem_ct, signature, pk) = bob
+        .receive_shared_secret(alice_id, alice.algorithms(), kem_pk, signature, pk)
+        .unwrap();
+    alice
+        .receive_shared_secret_response(bob_id, kem_ct, signature, pk)
+        .unwrap();
+    (alice, alice_id, bob, bob_id)
+}
+
+// The KEM key or ciphertext as a peer could send it, one byte short. Only the length checks stand between it and oqs.
+fn truncated<T: Serialize + DeserializeOwned>(value: &T) -> T {
+    let mut json = serde_json::to_value(value).unwrap();
+    json["bytes"].as_array_mut().unwrap().pop();
+    serde_json::from_value(json).unwrap()
+}
+
+#[test]
+fn truncated_kem_keys_and_ciphertexts_are_refused() {
+    let (alice_id, bob_id) = (PeerId::random(), PeerId::random());
+    let mut alice = Secret::new().unwrap();
+    let mut bob = Secret::new().unwrap();
+    let (kem_pk, signature, pk) = alice.send_shared_secret(bob_id).unwrap();
+    assert!(matches!(
+        bob.receive_shared_secret(
+            alice_id,
+            alice.algorithms(),
+            truncated(&kem_pk),
+            signature.clone(),
+            pk.clone()
+        ),
+        Err(SecretError::MalformedKey)
+    ));
+    let (kem_ct, signature, pk) = bob
+        .receive_shared_secret(alice_id, alice.algorithms(), kem_pk, signature, pk)
+        .unwrap();
+    assert!(matches!(
+        alice.receive_shared_secret_response(
+            bob_id,
+            truncated(&kem_ct),
+            signature.clone(),
+            pk.clone()
+        ),
+        Err(SecretError::MalformedCiphertext)
+    ));
+    // nothing was used up, the real response still completes the handshake
+    alice
+        .receive_shared_secret_response(bob_id, kem_ct, signature, pk)
+        .unwrap();
+    assert!(alice.has_session(&bob_id));
+}
+
+#[test]
+fn encrypt_before_handshake_has_no_session() {
+    let secret = Secret::new().unwrap();
+    let peer_id = PeerId::random();
+    assert!(!secret.has_session(&peer_id));
+    assert!(matches!(
+        secret.encrypt(peer_id, b"", b"hello"),
+        Err(SecretError::NoSession(peer)) if peer == peer_id
+    ));
+    assert!(matches!(
+        secret.decrypt(peer_id, b"", [0; 12], b"hello".to_vec()),
+        Err(SecretError::NoSession(peer)) if peer == peer_id
+    ));
+    // the handshake is what opens a session
+    let (alice, _, _, bob_id) = paired();
+    assert!(alice.has_session(&bob_id));
+    assert!(alice.encrypt(bob_id, b"", b"hello").is_ok());
+}
+
+#[test]
+fn missing_keys_and_requests_have_their_own_errors() {
+    let (alice_id, bob_id) = (PeerId::random(), PeerId::random());
+    let mut alice = Secret::new().unwrap();
+    let mut bob = Secret::new().unwrap();
+    let (kem_pk, signature, pk) = alice.send_shared_secret(bob_id).unwrap();
+    let (kem_ct, signature, pk) = bob
+        .receive_shared_secret(alice_id, alice.algorithms(), kem_pk, signature, pk)
+        .unwrap();
+    // a response nobody asked for
+    let mut carol = Secret::new().unwrap();
+    assert!(matches!(
+        carol.receive_shared_secret_response(bob_id, kem_ct, signature, pk),
+        Err(SecretError::NoPendingHandshake(peer)) if peer == bob_id
+    ));
+
+    let (alice, _, _, bob_id) = paired();
+    assert!(matches!(
+        alice.share_group_key(bob_id, b"", "group"),
+        Err(SecretError::NoGroupKey(room)) if room == "group"
+    ));
+    assert!(matches!(
+        alice.encrypt_group("group", b"", b"hello"),
+        Err(SecretError::NoGroupKey(room)) if room == "group"
+    ));
+    assert!(matches!(
+        alice.decrypt_group("group", b"", [0; 12], b"hello".to_vec()),
+        Err(SecretError::NoGroupKey(room)) if room == "group"
+    ));
+}
+
+#[test]
+fn round_trip_after_handshake() {
+    let (alice, alice_id, bob, bob_id) = paired();
+    let (nonce, ciphertext) = alice.encrypt(bob_id, b"aad", b"hello").unwrap();
+    assert_eq!(
+        bob.decrypt(alice_id, b"aad", nonce, ciphertext).unwrap(),
+        b"hello"
+    );
+}
+
+// a ciphertext sealed for one sender doesn't open as coming from another, so it can't be relayed as theirs
+#[test]
+fn decryption_fails_when_the_aad_peer_id_is_swapped() {
+    let (alice, alice_id, bob, bob_id) = paired();
+    let (nonce, ciphertext) = alice
+        .encrypt(bob_id, &alice_id.to_bytes(), b"hello")
+        .unwrap();
+    for other in [bob_id, PeerId::random()] {
+        assert!(matches!(
+            bob.decrypt(alice_id, &other.to_bytes(), nonce, ciphertext.clone()),
+            Err(SecretError::AuthenticationFailed)
+        ));
+    }
+    assert_eq!(
+        bob.decrypt(alice_id, &alice_id.to_bytes(), nonce, ciphertext)
+            .unwrap(),
+        b"hello"
+    );
+}
+
+// nonces have a fixed length, so there's no MalformedNonce: GCM is what notices a corrupted one
+#[test]
+fn corrupted_nonce_surfaces_as_authentication_failed() {
+    let (alice, alice_id, bob, bob_id) = paired();
+    let (mut nonce, ciphertext) = alice.encrypt(bob_id, b"aad", b"hello").unwrap();
+    nonce[0] ^= 1;
+    assert!(matches!(
+        bob.decrypt(alice_id, b"aad", nonce, ciphertext),
+        Err(SecretError::AuthenticationFailed)
+    ));
+}
+
+#[test]
+fn tampering_fails_authentication() {
+    let (alice, alice_id, bob, bob_id) = paired();
+    let (nonce, ciphertext) = alice.encrypt(bob_id, b"aad", b"hello").unwrap();
+
+    let mut corrupted = ciphertext.clone();
+    corrupted[0] ^= 1;
+    assert!(matches!(
+        bob.decrypt(alice_id, b"aad", nonce, corrupted),
+        Err(SecretError::AuthenticationFailed)
+    ));
+
+    assert!(matches!(
+        bob.decrypt(alice_id, b"other aad", nonce, ciphertext.clone()),
+        Err(SecretError::AuthenticationFailed)
+    ));
+
+    assert!(matches!(
+        bob.decrypt(alice_id, b"aad", nonce, ciphertext[..8].to_vec()),
+        Err(SecretError::MalformedCiphertext)
+    ));
+}
+
+#[test]
+fn repeated_signed_message_is_verified_from_cache() {
+    let (alice, alice_id, bob, bob_id) = paired();
+    let (nonce, ciphertext) = alice.encrypt_signed(bob_id, b"aad", b"hello").unwrap();
+    for _ in 0..3 {
+        assert_eq!(
+            bob.decrypt_verified(alice_id, b"aad", nonce, ciphertext.clone())
+                .unwrap(),
+            b"hello"
+        );
+    }
+    assert_eq!(bob.verify_cache_len(), 1);
+    bob.clear_verify_cache();
+    assert_eq!(bob.verify_cache_len(), 0);
+}
+
+#[test]
+fn sessions_need_a_rekey_once_they_get_old() {
+    let (mut alice, _, _, bob_id) = paired();
+    let clock = MockClock::new();
+    alice.clock = Arc::new(clock.clone());
+    assert!(!alice.needs_rekey(&bob_id));
+    clock.advance(alice.rekey_after);
+    assert!(alice.needs_rekey(&bob_id));
+}
+
+#[test]
+fn messages_in_flight_during_a_rekey_still_decrypt() {
+    let (mut alice, alice_id, mut bob, bob_id) = paired();
+    let (nonce, ciphertext) = alice.encrypt(bob_id, b"aad", b"first").unwrap();
+    let (late_nonce, late) = alice.encrypt(bob_id, b"aad", b"late").unwrap();
+    let (kem_pk, signature, pk) = alice.rekey(bob_id).unwrap();
+    let (kem_ct, signature, pk) = bob
+        .receive_shared_secret(alice_id, alice.algorithms(), kem_pk, signature, pk)
+        .unwrap();
+    // the exchange overtook a message sealed with the old secret
+    assert_eq!(
+        bob.decrypt(alice_id, b"aad", nonce, ciphertext).unwrap(),
+        b"first"
+    );
+    alice
+        .receive_shared_secret_response(bob_id, kem_ct, signature, pk)
+        .unwrap();
+    let (nonce, ciphertext) = alice.encrypt(bob_id, b"aad", b"second").unwrap();
+    assert_eq!(
+        bob.decrypt(alice_id, b"aad", nonce, ciphertext).unwrap(),
+        b"second"
+    );
+    // the new secret is in use, the old one is gone
+    assert!(matches!(
+        bob.decrypt(alice_id, b"aad", late_nonce, late),
+        Err(SecretError::AuthenticationFailed)
+    ));
+}
+
+#[test]
+fn key_rotation_is_accepted_when_signed_by_the_pinned_key() {
+    let (mut alice, alice_id, mut bob, bob_id) = paired();
+    let (old_pk, new_pk, signature) = alice.rotate_signing_key().unwrap();
+    assert_eq!(alice.public_key, new_pk);
+
+    // signed with a key bob never pinned
+    let (_, _, forged) = Secret::new().unwrap().rotate_signing_key().unwrap();
+    assert!(matches!(
+        bob.receive_key_rotation(alice_id, old_pk.clone(), new_pk.clone(), forged),
+        Err(SecretError::BadSignature(peer)) if peer == alice_id
+    ));
+    assert!(matches!(
+        bob.receive_key_rotation(
+            PeerId::random(),
+            old_pk.clone(),
+            new_pk.clone(),
+            signature.clone()
+        ),
+        Err(SecretError::UnknownKey(_))
+    ));
+
+    bob.receive_key_rotation(alice_id, old_pk, new_pk.clone(), signature)
+        .unwrap();
+    assert_eq!(bob.known_keys.get(&alice_id), Some(&new_pk));
+    let (nonce, ciphertext) = alice.encrypt_signed(bob_id, b"aad", b"hello").unwrap();
+    assert_eq!(
+        bob.decrypt_verified(alice_id, b"aad", nonce, ciphertext)
+            .unwrap(),
+        b"hello"
+    );
+}
+
+// both sides start a handshake at once, only the exchange of the smaller peer id is answered
+#[test]
+fn simultaneous_handshakes_agree_on_one_secret() {
+    let (alice_id, bob_id) = (PeerId::random(), PeerId::random());
+    let mut alice = Secret::new().unwrap();
+    let mut bob = Secret::new().unwrap();
+    let from_alice = alice.send_shared_secret(bob_id).unwrap();
+    let from_bob = bob.send_shared_secret(alice_id).unwrap();
+    assert_eq!(alice.handshake_state(&bob_id), HandshakeState::Initiated);
+
+    let (mut winner, winner_id, mut loser, loser_id, exchange, ignored) =
+        if alice_id.to_bytes() < bob_id.to_bytes() {
+            (alice, alice_id, bob, bob_id, from_alice, from_bob)
+        } else {
+            (bob, bob_id, alice, alice_id, from_bob, from_alice)
+        };
+    // the exchanges cross, each side sees the other's while its own is still unanswered
+    assert!(!winner.resolve_handshake_collision(winner_id, loser_id));
+    assert!(loser.resolve_handshake_collision(loser_id, winner_id));
+    assert_eq!(loser.handshake_state(&winner_id), HandshakeState::None);
+
+    let (kem_pk, signature, pk) = exchange;
+    let algorithms = winner.algorithms();
+    let (kem_ct, signature, pk) = loser
+        .receive_shared_secret(winner_id, algorithms, kem_pk, signature, pk)
+        .unwrap();
+    winner
+        .receive_shared_secret_response(loser_id, kem_ct, signature, pk)
+        .unwrap();
+    assert_eq!(
+        winner.handshake_state(&loser_id),
+        HandshakeState::Established
+    );
+    assert_eq!(
+        loser.handshake_state(&winner_id),
+        HandshakeState::Established
+    );
+    assert_eq!(
+        winner.shared_secret[&loser_id].as_ref(),
+        loser.shared_secret[&winner_id].as_ref()
+    );
+
+    // had the winner answered the dropped handshake after all, the response would be rejected
+    let (kem_pk, signature, pk) = ignored;
+    let (kem_ct, signature, pk) = winner
+        .receive_shared_secret(loser_id, algorithms, kem_pk, signature, pk)
+        .unwrap();
+    assert!(
+        loser
+            .receive_shared_secret_response(winner_id, kem_ct, signature, pk)
+            .is_err()
+    );
+}
+
+// a handshake pins the key, both sides then see the same fingerprint for it
+#[test]
+fn pinned_keys_are_listed_with_matching_fingerprints() {
+    let (alice, alice_id, bob, bob_id) = paired();
+    let pinned: Vec<_> = alice.pinned_keys().collect();
+    assert_eq!(pinned, [(&bob_id, &bob.public_key)]);
+    let (_, bobs_key) = bob.pinned_keys().next().unwrap();
+    assert_eq!(bob.pinned_keys().next().unwrap().0, &alice_id);
+    assert_eq!(
+        Secret::fingerprint(bobs_key),
+        Secret::fingerprint(&alice.public_key)
+    );
+    assert_ne!(
+        Secret::fingerprint(&alice.public_key),
+        Secret::fingerprint(&bob.public_key)
+    );
+}
+
+#[test]
+fn membership_proofs_need_the_pinned_key() {
+    let (alice, alice_id, bob, _) = paired();
+    let signature = alice.sign_membership("public_club", 1_000).unwrap();
+    bob.verify_membership(alice_id, "public_club", 1_000, &signature)
+        .unwrap();
+    // bound to the room and the time it was signed for
+    assert!(matches!(
+        bob.verify_membership(alice_id, "public_other", 1_000, &signature),
+        Err(SecretError::BadSignature(_))
+    ));
+    assert!(matches!(
+        bob.verify_membership(alice_id, "public_club", 2_000, &signature),
+        Err(SecretError::BadSignature(_))
+    ));
+    let stranger = PeerId::random();
+    assert!(matches!(
+        bob.verify_membership(stranger, "public_club", 1_000, &signature),
+        Err(SecretError::UnknownKey(peer)) if peer == stranger
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn key_files_are_only_readable_by_us() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::env::temp_dir().join(format!("keys_{}", PeerId::random()));
+    Secret::load_or_generate(&path)
+        .unwrap()
+        .save_keys(&path)
+        .unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(mode & 0o777, 0o600);
+}
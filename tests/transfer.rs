@@ -0,0 +1,34 @@
+use libp2p::PeerId;
+use std::time::Instant;
+use stockchain::gossip::transfer::{IncomingFile, MAX_FILE_CHUNKS, TransferError};
+
+#[test]
+fn oversized_transfers_are_refused_up_front() {
+    // would be a 100 GB allocation if it got through
+    assert!(matches!(
+        IncomingFile::new(PeerId::random(), u32::MAX, Instant::now()),
+        Err(TransferError::TooLarge { total: u32::MAX, max }) if max == MAX_FILE_CHUNKS
+    ));
+    assert!(IncomingFile::new(PeerId::random(), MAX_FILE_CHUNKS, Instant::now()).is_ok());
+}
+
+#[test]
+fn chunks_must_agree_on_the_total() {
+    let now = Instant::now();
+    let mut file = IncomingFile::new(PeerId::random(), 2, now).unwrap();
+    file.insert(0, 2, b"hello ".to_vec(), now).unwrap();
+    assert!(matches!(
+        file.insert(1, 3, b"there".to_vec(), now),
+        Err(TransferError::TotalMismatch {
+            total: 3,
+            expected: 2
+        })
+    ));
+    assert!(!file.is_complete());
+    file.insert(1, 2, b"world".to_vec(), now).unwrap();
+    assert!(file.is_complete());
+    // a repeated chunk isn't buffered twice
+    file.insert(1, 2, b"world".to_vec(), now).unwrap();
+    assert_eq!(file.buffered(), b"hello world".len());
+    assert_eq!(file.assemble(), b"hello world");
+}
@@ -0,0 +1,125 @@
+use libp2p::PeerId;
+use stockchain::{
+    communication::{InteractionMessage, SharedSecretExchange},
+    gossip::{
+        Gossip, GossipConfig, GossipSendError,
+        room::GossipRooms,
+        secret::Secret,
+        wire::{self, DecodeError, WireError, WireFormat},
+    },
+};
+
+#[test]
+fn tags_round_trip() {
+    for format in [WireFormat::Json, WireFormat::Cbor] {
+        let tagged = wire::tag(format, b"payload".to_vec());
+        assert_eq!(tagged.len(), b"payload".len() + 1);
+        let (untagged_format, data) = wire::untag(&tagged).unwrap();
+        assert_eq!(untagged_format, format);
+        assert_eq!(data, b"payload");
+    }
+    assert!(matches!(
+        wire::untag(b""),
+        Err(WireError::UnknownFormat(None))
+    ));
+    assert!(matches!(
+        wire::untag(b"{\"Ping\":1}"),
+        Err(WireError::UnknownFormat(Some(b'{')))
+    ));
+}
+
+#[test]
+fn cbor_shrinks_keys() {
+    let (original, _) = Secret::new().unwrap().keys();
+    let message = InteractionMessage::ReplyPublicKey(original.clone());
+    let json = WireFormat::Json.serialize(&message).unwrap();
+    let cbor = WireFormat::Cbor.serialize(&message).unwrap();
+    assert!(cbor.len() < json.len());
+    let InteractionMessage::ReplyPublicKey(pk) = WireFormat::deserialize_cbor(&cbor).unwrap()
+    else {
+        panic!("deserialized into another message");
+    };
+    assert_eq!(pk, original);
+    assert!(matches!(
+        WireFormat::deserialize_cbor(&json),
+        Err(WireError::Cbor(_))
+    ));
+}
+
+#[tokio::test]
+async fn encoded_messages_decode_in_both_formats() {
+    for wire_format in [WireFormat::Json, WireFormat::Cbor] {
+        let config = GossipConfig {
+            wire_format,
+            ..GossipConfig::default()
+        };
+        let gossip = Gossip::with_config(config).unwrap();
+        // long enough to be compressed
+        for text in ["short".to_string(), "long ".repeat(1000)] {
+            let message = InteractionMessage::PublicChat(text.clone());
+            let data = gossip.encode_message(&message).unwrap();
+            let InteractionMessage::PublicChat(decoded) = gossip.decode_message(&data).unwrap()
+            else {
+                panic!("decoded into another message");
+            };
+            assert_eq!(decoded, text);
+        }
+    }
+}
+
+#[tokio::test]
+async fn encoding_uses_up_no_sequence_number() {
+    let gossip = Gossip::new().unwrap();
+    let message = InteractionMessage::Ping(1);
+    let first = gossip.encode_message(&message).unwrap();
+    let second = gossip.encode_message(&message).unwrap();
+    let seq = |data: &[u8]| gossip.nonce.get_seq(&data[1..]).unwrap();
+    assert_eq!(seq(&first), seq(&second));
+    assert!(matches!(
+        gossip.decode_message(&first[..4]),
+        Err(DecodeError::Nonce(_))
+    ));
+    assert!(matches!(
+        gossip.decode_message(b"\x07"),
+        Err(DecodeError::Wire(WireError::UnknownFormat(Some(7))))
+    ));
+}
+
+#[tokio::test]
+async fn oversized_messages_are_refused_before_publishing() {
+    let config = GossipConfig {
+        max_transmit_size: 1024,
+        compress_threshold: None,
+        ..GossipConfig::default()
+    };
+    let mut gossip = Gossip::with_config(config).unwrap();
+    gossip.join_room("public_wire").unwrap();
+    let topic = gossip.get_topic_from_name("public_wire").unwrap();
+    let message = InteractionMessage::RoomAnnounce(vec!["a".repeat(1024)]);
+    let result = gossip.try_gossip(&message, topic);
+    assert!(matches!(
+        result,
+        Err(GossipSendError::TooLarge { max: 1024, .. })
+    ));
+    assert_eq!(gossip.metrics().messages_sent, 0);
+}
+
+#[tokio::test]
+async fn default_transmit_size_fits_a_handshake() {
+    for format in [WireFormat::Json, WireFormat::Cbor] {
+        let config = GossipConfig {
+            wire_format: format,
+            compress_threshold: None,
+            ..GossipConfig::default()
+        };
+        let max = config.max_transmit_size;
+        let mut gossip = Gossip::with_config(config).unwrap();
+        let algorithms = gossip.secret.algorithms();
+        let (kem_pk, signature, pk) = gossip.secret.send_shared_secret(PeerId::random()).unwrap();
+        let exchange = SharedSecretExchange::new(algorithms, kem_pk, signature, pk);
+        let data = gossip
+            .encode_message(&InteractionMessage::SharedSecretExchange(exchange))
+            .unwrap();
+        assert!(data.len() <= max, "{} bytes in {:?}", data.len(), format);
+    }
+}